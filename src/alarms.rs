@@ -0,0 +1,468 @@
+use std::fmt;
+
+use resol_vbus::{
+    chrono::prelude::*,
+    chrono::Duration,
+    DataSet,
+    Language,
+    Specification,
+};
+
+
+use config::{AlarmCondition, AlarmConfig, Config};
+use error::Result;
+use notifiers::{Notification, NotificationDispatcher};
+use tick_source::TickSource;
+use virtual_fields::{sample_field, VirtualFields};
+
+
+/// The default sentinel values RESOL controllers report for broken or
+/// disconnected sensors.
+const DEFAULT_SENSOR_FAULT_VALUES: &[f64] = &[888.8, -888.8, 999.9, -999.9];
+
+
+struct Alarm {
+    name: String,
+    condition: AlarmCondition,
+    field: Option<String>,
+    packet: Option<String>,
+    threshold: f64,
+    hysteresis: f64,
+    values: Vec<f64>,
+    duration: Duration,
+    notifiers: Option<Vec<String>>,
+    repeat_interval: Option<Duration>,
+    is_active: bool,
+    last_notified_at: Option<DateTime<UTC>>,
+    last_sample: Option<(DateTime<UTC>, f64)>,
+}
+
+
+impl Alarm {
+    fn from_config(alarm_config: &AlarmConfig) -> Alarm {
+        Alarm {
+            name: alarm_config.name.clone(),
+            condition: alarm_config.condition,
+            field: alarm_config.field.clone(),
+            packet: alarm_config.packet.clone(),
+            threshold: alarm_config.threshold.unwrap_or(0.0),
+            hysteresis: alarm_config.hysteresis.unwrap_or(0.0),
+            values: alarm_config.values.clone().unwrap_or(DEFAULT_SENSOR_FAULT_VALUES.to_vec()),
+            duration: Duration::seconds(alarm_config.duration.unwrap_or(300)),
+            notifiers: alarm_config.notifiers.clone(),
+            repeat_interval: alarm_config.repeat_interval.map(Duration::seconds),
+            is_active: false,
+            last_notified_at: None,
+            last_sample: None,
+        }
+    }
+}
+
+
+/// The values an alarm is evaluated against.
+struct Context<'a> {
+    spec: &'a Specification,
+    data_set: &'a DataSet,
+    virtual_fields: &'a VirtualFields,
+    last_receive_time: DateTime<UTC>,
+    started_at: DateTime<UTC>,
+    now: DateTime<UTC>,
+}
+
+
+impl<'a> Context<'a> {
+    fn sample(&self, field: &str) -> Option<(DateTime<UTC>, f64)> {
+        match sample_field(self.spec, self.data_set, field) {
+            Some(sample) => Some(sample),
+            None => self.virtual_fields.value(field).map(|value| (self.now, value)),
+        }
+    }
+}
+
+
+/// Evaluates the `[[alarm]]` rules and sends notifications when an alarm
+/// becomes active or recovers.
+///
+/// Notifications are only sent when the state of an alarm changes (or after
+/// the `repeat_interval` while it stays active), so that a value hovering
+/// around a threshold does not flood the notifiers. The `hysteresis` moves
+/// the threshold an active alarm has to cross to recover. Alarms on a field
+/// whose value becomes unknown, e.g. because its packet is stale, keep their
+/// state until the value is known again.
+pub struct AlarmManager {
+    spec: Specification,
+    alarms: Vec<Alarm>,
+    dispatcher: NotificationDispatcher,
+    notifications_enabled: bool,
+    tick_source: TickSource,
+    started_at: DateTime<UTC>,
+}
+
+
+impl fmt::Debug for AlarmManager {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AlarmManager")
+            .field("alarms", &self.alarms.iter().map(|alarm| &alarm.name).collect::<Vec<_>>())
+            .field("dispatcher", &self.dispatcher)
+            .field("notifications_enabled", &self.notifications_enabled)
+            .finish()
+    }
+}
+
+
+impl AlarmManager {
+    /// Create the alarms and notifiers configured in the `config`.
+    pub fn from_config(config: &Config, now: DateTime<UTC>) -> Result<AlarmManager> {
+        let spec_file = config.load_spec_file()?;
+
+        let spec = Specification::from_file(spec_file, Language::En);
+
+        Ok(AlarmManager {
+            spec,
+            alarms: config.alarm.iter().map(Alarm::from_config).collect(),
+            dispatcher: NotificationDispatcher::from_config(config)?,
+            notifications_enabled: true,
+            tick_source: TickSource::new(1, now),
+            started_at: now,
+        })
+    }
+
+    /// Start evaluating the alarms at `now`, the first timestamp delivered by
+    /// the data source.
+    pub fn start(&mut self, now: DateTime<UTC>) {
+        self.tick_source.reset(now);
+        self.started_at = now;
+    }
+
+    /// Enable or disable sending notifications, e.g. while replaying
+    /// recorded data. Alarms are still evaluated and logged.
+    pub fn set_notifications_enabled(&mut self, enabled: bool) {
+        self.notifications_enabled = enabled;
+    }
+
+    /// The number of alarms that are currently active.
+    pub fn active_alarm_count(&self) -> usize {
+        self.alarms.iter().filter(|alarm| alarm.is_active).count()
+    }
+
+    /// Evaluate all alarms, at most once per second.
+    pub fn process(&mut self, data_set: &DataSet, virtual_fields: &VirtualFields, last_receive_time: Option<DateTime<UTC>>, now: DateTime<UTC>) {
+        if self.alarms.is_empty() || !self.tick_source.process(now) {
+            return;
+        }
+
+        let context = Context {
+            spec: &self.spec,
+            data_set,
+            virtual_fields,
+            last_receive_time: last_receive_time.unwrap_or(self.started_at),
+            started_at: self.started_at,
+            now,
+        };
+
+        for alarm in self.alarms.iter_mut() {
+            let (is_active, message) = match evaluate(alarm, &context) {
+                Some(result) => result,
+                None => continue,
+            };
+
+            let notify = update_state(alarm, is_active, &message, now);
+
+            if notify && !self.notifications_enabled {
+                debug!("Not sending notification for alarm {}, notifications are disabled", alarm.name);
+            } else if notify {
+                alarm.last_notified_at = Some(now);
+
+                self.dispatcher.dispatch(Notification {
+                    alarm: alarm.name.clone(),
+                    is_active,
+                    message,
+                    timestamp: now.to_rfc3339(),
+                }, &alarm.notifiers);
+            }
+        }
+    }
+
+    /// Wait for pending notifications to be delivered.
+    pub fn shutdown(&mut self) {
+        self.dispatcher.shutdown();
+    }
+}
+
+
+/// Store the evaluated state of the `alarm` and return whether to notify
+/// about it: when the state changes or, while the alarm stays active, once
+/// the `repeat_interval` has passed since the last notification.
+fn update_state(alarm: &mut Alarm, is_active: bool, message: &str, now: DateTime<UTC>) -> bool {
+    if is_active != alarm.is_active {
+        alarm.is_active = is_active;
+        if is_active {
+            warn!("Alarm {} is active: {}", alarm.name, message);
+        } else {
+            info!("Alarm {} recovered: {}", alarm.name, message);
+        }
+        true
+    } else if let (true, Some(repeat_interval), Some(last_notified_at)) = (is_active, alarm.repeat_interval, alarm.last_notified_at) {
+        now.signed_duration_since(last_notified_at) >= repeat_interval
+    } else {
+        false
+    }
+}
+
+
+/// Returns whether the alarm's condition is met and a description of the
+/// current state, or `None` if there is nothing to evaluate (e.g. because the
+/// value is unknown or the packet was not received again since the last
+/// evaluation).
+fn evaluate(alarm: &mut Alarm, context: &Context) -> Option<(bool, String)> {
+    match alarm.condition {
+        AlarmCondition::Above | AlarmCondition::Below | AlarmCondition::SensorFault => {
+            let field = alarm.field.as_ref()?;
+            let (_, value) = context.sample(field)?;
+
+            let result = match alarm.condition {
+                AlarmCondition::Above => {
+                    let is_active = if alarm.is_active {
+                        value > alarm.threshold - alarm.hysteresis
+                    } else {
+                        value > alarm.threshold
+                    };
+                    (is_active, format!("{} is {}, threshold is above {}", field, value, alarm.threshold))
+                },
+                AlarmCondition::Below => {
+                    let is_active = if alarm.is_active {
+                        value < alarm.threshold + alarm.hysteresis
+                    } else {
+                        value < alarm.threshold
+                    };
+                    (is_active, format!("{} is {}, threshold is below {}", field, value, alarm.threshold))
+                },
+                _ => {
+                    let is_active = alarm.values.iter().any(|sentinel| (value - sentinel).abs() < 0.05);
+                    if is_active {
+                        (true, format!("{} reports sensor fault value {}", field, value))
+                    } else {
+                        (false, format!("{} is {}", field, value))
+                    }
+                },
+            };
+
+            Some(result)
+        },
+        AlarmCondition::Rate => {
+            let field = alarm.field.clone()?;
+            let (timestamp, value) = match context.sample(&field) {
+                Some(sample) => sample,
+                None => {
+                    // Do not compute a rate across the time the value was unknown
+                    alarm.last_sample = None;
+                    return None;
+                },
+            };
+
+            let last_sample = alarm.last_sample;
+            match last_sample {
+                Some((last_timestamp, _)) if timestamp <= last_timestamp => return None,
+                _ => alarm.last_sample = Some((timestamp, value)),
+            }
+
+            let (last_timestamp, last_value) = last_sample?;
+
+            let minutes = timestamp.signed_duration_since(last_timestamp).num_milliseconds() as f64 / 60000.0;
+            let rate = (value - last_value) / minutes;
+
+            let is_active = if alarm.is_active {
+                rate.abs() > alarm.threshold - alarm.hysteresis
+            } else {
+                rate.abs() > alarm.threshold
+            };
+
+            Some((is_active, format!("{} changes by {:.2} per minute, threshold is {}", field, rate, alarm.threshold)))
+        },
+        AlarmCondition::PacketMissing => {
+            let packet = alarm.packet.as_ref()?;
+
+            let last_received = context.data_set.iter()
+                .find(|data| data.id_string() == *packet)
+                .map(|data| data.as_header().timestamp)
+                .unwrap_or(context.started_at);
+
+            let age = context.now.signed_duration_since(last_received);
+
+            Some((age > alarm.duration, format!("packet {} was last received {} seconds ago", packet, age.num_seconds())))
+        },
+        AlarmCondition::ConnectionLost => {
+            let age = context.now.signed_duration_since(context.last_receive_time);
+
+            Some((age > alarm.duration, format!("last data was received {} seconds ago", age.num_seconds())))
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use resol_vbus::SpecificationFile;
+
+    use virtual_fields::VirtualField;
+
+    use super::*;
+
+    const FIELD: &str = "field";
+
+    fn alarm(condition: AlarmCondition, threshold: f64, hysteresis: f64) -> Alarm {
+        Alarm::from_config(&AlarmConfig {
+            name: "test".to_string(),
+            condition,
+            field: Some(FIELD.to_string()),
+            packet: Some("00_0010_7E11_10_0100".to_string()),
+            threshold: Some(threshold),
+            hysteresis: Some(hysteresis),
+            values: None,
+            duration: Some(60),
+            notifiers: None,
+            repeat_interval: None,
+        })
+    }
+
+    fn virtual_fields(value: Option<f64>) -> VirtualFields {
+        let mut virtual_fields = VirtualFields::new();
+        virtual_fields.set(VirtualField {
+            id: FIELD.to_string(),
+            name: "Field".to_string(),
+            unit: String::new(),
+            precision: 1,
+            value,
+        });
+        virtual_fields
+    }
+
+    fn start() -> DateTime<UTC> {
+        UTC.ymd(2024, 6, 1).and_hms(12, 0, 0)
+    }
+
+    /// Evaluate the `alarm` for the `value` `seconds` after the start and
+    /// store the resulting state like `AlarmManager::process` does.
+    fn evaluate_at(alarm: &mut Alarm, seconds: i64, value: Option<f64>) -> Option<bool> {
+        let spec = Specification::from_file(SpecificationFile::new_default(), Language::En);
+        let data_set = DataSet::new();
+        let virtual_fields = virtual_fields(value);
+
+        let now = start() + Duration::seconds(seconds);
+        let context = Context {
+            spec: &spec,
+            data_set: &data_set,
+            virtual_fields: &virtual_fields,
+            last_receive_time: start(),
+            started_at: start(),
+            now,
+        };
+
+        let (is_active, message) = evaluate(alarm, &context)?;
+        update_state(alarm, is_active, &message, now);
+        Some(is_active)
+    }
+
+    #[test]
+    fn test_above_uses_the_hysteresis_to_recover() {
+        let mut alarm = alarm(AlarmCondition::Above, 80.0, 5.0);
+
+        assert_eq!(Some(false), evaluate_at(&mut alarm, 0, Some(80.0)));
+        assert_eq!(Some(true), evaluate_at(&mut alarm, 1, Some(80.1)));
+        assert_eq!(Some(true), evaluate_at(&mut alarm, 2, Some(76.0)));
+        assert_eq!(Some(false), evaluate_at(&mut alarm, 3, Some(75.0)));
+        assert_eq!(Some(false), evaluate_at(&mut alarm, 4, Some(79.0)));
+    }
+
+    #[test]
+    fn test_below_uses_the_hysteresis_to_recover() {
+        let mut alarm = alarm(AlarmCondition::Below, 5.0, 2.0);
+
+        assert_eq!(Some(false), evaluate_at(&mut alarm, 0, Some(5.0)));
+        assert_eq!(Some(true), evaluate_at(&mut alarm, 1, Some(4.9)));
+        assert_eq!(Some(true), evaluate_at(&mut alarm, 2, Some(6.0)));
+        assert_eq!(Some(false), evaluate_at(&mut alarm, 3, Some(7.0)));
+        assert_eq!(Some(false), evaluate_at(&mut alarm, 4, Some(6.0)));
+    }
+
+    #[test]
+    fn test_unknown_value_keeps_the_state() {
+        let mut alarm = alarm(AlarmCondition::Above, 80.0, 0.0);
+
+        assert_eq!(Some(true), evaluate_at(&mut alarm, 0, Some(90.0)));
+        assert_eq!(None, evaluate_at(&mut alarm, 1, None));
+        assert!(alarm.is_active);
+        assert_eq!(Some(false), evaluate_at(&mut alarm, 2, Some(70.0)));
+    }
+
+    #[test]
+    fn test_rate_is_computed_per_minute() {
+        let mut alarm = alarm(AlarmCondition::Rate, 10.0, 2.0);
+
+        // The first sample has nothing to compare to
+        assert_eq!(None, evaluate_at(&mut alarm, 0, Some(20.0)));
+
+        // Samples that are not newer than the last one are skipped
+        assert_eq!(None, evaluate_at(&mut alarm, 0, Some(50.0)));
+        assert_eq!(Some((start(), 20.0)), alarm.last_sample);
+
+        // +6 in 30 seconds
+        assert_eq!(Some(true), evaluate_at(&mut alarm, 30, Some(26.0)));
+
+        // -4.5 in 30 seconds, still above the threshold minus the hysteresis
+        assert_eq!(Some(true), evaluate_at(&mut alarm, 60, Some(21.5)));
+
+        // +3 in 30 seconds
+        assert_eq!(Some(false), evaluate_at(&mut alarm, 90, Some(24.5)));
+
+        // No rate across an unknown value
+        assert_eq!(None, evaluate_at(&mut alarm, 120, None));
+        assert_eq!(None, evaluate_at(&mut alarm, 150, Some(100.0)));
+        assert_eq!(Some(false), evaluate_at(&mut alarm, 180, Some(100.0)));
+    }
+
+    #[test]
+    fn test_sensor_fault_values_match_within_tolerance() {
+        let mut alarm = alarm(AlarmCondition::SensorFault, 0.0, 0.0);
+
+        assert_eq!(Some(false), evaluate_at(&mut alarm, 0, Some(20.0)));
+        assert_eq!(Some(true), evaluate_at(&mut alarm, 1, Some(888.8)));
+        assert_eq!(Some(true), evaluate_at(&mut alarm, 2, Some(-999.86)));
+        assert_eq!(Some(false), evaluate_at(&mut alarm, 3, Some(999.84)));
+    }
+
+    #[test]
+    fn test_packet_missing_falls_back_to_the_start() {
+        let mut alarm = alarm(AlarmCondition::PacketMissing, 0.0, 0.0);
+
+        assert_eq!(Some(false), evaluate_at(&mut alarm, 60, None));
+        assert_eq!(Some(true), evaluate_at(&mut alarm, 61, None));
+    }
+
+    #[test]
+    fn test_connection_lost_after_duration() {
+        let mut alarm = alarm(AlarmCondition::ConnectionLost, 0.0, 0.0);
+
+        assert_eq!(Some(false), evaluate_at(&mut alarm, 60, None));
+        assert_eq!(Some(true), evaluate_at(&mut alarm, 61, None));
+    }
+
+    #[test]
+    fn test_active_alarm_is_only_repeated_after_the_repeat_interval() {
+        let mut alarm = alarm(AlarmCondition::Above, 80.0, 0.0);
+
+        assert!(update_state(&mut alarm, true, "", start()));
+        alarm.last_notified_at = Some(start());
+
+        // Without a repeat interval an active alarm is notified only once
+        assert!(!update_state(&mut alarm, true, "", start() + Duration::seconds(3600)));
+
+        alarm.repeat_interval = Some(Duration::seconds(600));
+        assert!(!update_state(&mut alarm, true, "", start() + Duration::seconds(599)));
+        assert!(update_state(&mut alarm, true, "", start() + Duration::seconds(600)));
+
+        // Recovering is always notified
+        assert!(update_state(&mut alarm, false, "", start() + Duration::seconds(601)));
+        assert!(!update_state(&mut alarm, false, "", start() + Duration::seconds(1800)));
+    }
+}
@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+
+use config::Config;
+
+
+/// Exponentially growing delay between reconnection attempts.
+#[derive(Debug)]
+pub struct Backoff {
+    min_delay: f64,
+    max_delay: f64,
+    factor: f64,
+    current_delay: f64,
+}
+
+
+impl Backoff {
+    /// Create a backoff using the `reconnect_*` settings of the `config`.
+    pub fn from_config(config: &Config) -> Backoff {
+        let min_delay = config.reconnect_min_delay.unwrap_or(1) as f64;
+        let max_delay = config.reconnect_max_delay.unwrap_or(60) as f64;
+        let factor = config.reconnect_backoff_factor.unwrap_or(2.0);
+
+        Backoff {
+            min_delay,
+            max_delay: max_delay.max(min_delay),
+            factor: factor.max(1.0),
+            current_delay: min_delay,
+        }
+    }
+
+    /// Start over with the minimum delay.
+    pub fn reset(&mut self) {
+        self.current_delay = self.min_delay;
+    }
+
+    /// Return the current delay and increase it for the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current_delay;
+
+        self.current_delay = (self.current_delay * self.factor).min(self.max_delay);
+
+        Duration::from_millis((delay * 1000.0) as u64)
+    }
+}
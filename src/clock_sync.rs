@@ -0,0 +1,96 @@
+use resol_vbus::{
+    chrono::prelude::*,
+    chrono::Duration,
+    Data,
+};
+
+
+use config::Config;
+use data_source::DataSource;
+use error::Result;
+use params::ParamClient;
+
+
+/// Periodically compares the controller's clock with the host's clock and
+/// corrects it if it drifted too far.
+///
+/// The controller's date / time parameter contains the number of seconds
+/// since 2001-01-01 00:00:00 local time. The `ClockSync` wraps the connection
+/// to the controller and is used as the `DataSource` of the logger, performing
+/// the checks between receiving data.
+#[derive(Debug)]
+pub struct ClockSync {
+    client: ParamClient,
+    value: String,
+    interval: Duration,
+    max_drift: Duration,
+    next_sync: DateTime<UTC>,
+}
+
+
+impl ClockSync {
+    /// Create a clock synchronization for the controller reachable using the
+    /// `client`, based on the `clock_sync_*` settings.
+    pub fn from_config(config: &Config, client: ParamClient) -> Result<ClockSync> {
+        let value = match config.clock_sync_value {
+            Some(ref value) => value.clone(),
+            None => return Err("Clock synchronization requires a `clock_sync_value`".into()),
+        };
+
+        Ok(ClockSync {
+            client,
+            value,
+            interval: Duration::seconds(config.clock_sync_interval.unwrap_or(3600)),
+            max_drift: Duration::seconds(config.clock_sync_max_drift.unwrap_or(30)),
+            next_sync: UTC::now(),
+        })
+    }
+
+    /// Check the controller's clock and correct it if necessary.
+    ///
+    /// Returns the drift that was detected, positive if the controller's
+    /// clock is ahead of the host's clock.
+    pub fn sync(&mut self) -> Result<Duration> {
+        let raw_value = self.client.get(&self.value)?;
+
+        let controller_now = controller_epoch() + Duration::seconds(raw_value as i64);
+        let drift = controller_now.signed_duration_since(Local::now().naive_local());
+
+        if drift.num_seconds().abs() > self.max_drift.num_seconds() {
+            let new_value = Local::now().naive_local().signed_duration_since(controller_epoch()).num_seconds() as i32;
+
+            self.client.set(&self.value, new_value, false)?;
+
+            info!("Controller clock drifted by {} seconds, corrected it", drift.num_seconds());
+        } else {
+            debug!("Controller clock drifted by {} seconds", drift.num_seconds());
+        }
+
+        Ok(drift)
+    }
+}
+
+
+impl DataSource for ClockSync {
+    fn receive_data(&mut self) -> Result<Option<Data>> {
+        let now = UTC::now();
+        if now >= self.next_sync {
+            self.next_sync = now + self.interval;
+
+            if let Err(err) = self.sync() {
+                warn!("Unable to synchronize controller clock: {}", err);
+            }
+        }
+
+        self.client.receive_data()
+    }
+
+    fn now(&self) -> DateTime<UTC> {
+        self.client.now()
+    }
+}
+
+
+fn controller_epoch() -> NaiveDateTime {
+    NaiveDate::from_ymd(2001, 1, 1).and_hms(0, 0, 0)
+}
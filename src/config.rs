@@ -1,46 +1,525 @@
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Read;
 
 use resol_vbus::SpecificationFile;
 
 use error::Result;
+use validation::validate_config;
 
 
-#[derive(Deserialize)]
+/// Horizontal alignment of a PNG label relative to its `x` position.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PngLabelAlign {
+    /// Text starts at `x`.
+    Left,
+    /// Text is centered around `x`.
+    Center,
+    /// Text ends at `x`.
+    Right,
+}
+
+
+/// How to handle packets that exceeded the `max_packet_age`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StalePacketMode {
+    /// Remove the packet from the data set.
+    Remove,
+    /// Keep the packet, but clear its values.
+    Null,
+}
+
+
+/// How the value of an `[[energy]]` field is aggregated.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EnergyFieldKind {
+    /// The field contains a power (e.g. W) that is integrated over time.
+    Power,
+    /// The field contains an energy counter (e.g. Wh) whose increments are summed up.
+    Counter,
+}
+
+
+/// The condition that activates an `[[alarm]]`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmCondition {
+    /// The field's value is above the `threshold`.
+    Above,
+    /// The field's value is below the `threshold`.
+    Below,
+    /// The field's value changes faster than `threshold` per minute.
+    Rate,
+    /// The field contains a value the controller uses to signal a sensor fault.
+    SensorFault,
+    /// The packet was not received for `duration` seconds.
+    PacketMissing,
+    /// No data was received for `duration` seconds.
+    ConnectionLost,
+}
+
+
+/// The way a `[[notifier]]` delivers notifications.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifierKind {
+    /// Run a local command.
+    Command,
+    /// Send an email using SMTP.
+    Smtp,
+    /// POST a JSON object to a URL.
+    Webhook,
+}
+
+
+/// How the connection to the SMTP server is secured.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Use TLS from the start (usually port 465).
+    Tls,
+    /// Upgrade the connection using STARTTLS (usually port 587).
+    Starttls,
+    /// Do not encrypt the connection (usually port 25).
+    None,
+}
+
+
+/// A text drawn onto the PNG.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PngLabelConfig {
+    /// Horizontal position in pixels.
+    pub x: i32,
+    /// Vertical position in pixels.
+    pub y: i32,
+    /// Font size in pixels.
+    pub size: Option<f32>,
+    /// RGBA color of the text.
+    pub color: Option<[u8; 4]>,
+    /// Alignment relative to `x`.
+    pub align: Option<PngLabelAlign>,
+    /// Packet field ID whose value is drawn.
+    pub field: Option<String>,
+    /// Whether to append the unit to the field value.
+    pub unit: Option<bool>,
+    /// strftime pattern used to draw the current local time.
+    pub timestamp: Option<String>,
+    /// Static text to draw.
+    pub text: Option<String>,
+    /// Format string, "{}" is replaced with the text.
+    pub format: Option<String>,
+    /// Text to draw if the field has no value.
+    pub fallback: Option<String>,
+}
+
+
+/// Settings of a PNG generator instance.
+#[derive(Debug, Deserialize)]
+pub struct PngConfig {
+    /// Name used to identify this instance in log messages.
+    pub name: Option<String>,
+    /// Number of seconds between each tick, 0 disables the instance.
+    pub tick_interval: i64,
+    /// Filename of the background image.
+    pub input_filename: String,
+    /// Filename of the output image.
+    pub output_filename: String,
+    /// The labels to draw onto the image.
+    #[serde(default)]
+    pub labels: Vec<PngLabelConfig>,
+}
+
+
+/// Settings of a CSV generator instance.
+#[derive(Debug, Deserialize)]
+pub struct CsvConfig {
+    /// Name used to identify this instance in log messages.
+    pub name: Option<String>,
+    /// Number of seconds between each tick, 0 disables the instance.
+    pub tick_interval: i64,
+    /// strftime pattern of the CSV filename.
+    pub output_filename_pattern: String,
+    /// Packet field IDs to write as columns, all fields if not set.
+    pub fields: Option<Vec<String>>,
+}
+
+
+/// Settings of a live data text generator instance.
+#[derive(Debug, Deserialize)]
+pub struct LiveDataTextConfig {
+    /// Name used to identify this instance in log messages.
+    pub name: Option<String>,
+    /// Number of seconds between each tick, 0 disables the instance.
+    pub tick_interval: i64,
+    /// Filename of the text file.
+    pub output_filename: String,
+}
+
+
+/// Settings of a SQLite logger instance.
+#[derive(Debug, Deserialize)]
+pub struct SqliteConfig {
+    /// Name used to identify this instance in log messages.
+    pub name: Option<String>,
+    /// Number of seconds between each tick, 0 disables the instance.
+    pub tick_interval: i64,
+    /// Filename of the database.
+    pub filename: String,
+    /// Name of the data sets table in relational mode.
+    pub datasets_table: Option<String>,
+    /// Name of the fields table in relational mode.
+    pub fields_table: Option<String>,
+    /// Insert statement in tabular mode.
+    pub statement: Option<String>,
+    /// Values bound to the insert statement in tabular mode.
+    pub fields: Option<Vec<String>>,
+}
+
+
+/// Settings of an MQTT publisher instance.
+#[derive(Debug, Deserialize)]
+pub struct MqttConfig {
+    /// Name used to identify this instance in log messages.
+    pub name: Option<String>,
+    /// Number of seconds between each tick, 0 disables the instance.
+    pub tick_interval: i64,
+    /// Host name of the broker.
+    pub host: Option<String>,
+    /// Port of the broker.
+    pub port: Option<u16>,
+    /// Client ID used to connect to the broker.
+    pub client_id: Option<String>,
+    /// Username used to connect to the broker.
+    pub username: Option<String>,
+    /// Password used to connect to the broker.
+    pub password: Option<String>,
+    /// Filename of a PEM encoded CA certificate, enables TLS.
+    pub tls_ca_filename: Option<String>,
+    /// Prefix prepended to every topic.
+    pub topic_prefix: Option<String>,
+    /// Quality of service level used for publications.
+    pub qos: Option<u8>,
+    /// Whether the broker should retain the published values.
+    pub retain: Option<bool>,
+    /// Topic prefix used for Home Assistant MQTT discovery.
+    pub discovery_prefix: Option<String>,
+}
+
+
+/// Settings of an InfluxDB generator instance.
+#[derive(Debug, Deserialize)]
+pub struct InfluxDbConfig {
+    /// Name used to identify this instance in log messages.
+    pub name: Option<String>,
+    /// Number of seconds between each tick, 0 disables the instance.
+    pub tick_interval: i64,
+    /// URL of the InfluxDB v2 write API.
+    pub url: Option<String>,
+    /// InfluxDB organization.
+    pub org: Option<String>,
+    /// InfluxDB bucket.
+    pub bucket: Option<String>,
+    /// InfluxDB API token.
+    pub token: Option<String>,
+    /// strftime pattern of the line protocol filename.
+    pub output_filename_pattern: Option<String>,
+    /// Whether to use sanitized field names instead of field IDs as keys.
+    pub field_names: Option<bool>,
+    /// Maximum number of lines buffered while the server is unreachable.
+    pub max_buffered_lines: Option<usize>,
+}
+
+
+/// Settings of a recording generator instance.
+#[derive(Debug, Deserialize)]
+pub struct RecordingConfig {
+    /// Name used to identify this instance in log messages.
+    pub name: Option<String>,
+    /// strftime pattern of the recording filename.
+    pub output_filename_pattern: String,
+}
+
+
+/// Settings of a datagram logger instance.
+#[derive(Debug, Deserialize)]
+pub struct DatagramsConfig {
+    /// Name used to identify this instance in log messages.
+    pub name: Option<String>,
+    /// strftime pattern of the JSON lines filename.
+    pub output_filename_pattern: Option<String>,
+    /// Filename of the SQLite database.
+    pub sqlite_filename: Option<String>,
+    /// Name of the SQLite table.
+    pub sqlite_table: Option<String>,
+    /// Whether to log VBus protocol 3.0 telegrams as well.
+    pub include_telegrams: Option<bool>,
+}
+
+
+/// The settings of an energy aggregator instance.
+#[derive(Debug, Deserialize)]
+pub struct EnergyConfig {
+    /// Name used in the virtual field IDs and the SQLite table.
+    pub name: String,
+    /// Packet field ID of the power or counter field.
+    pub field: String,
+    /// Whether the field contains a power or an energy counter.
+    pub kind: EnergyFieldKind,
+    /// Number of seconds between each sample (0 disables the aggregator).
+    pub tick_interval: i64,
+    /// Filename of the SQLite database.
+    pub sqlite_filename: String,
+    /// Name of the SQLite table.
+    pub sqlite_table: Option<String>,
+    /// Maximum number of seconds between two samples whose yield is added to
+    /// the daily, monthly and yearly totals.
+    pub max_gap: Option<i64>,
+    /// Number of Wh a counter has to drop by to be considered reset.
+    pub reset_threshold: Option<f64>,
+}
+
+
+/// The settings of a relay statistics instance.
+#[derive(Debug, Deserialize)]
+pub struct RelayConfig {
+    /// Name used in the virtual field IDs and the SQLite table.
+    pub name: String,
+    /// Packet field ID of the relay's on / off or speed field.
+    pub field: String,
+    /// Filename of the SQLite database.
+    pub sqlite_filename: String,
+    /// Name of the SQLite table.
+    pub sqlite_table: Option<String>,
+    /// Maximum number of seconds between two samples that are counted.
+    pub max_gap: Option<i64>,
+}
+
+
+/// The settings of a derived field.
+#[derive(Debug, Deserialize)]
+pub struct DerivedConfig {
+    /// Name used in the virtual field ID.
+    pub name: String,
+    /// Expression computing the value from packet fields and virtual fields.
+    pub expression: String,
+    /// Human-readable name of the field, defaults to the `name`.
+    pub label: Option<String>,
+    /// Unit text of the field.
+    pub unit: Option<String>,
+    /// Number of decimal places used when formatting the value.
+    pub precision: Option<usize>,
+}
+
+
+/// The settings of a summary logger instance.
+#[derive(Debug, Deserialize)]
+pub struct SummaryConfig {
+    /// Name used to identify this instance in log messages.
+    pub name: Option<String>,
+    /// Number of seconds in each summarized interval (0 disables the logger).
+    pub tick_interval: i64,
+    /// Filename of the SQLite database.
+    pub sqlite_filename: String,
+    /// Name of the SQLite table.
+    pub sqlite_table: Option<String>,
+    /// Packet field IDs to summarize, defaults to all fields.
+    pub fields: Option<Vec<String>>,
+}
+
+
+/// The settings of an alarm.
+#[derive(Debug, Deserialize)]
+pub struct AlarmConfig {
+    /// Name used in log messages and notifications.
+    pub name: String,
+    /// The condition that activates the alarm.
+    pub condition: AlarmCondition,
+    /// Packet field ID or virtual field ID the condition is evaluated for.
+    pub field: Option<String>,
+    /// Packet ID for the `packet_missing` condition.
+    pub packet: Option<String>,
+    /// Threshold for the `above`, `below` and `rate` conditions.
+    pub threshold: Option<f64>,
+    /// Amount the value has to fall back behind the threshold to recover.
+    pub hysteresis: Option<f64>,
+    /// Sentinel values for the `sensor_fault` condition.
+    pub values: Option<Vec<f64>>,
+    /// Number of seconds for the `packet_missing` and `connection_lost` conditions.
+    pub duration: Option<i64>,
+    /// Names of the notifiers to use, defaults to all notifiers.
+    pub notifiers: Option<Vec<String>>,
+    /// Number of seconds after which an active alarm is notified again.
+    pub repeat_interval: Option<i64>,
+}
+
+
+/// The settings of a notifier.
+#[derive(Debug, Deserialize)]
+pub struct NotifierConfig {
+    /// Name used to reference the notifier in the `notifiers` of an `[[alarm]]`.
+    pub name: String,
+    /// The way notifications are delivered.
+    pub kind: NotifierKind,
+    /// Command to run for the `command` kind.
+    pub command: Option<String>,
+    /// Arguments passed to the command.
+    pub args: Option<Vec<String>>,
+    /// URL to POST to for the `webhook` kind.
+    pub url: Option<String>,
+    /// Host name of the SMTP server.
+    pub smtp_host: Option<String>,
+    /// Port of the SMTP server.
+    pub smtp_port: Option<u16>,
+    /// How the connection to the SMTP server is secured.
+    pub smtp_security: Option<SmtpSecurity>,
+    /// User name to log into the SMTP server.
+    pub smtp_username: Option<String>,
+    /// Password to log into the SMTP server.
+    pub smtp_password: Option<String>,
+    /// Sender address of the emails.
+    pub from: Option<String>,
+    /// Recipient addresses of the emails.
+    pub to: Option<Vec<String>>,
+}
+
+
+/// The settings loaded from the `config.toml`.
+#[derive(Debug, Deserialize)]
 pub struct Config {
+    /// Path of the serial port.
     pub path: Option<String>,
 
+    /// Address of the VBus-over-TCP device.
     pub address: Option<String>,
+    /// VBus.net via tag of the VBus-over-TCP device.
     pub via_tag: Option<String>,
+    /// Password of the VBus-over-TCP device.
     pub password: Option<String>,
+    /// Channel of a multi-channel VBus-over-TCP device.
     pub channel: Option<u8>,
+    /// Number of seconds without data until the connection is considered lost.
     pub timeout: Option<i64>,
+    /// Packet IDs expected on this connection.
     pub known_packet_ids: Vec<String>,
 
-    pub png_tick_interval: i64,
-    pub png_input_filename: String,
-    pub png_output_filename: String,
+    /// Files to replay instead of connecting to a live VBus.
+    pub replay_filenames: Option<Vec<String>>,
+    /// RFC3339 timestamp at which raw captures started.
+    pub replay_raw_start_time: Option<String>,
 
-    pub csv_tick_interval: i64,
-    pub csv_output_filename_pattern: String,
+    /// Initial number of seconds to wait before reconnecting.
+    pub reconnect_min_delay: Option<u64>,
+    /// Maximum number of seconds to wait before reconnecting.
+    pub reconnect_max_delay: Option<u64>,
+    /// Factor applied to the reconnect delay after each failed attempt.
+    pub reconnect_backoff_factor: Option<f64>,
 
-    pub live_data_text_tick_interval: i64,
-    pub live_data_text_output_filename: String,
+    /// Address the HTTP server listens on.
+    pub http_address: Option<String>,
 
-    pub sqlite_tick_interval: i64,
-    pub sqlite_filename: String,
-    pub sqlite_datasets_table: Option<String>,
-    pub sqlite_fields_table: Option<String>,
-    pub sqlite_statement: Option<String>,
-    pub sqlite_fields: Option<Vec<String>>,
+    /// Address the VBus-over-TCP server listens on.
+    pub vbus_server_address: Option<String>,
+    /// Password VBus-over-TCP clients have to provide.
+    pub vbus_server_password: Option<String>,
+
+    /// Number of seconds after which a packet that was not received again is considered stale.
+    pub max_packet_age: Option<i64>,
+    /// How to handle stale packets.
+    pub stale_packet_mode: Option<StalePacketMode>,
+
+    /// Filename of the JSON file the settled packets are persisted to.
+    pub state_filename: Option<String>,
+    /// Whether to persist the packets' frame data as well.
+    pub state_save_values: Option<bool>,
+    /// Number of seconds between each save of the frame data.
+    pub state_tick_interval: Option<i64>,
 
+    /// Value index or ID name of the controller's date / time parameter.
+    pub clock_sync_value: Option<String>,
+    /// Number of seconds between each check of the controller's clock.
+    pub clock_sync_interval: Option<i64>,
+    /// Number of seconds the controller's clock may drift before it is corrected.
+    pub clock_sync_max_drift: Option<i64>,
+
+    /// Filename of the VBus Specification File.
     pub vsf_filename: Option<String>,
+
+    /// PNG generator instances.
+    #[serde(default)]
+    pub png: Vec<PngConfig>,
+
+    /// CSV generator instances.
+    #[serde(default)]
+    pub csv: Vec<CsvConfig>,
+
+    /// Live data text generator instances.
+    #[serde(default)]
+    pub live_data_text: Vec<LiveDataTextConfig>,
+
+    /// SQLite logger instances.
+    #[serde(default)]
+    pub sqlite: Vec<SqliteConfig>,
+
+    /// MQTT publisher instances.
+    #[serde(default)]
+    pub mqtt: Vec<MqttConfig>,
+
+    /// InfluxDB generator instances.
+    #[serde(default)]
+    pub influxdb: Vec<InfluxDbConfig>,
+
+    /// Recording generator instances.
+    #[serde(default)]
+    pub recording: Vec<RecordingConfig>,
+
+    /// Datagram logger instances.
+    #[serde(default)]
+    pub datagrams: Vec<DatagramsConfig>,
+
+    /// Energy aggregator instances.
+    #[serde(default)]
+    pub energy: Vec<EnergyConfig>,
+
+    /// Relay statistics instances.
+    #[serde(default)]
+    pub relay: Vec<RelayConfig>,
+
+    /// Derived fields.
+    #[serde(default)]
+    pub derived: Vec<DerivedConfig>,
+
+    /// Summary logger instances.
+    #[serde(default)]
+    pub summary: Vec<SummaryConfig>,
+
+    /// Alarms.
+    #[serde(default)]
+    pub alarm: Vec<AlarmConfig>,
+    /// Notifiers delivering the alarms.
+    #[serde(default)]
+    pub notifier: Vec<NotifierConfig>,
+
+    /// Top-level keys that are not known, e.g. settings of older versions.
+    #[serde(flatten)]
+    pub unknown_keys: BTreeMap<String, toml::Value>,
 }
 
 
 impl Config {
+    /// Load the `config.toml` from the current directory.
     pub fn load() -> Result<Config> {
-        let mut file = File::open("config.toml")?;
+        Config::load_from("config.toml")
+    }
+
+    /// Load the config from the file at `path`.
+    pub fn load_from(path: &str) -> Result<Config> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) => return Err(format!("Unable to open config file {:?}: {}", path, err).into()),
+        };
 
         let mut config_string = String::new();
 
@@ -51,6 +530,29 @@ impl Config {
         Ok(config)
     }
 
+    /// Check the config, reporting all problems found at once.
+    pub fn validate(&self) -> Result<()> {
+        let problems = validate_config(self);
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            let mut message = format!("Found {} problems in the config:", problems.len());
+            for problem in problems.iter() {
+                message.push_str("\n  - ");
+                message.push_str(problem);
+            }
+            Err(message.into())
+        }
+    }
+
+    /// Whether the `replay_filenames` are replayed instead of connecting to a
+    /// live VBus.
+    pub fn is_replaying(&self) -> bool {
+        self.replay_filenames.is_some() && self.path.is_none() && self.address.is_none()
+    }
+
+    /// Load the configured VSF file or fall back to the built-in one.
     pub fn load_spec_file(&self) -> Result<SpecificationFile> {
         let spec_file = match &self.vsf_filename {
             Some(filename) => {
@@ -0,0 +1,170 @@
+use std::io::{Read, Result as IoResult, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use resol_vbus::{
+    LiveDataStream,
+    ReadWithTimeout,
+    TcpConnector,
+};
+
+
+use config::Config;
+use data_source::DataSource;
+use error::{Error, Result};
+use serial_port_stream::SerialPortStream;
+use vbus_server::VBusServer;
+
+
+/// The reading half of a connection to a live VBus.
+pub trait VBusReader: Read + ReadWithTimeout + Send {}
+
+
+impl<T: Read + ReadWithTimeout + Send> VBusReader for T {}
+
+
+impl ReadWithTimeout for Box<dyn VBusReader> {
+    fn read_with_timeout(&mut self, buf: &mut [u8], timeout: Option<Duration>) -> IoResult<usize> {
+        (**self).read_with_timeout(buf, timeout)
+    }
+}
+
+
+/// A `LiveDataStream` connected using a `ConnectionBuilder`.
+pub type LiveStream = LiveDataStream<Box<dyn VBusReader>, Box<dyn Write + Send>>;
+
+
+/// Describes how to connect to a live VBus, either using a serial port or a
+/// VBus-over-TCP device.
+///
+/// ```no_run
+/// use resol_vbus_logger::ConnectionBuilder;
+///
+/// let source = ConnectionBuilder::new()
+///     .address("192.168.0.10:7053")
+///     .password("vbus")
+///     .connect()
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionBuilder {
+    path: Option<String>,
+    address: Option<String>,
+    via_tag: Option<String>,
+    password: Option<String>,
+    channel: Option<u8>,
+    server: Option<VBusServer>,
+}
+
+
+impl ConnectionBuilder {
+    /// Create a builder without any connection method configured.
+    pub fn new() -> ConnectionBuilder {
+        ConnectionBuilder::default()
+    }
+
+    /// Create a builder using the connection settings of the `config`.
+    pub fn from_config(config: &Config) -> ConnectionBuilder {
+        ConnectionBuilder {
+            path: config.path.clone(),
+            address: config.address.clone(),
+            via_tag: config.via_tag.clone(),
+            password: config.password.clone(),
+            channel: config.channel,
+            server: None,
+        }
+    }
+
+    /// Connect to the serial port at `path`.
+    pub fn path(mut self, path: &str) -> ConnectionBuilder {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    /// Connect to the VBus-over-TCP device at `address`.
+    pub fn address(mut self, address: &str) -> ConnectionBuilder {
+        self.address = Some(address.to_string());
+        self
+    }
+
+    /// Use the VBus.net via tag to connect to the VBus-over-TCP device.
+    pub fn via_tag(mut self, via_tag: &str) -> ConnectionBuilder {
+        self.via_tag = Some(via_tag.to_string());
+        self
+    }
+
+    /// Use the password to connect to the VBus-over-TCP device (defaults to "vbus").
+    pub fn password(mut self, password: &str) -> ConnectionBuilder {
+        self.password = Some(password.to_string());
+        self
+    }
+
+    /// Use the channel of a multi-channel VBus-over-TCP device (like the DL3).
+    pub fn channel(mut self, channel: u8) -> ConnectionBuilder {
+        self.channel = Some(channel);
+        self
+    }
+
+    /// Share the connection with the clients of the VBus-over-TCP `server`.
+    pub fn server(mut self, server: VBusServer) -> ConnectionBuilder {
+        self.server = Some(server);
+        self
+    }
+
+    /// Whether a connection method is configured.
+    pub fn is_configured(&self) -> bool {
+        self.path.is_some() || self.address.is_some()
+    }
+
+    /// Open the connection and return a `DataSource` for it.
+    pub fn connect(&self) -> Result<Box<dyn DataSource>> {
+        Ok(Box::new(self.connect_stream()?))
+    }
+
+    /// Open the connection and return the `LiveDataStream` for it, e.g. to
+    /// send datagrams to the controller.
+    pub fn connect_stream(&self) -> Result<LiveStream> {
+        let channel = self.channel.unwrap_or(0);
+
+        let (reader, writer): (Box<dyn VBusReader>, Box<dyn Write + Send>) = if let Some(ref path) = self.path {
+            debug!("Using serial port");
+
+            debug!("Connecting serial port");
+            let port = serialport::new(path, 9600).open()?;
+
+            let reader = SerialPortStream::new(port.try_clone()?);
+            let writer = SerialPortStream::new(port);
+
+            (Box::new(reader), Box::new(writer))
+        } else if let Some(ref address) = self.address {
+            debug!("Using TCP stream");
+
+            debug!("Connection TCP stream");
+            let stream = TcpStream::connect(address)?;
+
+            debug!("Performing VBus-over-TCP handshake");
+            let mut tcp_connector = TcpConnector::new(stream);
+            tcp_connector.via_tag = self.via_tag.clone();
+            tcp_connector.password = self.password.clone().unwrap_or("vbus".to_string());
+            tcp_connector.channel = self.channel.clone();
+            tcp_connector.connect()?;
+
+            let reader = tcp_connector.into_inner();
+            let writer = reader.try_clone()?;
+
+            (Box::new(reader), Box::new(writer))
+        } else {
+            return Err(Error::from("Unexpected connection method"));
+        };
+
+        let (reader, writer) = match self.server {
+            Some(ref server) => server.attach(reader, writer),
+            None => (reader, writer),
+        };
+
+        debug!("Creating live data stream");
+        let lds = LiveDataStream::new(channel, 0x0020, reader, writer)?;
+
+        Ok(lds)
+    }
+}
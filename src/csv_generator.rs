@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::io::Write;
 
 use resol_vbus::{
@@ -9,40 +11,111 @@ use resol_vbus::{
 };
 
 
-use config::Config;
+use config::{Config, CsvConfig};
 use error::{Result};
+use output::Output;
 use timestamp_file_writer::TimestampFileWriter;
+use virtual_fields::VirtualFields;
 
 
+/// Appends a row containing VBus values to a CSV file on every tick.
 pub struct CsvGenerator {
-    pub spec: Specification,
-    pub file_writer: TimestampFileWriter<Local>,
-    pub id_hash: Option<u64>,
+    spec: Specification,
+    file_writer: TimestampFileWriter<Local>,
+    id_hash: Option<u64>,
+    fields: Option<Vec<String>>,
+}
+
+
+impl fmt::Debug for CsvGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CsvGenerator")
+            .field("file_writer", &self.file_writer)
+            .field("fields", &self.fields)
+            .finish()
+    }
 }
 
 
 impl CsvGenerator {
-    pub fn from_config(config: &Config) -> Result<CsvGenerator> {
+    /// Create a generator from the settings of a `[[csv]]` table.
+    pub fn from_config(config: &Config, csv_config: &CsvConfig) -> Result<CsvGenerator> {
         let spec_file = config.load_spec_file()?;
 
         let spec = Specification::from_file(spec_file, Language::De);
 
-        let file_writer = TimestampFileWriter::new(config.csv_output_filename_pattern.clone(), Local::now());
+        let file_writer = TimestampFileWriter::new(csv_config.output_filename_pattern.clone(), Local::now());
 
         Ok(CsvGenerator {
             spec,
             file_writer,
             id_hash: None,
+            fields: csv_config.fields.clone(),
         })
     }
 
-    pub fn generate(&mut self, orig_data_set: &DataSet, now: &DateTime<UTC>) -> Result<()> {
+    fn columns(&self, data_set: &DataSet, virtual_fields: &VirtualFields) -> Vec<(String, String)> {
+        let mut columns = Vec::new();
+        let mut column_by_id = HashMap::new();
+
+        for field in self.spec.fields_in_data_set(data_set) {
+            let name = &field.field_spec().name;
+            let unit_text = field.field_spec().unit_text.trim();
+            let header = if unit_text.len() > 0 {
+                format!("{} [{}]", name, unit_text)
+            } else {
+                format!("{}", name)
+            };
+
+            let value = format!("{}", field.fmt_raw_value(false));
+
+            if self.fields.is_some() {
+                let key = format!("{}_{}", field.packet_spec().packet_id, field.field_spec().field_id);
+                column_by_id.insert(key, (header, value));
+            } else {
+                columns.push((header, value));
+            }
+        }
+
+        for field in virtual_fields.iter() {
+            let header = if field.unit.len() > 0 {
+                format!("{} [{}]", field.name, field.unit)
+            } else {
+                field.name.clone()
+            };
+
+            let value = field.fmt_value(false);
+
+            if self.fields.is_some() {
+                column_by_id.insert(field.id.clone(), (header, value));
+            } else {
+                columns.push((header, value));
+            }
+        }
+
+        if let Some(ref fields) = self.fields {
+            for field_id in fields.iter() {
+                match column_by_id.get(field_id) {
+                    Some(column) => columns.push(column.clone()),
+                    None => columns.push((field_id.clone(), String::new())),
+                }
+            }
+        }
+
+        columns
+    }
+
+    /// Append a row for the `data_set` and the `virtual_fields`, preceded by a
+    /// header if necessary.
+    pub fn generate(&mut self, orig_data_set: &DataSet, virtual_fields: &VirtualFields, now: &DateTime<UTC>) -> Result<()> {
         let mut data_set = orig_data_set.clone();
 
         data_set.sort();
 
         let local_now = now.with_timezone(&Local);
 
+        let columns = self.columns(&data_set, virtual_fields);
+
         let output = &mut self.file_writer;
 
         let is_new_file = output.set_timestamp(local_now)?;
@@ -70,14 +143,8 @@ impl CsvGenerator {
 
             write!(output, "Datum")?;
 
-            for field in self.spec.fields_in_data_set(&data_set) {
-                let name = &field.field_spec().name;
-                let unit_text = field.field_spec().unit_text.trim();
-                if unit_text.len() > 0 {
-                    write!(output, "\t{} [{}]", name, unit_text)?;
-                } else {
-                    write!(output, "\t{}", name)?;
-                }
+            for &(ref header, _) in columns.iter() {
+                write!(output, "\t{}", header)?;
             }
 
             write!(output, "\n")?;
@@ -85,8 +152,8 @@ impl CsvGenerator {
 
         write!(output, "{}", local_now.format("%Y.%m.%d %H:%M:%S"))?;
 
-        for field in self.spec.fields_in_data_set(&data_set) {
-            write!(output, "\t{}", field.fmt_raw_value(false))?;
+        for &(_, ref value) in columns.iter() {
+            write!(output, "\t{}", value)?;
         }
 
         write!(output, "\n")?;
@@ -95,4 +162,11 @@ impl CsvGenerator {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+
+impl Output for CsvGenerator {
+    fn on_tick(&mut self, data_set: &DataSet, virtual_fields: &VirtualFields, now: &DateTime<UTC>) -> Result<()> {
+        self.generate(data_set, virtual_fields, now)
+    }
+}
@@ -0,0 +1,61 @@
+use std::io::{Read, Write};
+
+use resol_vbus::{
+    chrono::prelude::*,
+    Data,
+    LiveDataStream,
+    ReadWithTimeout,
+};
+
+
+use error::Result;
+
+
+/// A source of VBus data that also provides the clock used to drive the tick sources.
+pub trait DataSource {
+    /// Try to receive the next `Data`, returning `None` if nothing arrived in time.
+    fn receive_data(&mut self) -> Result<Option<Data>>;
+
+    /// The current time according to this source.
+    fn now(&self) -> DateTime<UTC>;
+
+    /// The timestamp the logger starts at, used to seed the tick sources.
+    fn start_time(&mut self) -> Result<DateTime<UTC>> {
+        Ok(self.now())
+    }
+
+    /// Whether the source has run out of data and will never produce more.
+    fn is_finished(&self) -> bool {
+        false
+    }
+}
+
+
+impl<S: DataSource + ?Sized> DataSource for Box<S> {
+    fn receive_data(&mut self) -> Result<Option<Data>> {
+        (**self).receive_data()
+    }
+
+    fn now(&self) -> DateTime<UTC> {
+        (**self).now()
+    }
+
+    fn start_time(&mut self) -> Result<DateTime<UTC>> {
+        (**self).start_time()
+    }
+
+    fn is_finished(&self) -> bool {
+        (**self).is_finished()
+    }
+}
+
+
+impl<R: Read + ReadWithTimeout, W: Write> DataSource for LiveDataStream<R, W> {
+    fn receive_data(&mut self) -> Result<Option<Data>> {
+        Ok(self.receive(500)?)
+    }
+
+    fn now(&self) -> DateTime<UTC> {
+        UTC::now()
+    }
+}
@@ -0,0 +1,200 @@
+use std::fmt;
+use std::io::Write;
+
+use resol_vbus::{
+    chrono::prelude::*,
+    Data,
+};
+
+use sqlite::Connection;
+
+
+use config::DatagramsConfig;
+use error::Result;
+use output::Output;
+use timestamp_file_writer::TimestampFileWriter;
+
+
+#[derive(Serialize)]
+struct DatagramRecord {
+    timestamp: String,
+    id: String,
+    channel: u8,
+    destination_address: u16,
+    source_address: u16,
+    protocol_version: u8,
+    command: u16,
+    command_name: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_index: Option<i16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frame_data: Option<String>,
+}
+
+
+/// Logs VBus protocol 2.0 datagrams and optionally 3.0 telegrams, e.g. to
+/// audit parameter changes done on the controller.
+///
+/// Each datagram is decoded into its command, value index and value and
+/// appended to a JSON lines file and / or inserted into a SQLite table.
+pub struct DatagramLogger {
+    file_writer: Option<TimestampFileWriter<Local>>,
+    connection: Option<Connection>,
+    table: String,
+    include_telegrams: bool,
+}
+
+
+impl fmt::Debug for DatagramLogger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DatagramLogger")
+            .field("file_writer", &self.file_writer)
+            .field("table", &self.table)
+            .field("include_telegrams", &self.include_telegrams)
+            .finish()
+    }
+}
+
+
+impl DatagramLogger {
+    /// Create a logger from the settings of a `[[datagrams]]` table.
+    pub fn from_config(datagrams_config: &DatagramsConfig) -> Result<DatagramLogger> {
+        let file_writer = match datagrams_config.output_filename_pattern {
+            Some(ref pattern) => Some(TimestampFileWriter::new(pattern.clone(), Local::now())),
+            None => None,
+        };
+
+        let table = datagrams_config.sqlite_table.clone().unwrap_or("datagrams".to_string());
+
+        let connection = match datagrams_config.sqlite_filename {
+            Some(ref filename) => {
+                let connection = sqlite::open(filename)?;
+                let stmt = format!("CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY, timestamp TEXT, channel INTEGER, destination_address INTEGER, source_address INTEGER, protocol_version INTEGER, command INTEGER, command_name TEXT, value_index INTEGER, value INTEGER, frame_data TEXT)", table);
+                connection.execute(stmt)?;
+                Some(connection)
+            },
+            None => None,
+        };
+
+        Ok(DatagramLogger {
+            file_writer,
+            connection,
+            table,
+            include_telegrams: datagrams_config.include_telegrams.unwrap_or(false),
+        })
+    }
+
+    /// Log the `data` if it is a datagram or telegram.
+    pub fn log(&mut self, data: &Data) -> Result<()> {
+        let header = data.as_header();
+
+        let record = match *data {
+            Data::Packet(_) => return Ok(()),
+            Data::Datagram(ref dgram) => DatagramRecord {
+                timestamp: header.timestamp.to_rfc3339(),
+                id: data.id_string(),
+                channel: header.channel,
+                destination_address: header.destination_address,
+                source_address: header.source_address,
+                protocol_version: header.protocol_version,
+                command: dgram.command,
+                command_name: datagram_command_name(dgram.command),
+                value_index: Some(dgram.param16),
+                value: Some(dgram.param32),
+                frame_data: None,
+            },
+            Data::Telegram(ref tgram) => {
+                if !self.include_telegrams {
+                    return Ok(());
+                }
+
+                // Bits 5 and 6 of the command contain the number of frames
+                let frame_count = ((tgram.command >> 5) & 0x03) as usize;
+                let frame_data = tgram.frame_data[0..frame_count * 7].iter().map(|byte| format!("{:02X}", byte)).collect::<String>();
+
+                DatagramRecord {
+                    timestamp: header.timestamp.to_rfc3339(),
+                    id: data.id_string(),
+                    channel: header.channel,
+                    destination_address: header.destination_address,
+                    source_address: header.source_address,
+                    protocol_version: header.protocol_version,
+                    command: tgram.command as u16,
+                    command_name: "Telegram",
+                    value_index: None,
+                    value: None,
+                    frame_data: Some(frame_data),
+                }
+            },
+        };
+
+        if let Some(ref mut output) = self.file_writer {
+            output.set_timestamp(header.timestamp.with_timezone(&Local))?;
+
+            writeln!(output, "{}", serde_json::to_string(&record)?)?;
+
+            output.flush()?;
+        }
+
+        if let Some(ref connection) = self.connection {
+            let stmt = format!("INSERT INTO {} (timestamp, channel, destination_address, source_address, protocol_version, command, command_name, value_index, value, frame_data) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", self.table);
+            let mut stmt = connection.prepare(&stmt)?;
+
+            stmt.bind(1, record.timestamp.as_str())?;
+            stmt.bind(2, record.channel as i64)?;
+            stmt.bind(3, record.destination_address as i64)?;
+            stmt.bind(4, record.source_address as i64)?;
+            stmt.bind(5, record.protocol_version as i64)?;
+            stmt.bind(6, record.command as i64)?;
+            stmt.bind(7, record.command_name)?;
+            match record.value_index {
+                Some(value_index) => stmt.bind(8, value_index as i64)?,
+                None => stmt.bind(8, ())?,
+            }
+            match record.value {
+                Some(value) => stmt.bind(9, value as i64)?,
+                None => stmt.bind(9, ())?,
+            }
+            match record.frame_data {
+                Some(ref frame_data) => stmt.bind(10, frame_data.as_str())?,
+                None => stmt.bind(10, ())?,
+            }
+
+            while stmt.next()? != sqlite::State::Done {
+                // repeat
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+impl Output for DatagramLogger {
+    fn on_data(&mut self, data: &Data) -> Result<()> {
+        self.log(data)
+    }
+}
+
+
+/// Return a human-readable name for a VBus protocol 2.0 datagram command.
+pub fn datagram_command_name(command: u16) -> &'static str {
+    match command {
+        0x0100 => "Answer",
+        0x0200 => "Set value",
+        0x0300 => "Get value",
+        0x0500 => "Bus clearance by master",
+        0x0600 => "Bus clearance by slave",
+        0x0F00 => "Ping",
+        0x1000 => "Get value ID hash by index",
+        0x1100 => "Get value index by ID hash",
+        0x1300 => "Get capabilities",
+        0x1400 => "Begin bulk value transaction",
+        0x1402 => "Commit bulk value transaction",
+        0x1404 => "Rollback bulk value transaction",
+        0x1500 => "Set bulk value",
+        _ => "Unknown",
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use resol_vbus::{
+    DataSet,
+    Language,
+    Specification,
+};
+
+
+use config::{Config, DerivedConfig};
+use error::Result;
+use expression::Expression;
+use output::Output;
+use virtual_fields::{VirtualField, VirtualFields};
+
+
+struct DerivedField {
+    id: String,
+    name: String,
+    unit: String,
+    precision: usize,
+    expression: Expression,
+    value: Option<f64>,
+}
+
+
+/// Computes the virtual fields defined by expressions in the `[[derived]]`
+/// tables, e.g. the difference between collector and store temperature.
+///
+/// Each field is provided as a virtual field named `derived_<name>`, so it can
+/// be used by all other outputs just like a packet field. Expressions can
+/// reference packet field IDs, the virtual fields of other outputs and the
+/// derived fields defined before them. The fields are evaluated whenever the
+/// data set changes.
+pub struct DerivedFields {
+    spec: Specification,
+    fields: Vec<DerivedField>,
+}
+
+
+impl fmt::Debug for DerivedFields {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DerivedFields")
+            .field("fields", &self.fields.iter().map(|field| &field.id).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+
+impl DerivedFields {
+    /// Create the fields defined in the `[[derived]]` tables of the `config`.
+    pub fn from_config(config: &Config) -> Result<DerivedFields> {
+        let spec_file = config.load_spec_file()?;
+
+        let spec = Specification::from_file(spec_file, Language::En);
+
+        let mut fields = Vec::new();
+        for derived_config in config.derived.iter() {
+            let expression = match Expression::parse(&derived_config.expression) {
+                Ok(expression) => expression,
+                Err(err) => return Err(format!("Unable to parse expression of derived field {}: {}", derived_config.name, err).into()),
+            };
+
+            fields.push(DerivedField {
+                id: DerivedFields::field_id(derived_config),
+                name: derived_config.label.clone().unwrap_or(derived_config.name.clone()),
+                unit: derived_config.unit.clone().unwrap_or(String::new()),
+                precision: derived_config.precision.unwrap_or(1),
+                expression,
+                value: None,
+            });
+        }
+
+        Ok(DerivedFields {
+            spec,
+            fields,
+        })
+    }
+
+    /// The ID of the virtual field defined by the `derived_config`.
+    pub fn field_id(derived_config: &DerivedConfig) -> String {
+        format!("derived_{}", derived_config.name)
+    }
+
+    /// Evaluate all expressions against the `data_set` and the `virtual_fields`.
+    pub fn update(&mut self, data_set: &DataSet, virtual_fields: &VirtualFields) {
+        let mut values = HashMap::new();
+
+        let derived_field_ids = self.fields.iter().map(|field| field.id.clone()).collect::<HashSet<_>>();
+
+        for field in self.spec.fields_in_data_set(data_set) {
+            if let Some(value) = field.raw_value_f64() {
+                values.insert(field.packet_field_id().packet_field_id_string(), value);
+            }
+        }
+
+        for field in self.fields.iter_mut() {
+            // Never fall back to the previous values of the derived fields
+            let value = field.expression.evaluate(&|id: &str| match values.get(id) {
+                Some(&value) => Some(value),
+                None if derived_field_ids.contains(id) => None,
+                None => virtual_fields.value(id),
+            });
+
+            // Make the value available to the fields defined after this one
+            if let Some(value) = value {
+                values.insert(field.id.clone(), value);
+            }
+
+            field.value = value;
+        }
+    }
+}
+
+
+impl Output for DerivedFields {
+    fn on_data_set_changed(&mut self, data_set: &DataSet, virtual_fields: &VirtualFields) -> Result<()> {
+        self.update(data_set, virtual_fields);
+        Ok(())
+    }
+
+    fn provide_virtual_fields(&self, virtual_fields: &mut VirtualFields) {
+        for field in self.fields.iter() {
+            virtual_fields.set(VirtualField {
+                id: field.id.clone(),
+                name: field.name.clone(),
+                unit: field.unit.clone(),
+                precision: field.precision,
+                value: field.value,
+            });
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use resol_vbus::SpecificationFile;
+
+    use super::*;
+
+    fn derived_fields(definitions: &[(&str, &str)]) -> DerivedFields {
+        let fields = definitions.iter().map(|&(name, expression)| DerivedField {
+            id: format!("derived_{}", name),
+            name: name.to_string(),
+            unit: String::new(),
+            precision: 1,
+            expression: Expression::parse(expression).unwrap(),
+            value: None,
+        }).collect();
+
+        DerivedFields {
+            spec: Specification::from_file(SpecificationFile::new_default(), Language::En),
+            fields,
+        }
+    }
+
+    fn virtual_fields(values: &[(&str, f64)]) -> VirtualFields {
+        let mut virtual_fields = VirtualFields::new();
+        for &(id, value) in values.iter() {
+            virtual_fields.set(VirtualField {
+                id: id.to_string(),
+                name: id.to_string(),
+                unit: String::new(),
+                precision: 1,
+                value: Some(value),
+            });
+        }
+        virtual_fields
+    }
+
+    fn values(derived_fields: &DerivedFields) -> Vec<Option<f64>> {
+        let mut virtual_fields = VirtualFields::new();
+        derived_fields.provide_virtual_fields(&mut virtual_fields);
+        derived_fields.fields.iter().map(|field| virtual_fields.value(&field.id)).collect()
+    }
+
+    #[test]
+    fn test_later_fields_use_earlier_fields() {
+        let mut derived_fields = derived_fields(&[
+            ("a", "energy_solar_day * 2"),
+            ("b", "derived_a + 1"),
+        ]);
+
+        derived_fields.update(&DataSet::new(), &virtual_fields(&[("energy_solar_day", 1200.0)]));
+
+        assert_eq!(vec![Some(2400.0), Some(2401.0)], values(&derived_fields));
+    }
+
+    #[test]
+    fn test_self_and_later_references_are_unknown() {
+        let mut derived_fields = derived_fields(&[
+            ("a", "derived_a + 1"),
+            ("b", "derived_c + 1"),
+            ("c", "1"),
+        ]);
+
+        // The previous values provided as virtual fields must not be used
+        let virtual_fields = virtual_fields(&[
+            ("derived_a", 5.0),
+            ("derived_b", 5.0),
+            ("derived_c", 5.0),
+        ]);
+
+        derived_fields.update(&DataSet::new(), &virtual_fields);
+
+        assert_eq!(vec![None, None, Some(1.0)], values(&derived_fields));
+    }
+
+    #[test]
+    fn test_virtual_fields_are_used_as_fallback() {
+        let mut derived_fields = derived_fields(&[
+            ("a", "energy_solar_day / 1000"),
+            ("b", "energy_solar_week / 1000"),
+        ]);
+
+        derived_fields.update(&DataSet::new(), &virtual_fields(&[("energy_solar_day", 1200.0)]));
+
+        assert_eq!(vec![Some(1.2), None], values(&derived_fields));
+    }
+}
@@ -0,0 +1,427 @@
+use std::fmt;
+
+use resol_vbus::{
+    chrono::prelude::*,
+    chrono::Duration,
+    DataSet,
+    Language,
+    Specification,
+    ToPacketFieldId,
+};
+
+use sqlite::{
+    Connection,
+    State,
+};
+
+
+use config::{Config, EnergyConfig, EnergyFieldKind};
+use error::Result;
+use output::Output;
+use virtual_fields::{sample_field, VirtualField, VirtualFields};
+
+
+/// The periods totals are tracked for, with the strftime pattern used to
+/// identify the current period in the SQLite table.
+const PERIODS: &[(&str, &str)] = &[
+    ("day", "%Y-%m-%d"),
+    ("month", "%Y-%m"),
+    ("year", "%Y"),
+    ("total", ""),
+];
+
+
+#[derive(Default)]
+struct Total {
+    start: Option<String>,
+    value: f64,
+}
+
+
+/// Aggregates the yield of a power or energy counter field into daily,
+/// monthly, yearly and overall totals.
+///
+/// Power fields are integrated over time, skipping gaps longer than
+/// `max_gap`. For energy counters the increments are summed up, so that a
+/// counter that is reset (e.g. by a controller reboot) does not produce a
+/// negative yield. A counter that drops by less than `reset_threshold` is
+/// considered a glitch and the sample is skipped. Counter increments over
+/// gaps longer than `max_gap` are only added to the overall total.
+///
+/// The periods are determined by the timestamps of the samples, so that
+/// replayed data is attributed to the periods it was recorded in. The totals
+/// are stored in a SQLite table, from which they are restored when a period
+/// is entered, and provided as virtual fields named `energy_<name>_day`,
+/// `energy_<name>_month`, `energy_<name>_year` and `energy_<name>_total`
+/// (in Wh).
+pub struct EnergyAggregator {
+    spec: Specification,
+    name: String,
+    field_id: String,
+    kind: EnergyFieldKind,
+    factor: f64,
+    max_gap: Duration,
+    reset_threshold: f64,
+    connection: Connection,
+    table: String,
+    last_sample: Option<(DateTime<UTC>, f64)>,
+    totals: Vec<Total>,
+}
+
+
+impl fmt::Debug for EnergyAggregator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EnergyAggregator")
+            .field("name", &self.name)
+            .field("field_id", &self.field_id)
+            .field("kind", &self.kind)
+            .field("table", &self.table)
+            .finish()
+    }
+}
+
+
+impl EnergyAggregator {
+    /// Create an aggregator from the settings of an `[[energy]]` table.
+    pub fn from_config(config: &Config, energy_config: &EnergyConfig) -> Result<EnergyAggregator> {
+        let spec_file = config.load_spec_file()?;
+
+        let spec = Specification::from_file(spec_file, Language::En);
+
+        let id = energy_config.field.to_packet_field_id()?;
+
+        let unit_code = match spec.get_packet_spec_by_id(id.packet_id).get_field_spec(id.field_id) {
+            Some(field_spec) => field_spec.unit_code.clone(),
+            None => return Err(format!("Field {} is not described in the VSF", energy_config.field).into()),
+        };
+
+        // Convert everything to W and Wh
+        let factor = match (energy_config.kind, unit_code.as_str()) {
+            (EnergyFieldKind::Power, "Watts") => 1.0,
+            (EnergyFieldKind::Power, "Kilowatts") => 1000.0,
+            (EnergyFieldKind::Counter, "WattHours") => 1.0,
+            (EnergyFieldKind::Counter, "KilowattHours") => 1000.0,
+            (EnergyFieldKind::Counter, "MegawattHours") => 1000000.0,
+            (kind, unit_code) => return Err(format!("Field {} has unsupported unit {:?} for kind {:?}", energy_config.field, unit_code, kind).into()),
+        };
+
+        let table = energy_config.sqlite_table.clone().unwrap_or("energy".to_string());
+
+        let connection = sqlite::open(&energy_config.sqlite_filename)?;
+
+        let mut aggregator = EnergyAggregator {
+            spec,
+            name: energy_config.name.clone(),
+            field_id: energy_config.field.clone(),
+            kind: energy_config.kind,
+            factor,
+            max_gap: Duration::seconds(energy_config.max_gap.unwrap_or(300)),
+            reset_threshold: energy_config.reset_threshold.unwrap_or(1000.0),
+            connection,
+            table,
+            last_sample: None,
+            totals: PERIODS.iter().map(|_| Total::default()).collect(),
+        };
+
+        aggregator.restore()?;
+
+        Ok(aggregator)
+    }
+
+    /// Create the table if it does not exist and restore the last counter
+    /// sample, so that the yield produced while the logger was not running
+    /// is not lost.
+    fn restore(&mut self) -> Result<()> {
+        let stmt = format!("CREATE TABLE IF NOT EXISTS {} (name TEXT, period TEXT, start TEXT, value REAL, PRIMARY KEY (name, period, start))", self.table);
+        self.connection.execute(stmt)?;
+
+        if self.kind == EnergyFieldKind::Counter {
+            let value = self.load_value("counter", "")?;
+            let timestamp = self.load_value("counter_timestamp", "")?;
+            if let (Some(value), Some(timestamp)) = (value, timestamp) {
+                self.last_sample = Some((UTC.timestamp(timestamp as i64, 0), value));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The IDs of the virtual fields provided by an aggregator created from
+    /// the `energy_config`.
+    pub fn field_ids(energy_config: &EnergyConfig) -> Vec<String> {
+        PERIODS.iter().map(|&(period, _)| format!("energy_{}_{}", energy_config.name, period)).collect()
+    }
+
+    /// Sample the field in the `data_set` and update the totals.
+    pub fn aggregate(&mut self, data_set: &DataSet) -> Result<()> {
+        let (timestamp, value) = match sample_field(&self.spec, data_set, &self.field_id) {
+            Some((timestamp, raw_value)) => (timestamp, raw_value * self.factor),
+            None => return Ok(()),
+        };
+
+        self.add_sample(timestamp, value)
+    }
+
+    fn add_sample(&mut self, timestamp: DateTime<UTC>, value: f64) -> Result<()> {
+        let (energy, is_gap) = match self.last_sample {
+            // Packet has not been received again since the last sample
+            Some((last_timestamp, _)) if timestamp <= last_timestamp && self.kind == EnergyFieldKind::Power => return Ok(()),
+            Some((last_timestamp, last_value)) => {
+                let gap = timestamp.signed_duration_since(last_timestamp);
+                let is_gap = gap > self.max_gap;
+
+                let energy = match self.kind {
+                    EnergyFieldKind::Power => if is_gap {
+                        debug!("Not integrating {} over a gap of {} seconds", self.name, gap.num_seconds());
+                        0.0
+                    } else {
+                        (last_value + value) / 2.0 * gap.num_milliseconds() as f64 / 3600000.0
+                    },
+                    EnergyFieldKind::Counter => if value >= last_value {
+                        if is_gap {
+                            debug!("Adding the yield of {} over a gap of {} seconds to the total only", self.name, gap.num_seconds());
+                        }
+                        value - last_value
+                    } else if value < self.reset_threshold || last_value - value > self.reset_threshold {
+                        info!("Counter of {} was reset from {} Wh to {} Wh", self.name, last_value, value);
+                        value
+                    } else {
+                        debug!("Skipping counter value {} Wh of {}, it is below the last value {} Wh", value, self.name, last_value);
+                        return Ok(());
+                    },
+                };
+
+                (energy, is_gap)
+            },
+            None => (0.0, false),
+        };
+
+        self.last_sample = Some((timestamp, value));
+
+        let local_timestamp = timestamp.with_timezone(&Local);
+        for (index, &(period, pattern)) in PERIODS.iter().enumerate() {
+            let start = local_timestamp.format(pattern).to_string();
+            if self.totals[index].start.as_ref() != Some(&start) {
+                // Continue with the stored total, e.g. after a restart or when replaying data
+                let value = self.load_value(period, &start)?.unwrap_or(0.0);
+                self.totals[index] = Total {
+                    start: Some(start),
+                    value,
+                };
+            }
+
+            // The yield over a gap cannot be attributed to a period
+            if !is_gap || period == "total" {
+                self.totals[index].value += energy;
+            }
+        }
+
+        for (total, &(period, _)) in self.totals.iter().zip(PERIODS.iter()) {
+            if let Some(ref start) = total.start {
+                self.store_value(period, start, total.value)?;
+            }
+        }
+
+        if self.kind == EnergyFieldKind::Counter {
+            self.store_value("counter", "", value)?;
+            self.store_value("counter_timestamp", "", timestamp.timestamp() as f64)?;
+        }
+
+        Ok(())
+    }
+
+    fn load_value(&self, period: &str, start: &str) -> Result<Option<f64>> {
+        let stmt = format!("SELECT value FROM {} WHERE name = ? AND period = ? AND start = ?", self.table);
+        let mut stmt = self.connection.prepare(stmt)?;
+        stmt.bind(1, self.name.as_str())?;
+        stmt.bind(2, period)?;
+        stmt.bind(3, start)?;
+
+        let mut value = None;
+        while stmt.next()? == State::Row {
+            value = Some(stmt.read::<f64>(0)?);
+        }
+
+        Ok(value)
+    }
+
+    fn store_value(&self, period: &str, start: &str, value: f64) -> Result<()> {
+        let stmt = format!("INSERT OR REPLACE INTO {} (name, period, start, value) VALUES (?, ?, ?, ?)", self.table);
+        let mut stmt = self.connection.prepare(stmt)?;
+        stmt.bind(1, self.name.as_str())?;
+        stmt.bind(2, period)?;
+        stmt.bind(3, start)?;
+        stmt.bind(4, value)?;
+
+        while stmt.next()? != State::Done {
+            // repeat
+        }
+
+        Ok(())
+    }
+}
+
+
+impl Output for EnergyAggregator {
+    fn on_tick(&mut self, data_set: &DataSet, _virtual_fields: &VirtualFields, _now: &DateTime<UTC>) -> Result<()> {
+        self.aggregate(data_set)
+    }
+
+    fn provide_virtual_fields(&self, virtual_fields: &mut VirtualFields) {
+        for (total, &(period, _)) in self.totals.iter().zip(PERIODS.iter()) {
+            virtual_fields.set(VirtualField {
+                id: format!("energy_{}_{}", self.name, period),
+                name: format!("{} ({})", self.name, period),
+                unit: "Wh".to_string(),
+                precision: 0,
+                value: total.start.as_ref().map(|_| total.value),
+            });
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use resol_vbus::SpecificationFile;
+
+    use super::*;
+
+    fn temp_filename(name: &str) -> String {
+        let path = env::temp_dir().join(format!("resol_vbus_logger_energy_{}_{}", process::id(), name));
+        path.to_string_lossy().into_owned()
+    }
+
+    fn aggregator(kind: EnergyFieldKind, filename: &str) -> EnergyAggregator {
+        let mut aggregator = EnergyAggregator {
+            spec: Specification::from_file(SpecificationFile::new_default(), Language::En),
+            name: "test".to_string(),
+            field_id: "00_0010_7E11_10_0100_000_2_0".to_string(),
+            kind,
+            factor: 1.0,
+            max_gap: Duration::seconds(300),
+            reset_threshold: 1000.0,
+            connection: sqlite::open(filename).unwrap(),
+            table: "energy".to_string(),
+            last_sample: None,
+            totals: PERIODS.iter().map(|_| Total::default()).collect(),
+        };
+        aggregator.restore().unwrap();
+        aggregator
+    }
+
+    fn total(aggregator: &EnergyAggregator, period: &str) -> f64 {
+        let index = PERIODS.iter().position(|&(name, _)| name == period).unwrap();
+        aggregator.totals[index].value
+    }
+
+    fn noon() -> DateTime<UTC> {
+        Local.ymd(2024, 6, 1).and_hms(12, 0, 0).with_timezone(&UTC)
+    }
+
+    #[test]
+    fn test_counter_reset_adds_the_new_value() {
+        let mut aggregator = aggregator(EnergyFieldKind::Counter, ":memory:");
+        let start = noon();
+
+        aggregator.add_sample(start, 50000.0).unwrap();
+        aggregator.add_sample(start + Duration::seconds(60), 50500.0).unwrap();
+        assert_eq!(500.0, total(&aggregator, "day"));
+
+        // Drops to near zero
+        aggregator.add_sample(start + Duration::seconds(120), 200.0).unwrap();
+        assert_eq!(700.0, total(&aggregator, "day"));
+
+        aggregator.add_sample(start + Duration::seconds(180), 5000.0).unwrap();
+        assert_eq!(5500.0, total(&aggregator, "day"));
+
+        // Drops by more than the threshold
+        aggregator.add_sample(start + Duration::seconds(240), 3000.0).unwrap();
+        assert_eq!(8500.0, total(&aggregator, "day"));
+        assert_eq!(8500.0, total(&aggregator, "total"));
+    }
+
+    #[test]
+    fn test_counter_glitch_is_skipped() {
+        let mut aggregator = aggregator(EnergyFieldKind::Counter, ":memory:");
+        let start = noon();
+
+        aggregator.add_sample(start, 50000.0).unwrap();
+        aggregator.add_sample(start + Duration::seconds(60), 50100.0).unwrap();
+        aggregator.add_sample(start + Duration::seconds(120), 49950.0).unwrap();
+        assert_eq!(100.0, total(&aggregator, "day"));
+        assert_eq!(Some((start + Duration::seconds(60), 50100.0)), aggregator.last_sample);
+
+        aggregator.add_sample(start + Duration::seconds(180), 50200.0).unwrap();
+        assert_eq!(200.0, total(&aggregator, "day"));
+    }
+
+    #[test]
+    fn test_gaps_are_not_added_to_the_periods() {
+        let mut aggregator = aggregator(EnergyFieldKind::Power, ":memory:");
+        let start = noon();
+
+        aggregator.add_sample(start, 3600.0).unwrap();
+        aggregator.add_sample(start + Duration::seconds(60), 3600.0).unwrap();
+        assert_eq!(60.0, total(&aggregator, "day"));
+
+        aggregator.add_sample(start + Duration::seconds(660), 3600.0).unwrap();
+        assert_eq!(60.0, total(&aggregator, "day"));
+
+        aggregator.add_sample(start + Duration::seconds(720), 3600.0).unwrap();
+        assert_eq!(120.0, total(&aggregator, "day"));
+        assert_eq!(120.0, total(&aggregator, "total"));
+
+        let mut aggregator = self::aggregator(EnergyFieldKind::Counter, ":memory:");
+
+        aggregator.add_sample(start, 1000.0).unwrap();
+        aggregator.add_sample(start + Duration::seconds(3600), 1100.0).unwrap();
+        assert_eq!(0.0, total(&aggregator, "day"));
+        assert_eq!(100.0, total(&aggregator, "total"));
+    }
+
+    #[test]
+    fn test_periods_roll_over_at_the_sample_timestamps() {
+        let mut aggregator = aggregator(EnergyFieldKind::Power, ":memory:");
+        let start = Local.ymd(2024, 6, 1).and_hms(23, 59, 0).with_timezone(&UTC);
+
+        aggregator.add_sample(start, 3600.0).unwrap();
+        aggregator.add_sample(start + Duration::seconds(30), 3600.0).unwrap();
+        aggregator.add_sample(start + Duration::seconds(90), 3600.0).unwrap();
+
+        assert_eq!(Some(30.0), aggregator.load_value("day", "2024-06-01").unwrap());
+        assert_eq!(Some(60.0), aggregator.load_value("day", "2024-06-02").unwrap());
+        assert_eq!(60.0, total(&aggregator, "day"));
+        assert_eq!(90.0, total(&aggregator, "month"));
+        assert_eq!(90.0, total(&aggregator, "total"));
+    }
+
+    #[test]
+    fn test_counter_is_restored_with_its_timestamp() {
+        let filename = temp_filename("restore.sqlite");
+        let start = noon();
+
+        {
+            let mut aggregator = aggregator(EnergyFieldKind::Counter, &filename);
+            aggregator.add_sample(start, 1000.0).unwrap();
+            aggregator.add_sample(start + Duration::seconds(60), 1100.0).unwrap();
+        }
+
+        let mut aggregator = aggregator(EnergyFieldKind::Counter, &filename);
+        assert_eq!(Some((start + Duration::seconds(60), 1100.0)), aggregator.last_sample);
+
+        aggregator.add_sample(start + Duration::seconds(120), 1200.0).unwrap();
+        assert_eq!(200.0, total(&aggregator, "day"));
+
+        aggregator.add_sample(start + Duration::seconds(3720), 1300.0).unwrap();
+        assert_eq!(200.0, total(&aggregator, "day"));
+        assert_eq!(300.0, total(&aggregator, "total"));
+
+        drop(aggregator);
+        fs::remove_file(&filename).unwrap();
+    }
+}
@@ -4,6 +4,7 @@ use std::ops::Deref;
 use std::result::Result as StdResult;
 
 
+/// The error type used throughout this crate.
 #[derive(Debug)]
 pub struct Error {
     description: String,
@@ -12,6 +13,7 @@ pub struct Error {
 
 
 impl Error {
+    /// Create an error with an optional underlying cause.
     pub fn new(description: String, cause: Option<Box<dyn StdError>>) -> Error {
         Error {
             description,
@@ -61,21 +63,28 @@ impl From<String> for Error {
 
 
 macro_rules! from_other_error {
-    ($type:path) => {
+    ($type:path, $description:expr) => {
         impl From<$type> for Error {
             fn from(cause: $type) -> Error {
-                Error::new("An error occurred".to_owned(), Some(Box::new(cause)))
+                Error::new($description.to_owned(), Some(Box::new(cause)))
             }
         }
     };
 }
 
 
-from_other_error!(::std::io::Error);
-from_other_error!(::image::ImageError);
-from_other_error!(::serialport::Error);
-from_other_error!(::sqlite::Error);
-from_other_error!(::toml::de::Error);
+from_other_error!(::std::io::Error, "I/O error");
+from_other_error!(::std::time::SystemTimeError, "System time error");
+from_other_error!(::image::ImageError, "Image error");
+from_other_error!(::lettre::address::AddressError, "Invalid email address");
+from_other_error!(::lettre::error::Error, "Email error");
+from_other_error!(::lettre::transport::smtp::Error, "SMTP error");
+from_other_error!(::rumqttc::ClientError, "MQTT client error");
+from_other_error!(::serde_json::Error, "JSON error");
+from_other_error!(::serialport::Error, "Serial port error");
+from_other_error!(::sqlite::Error, "SQLite error");
+from_other_error!(::toml::de::Error, "Unable to parse config");
 
 
+/// The result type used throughout this crate.
 pub type Result<T> = StdResult<T, Error>;
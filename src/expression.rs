@@ -0,0 +1,394 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+
+use error::Result;
+
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Identifier(String),
+    Operator(char),
+    OpenParen,
+    CloseParen,
+    Comma,
+}
+
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Number(value) => write!(f, "`{}`", value),
+            Token::Identifier(ref name) => write!(f, "`{}`", name),
+            Token::Operator(op) => write!(f, "`{}`", op),
+            Token::OpenParen => write!(f, "`(`"),
+            Token::CloseParen => write!(f, "`)`"),
+            Token::Comma => write!(f, "`,`"),
+        }
+    }
+}
+
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars: Peekable<Chars> = input.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+                    word.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+
+            // Packet field IDs like "00_0010_7E11_10_0100_000_2_0" start with
+            // a digit as well, so only words that parse completely are numbers
+            let starts_like_number = word.starts_with(|c: char| c.is_ascii_digit() || c == '.');
+            match word.parse::<f64>() {
+                Ok(value) if starts_like_number => tokens.push(Token::Number(value)),
+                _ if word.contains('.') => return Err(format!("Invalid number `{}`", word).into()),
+                _ => tokens.push(Token::Identifier(word)),
+            }
+        } else {
+            let token = match c {
+                '+' | '-' | '*' | '/' | '%' | '^' => Token::Operator(c),
+                '(' => Token::OpenParen,
+                ')' => Token::CloseParen,
+                ',' => Token::Comma,
+                _ => return Err(format!("Unexpected character `{}`", c).into()),
+            };
+            tokens.push(token);
+            chars.next();
+        }
+    }
+
+    Ok(tokens)
+}
+
+
+/// The functions that can be called in an expression.
+const FUNCTIONS: &[(&str, usize)] = &[
+    ("abs", 1),
+    ("round", 1),
+    ("min", 2),
+    ("max", 2),
+];
+
+
+/// An arithmetic expression over packet field IDs and virtual field IDs.
+///
+/// Supports numbers, field IDs, the binary operators `+`, `-`, `*`, `/`, `%`
+/// and `^`, unary minus, parentheses and the functions `abs(x)`, `round(x)`,
+/// `min(a, b)` and `max(a, b)`, e.g. `00_0010_7E11_10_0100_000_2_0 * 1.8 + 32`.
+#[derive(Clone, Debug)]
+pub enum Expression {
+    /// A constant value.
+    Number(f64),
+    /// The value of a packet field or virtual field.
+    Field(String),
+    /// The negated value of the inner expression.
+    Negate(Box<Expression>),
+    /// A binary operation.
+    Binary(char, Box<Expression>, Box<Expression>),
+    /// A function call.
+    Call(String, Vec<Expression>),
+}
+
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        match self.next() {
+            Some(ref token) if *token == expected => Ok(()),
+            Some(token) => Err(format!("Expected {}, found {}", expected, token).into()),
+            None => Err(format!("Expected {}, found end of expression", expected).into()),
+        }
+    }
+
+    // sum := product (("+" | "-") product)*
+    fn parse_sum(&mut self) -> Result<Expression> {
+        let mut lhs = self.parse_product()?;
+
+        while let Some(&Token::Operator(op)) = self.peek() {
+            if op != '+' && op != '-' {
+                break;
+            }
+            self.next();
+            let rhs = self.parse_product()?;
+            lhs = Expression::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    // product := unary (("*" | "/" | "%") unary)*
+    fn parse_product(&mut self) -> Result<Expression> {
+        let mut lhs = self.parse_unary()?;
+
+        while let Some(&Token::Operator(op)) = self.peek() {
+            if op != '*' && op != '/' && op != '%' {
+                break;
+            }
+            self.next();
+            let rhs = self.parse_unary()?;
+            lhs = Expression::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    // unary := "-" unary | power
+    fn parse_unary(&mut self) -> Result<Expression> {
+        if let Some(&Token::Operator('-')) = self.peek() {
+            self.next();
+            let inner = self.parse_unary()?;
+            return Ok(Expression::Negate(Box::new(inner)));
+        }
+
+        self.parse_power()
+    }
+
+    // power := primary ("^" unary)?
+    fn parse_power(&mut self) -> Result<Expression> {
+        let base = self.parse_primary()?;
+
+        if let Some(&Token::Operator('^')) = self.peek() {
+            self.next();
+            let exponent = self.parse_unary()?;
+            return Ok(Expression::Binary('^', Box::new(base), Box::new(exponent)));
+        }
+
+        Ok(base)
+    }
+
+    // primary := number | identifier | identifier "(" arguments ")" | "(" sum ")"
+    fn parse_primary(&mut self) -> Result<Expression> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expression::Number(value)),
+            Some(Token::Identifier(name)) => {
+                if self.peek() != Some(&Token::OpenParen) {
+                    return Ok(Expression::Field(name));
+                }
+                self.next();
+
+                let mut args = Vec::new();
+                if self.peek() != Some(&Token::CloseParen) {
+                    args.push(self.parse_sum()?);
+                    while self.peek() == Some(&Token::Comma) {
+                        self.next();
+                        args.push(self.parse_sum()?);
+                    }
+                }
+                self.expect(Token::CloseParen)?;
+
+                match FUNCTIONS.iter().find(|&&(function, _)| function == name) {
+                    Some(&(_, arity)) if arity == args.len() => Ok(Expression::Call(name, args)),
+                    Some(&(_, arity)) => Err(format!("Function {} expects {} argument(s), found {}", name, arity, args.len()).into()),
+                    None => Err(format!("Unknown function `{}`", name).into()),
+                }
+            },
+            Some(Token::OpenParen) => {
+                let inner = self.parse_sum()?;
+                self.expect(Token::CloseParen)?;
+                Ok(inner)
+            },
+            Some(token) => Err(format!("Unexpected {}", token).into()),
+            None => Err("Unexpected end of expression".into()),
+        }
+    }
+}
+
+
+impl Expression {
+    /// Parse the `input`.
+    pub fn parse(input: &str) -> Result<Expression> {
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            position: 0,
+        };
+
+        let expression = parser.parse_sum()?;
+
+        match parser.next() {
+            Some(token) => Err(format!("Unexpected {} after end of expression", token).into()),
+            None => Ok(expression),
+        }
+    }
+
+    /// The IDs of all fields referenced in this expression.
+    pub fn field_ids(&self) -> Vec<String> {
+        let mut ids = Vec::new();
+        self.collect_field_ids(&mut ids);
+        ids
+    }
+
+    fn collect_field_ids(&self, ids: &mut Vec<String>) {
+        match *self {
+            Expression::Number(_) => {},
+            Expression::Field(ref id) => if !ids.contains(id) {
+                ids.push(id.clone());
+            },
+            Expression::Negate(ref inner) => inner.collect_field_ids(ids),
+            Expression::Binary(_, ref lhs, ref rhs) => {
+                lhs.collect_field_ids(ids);
+                rhs.collect_field_ids(ids);
+            },
+            Expression::Call(_, ref args) => for arg in args.iter() {
+                arg.collect_field_ids(ids);
+            },
+        }
+    }
+
+    /// Evaluate the expression, looking up field values using `value_of`.
+    /// Returns `None` if a field value is unknown or the result is not a
+    /// finite number (e.g. after a division by zero).
+    pub fn evaluate<F>(&self, value_of: &F) -> Option<f64> where F: Fn(&str) -> Option<f64> {
+        let value = match *self {
+            Expression::Number(value) => value,
+            Expression::Field(ref id) => value_of(id)?,
+            Expression::Negate(ref inner) => -inner.evaluate(value_of)?,
+            Expression::Binary(op, ref lhs, ref rhs) => {
+                let lhs = lhs.evaluate(value_of)?;
+                let rhs = rhs.evaluate(value_of)?;
+                match op {
+                    '+' => lhs + rhs,
+                    '-' => lhs - rhs,
+                    '*' => lhs * rhs,
+                    '/' => lhs / rhs,
+                    '%' => lhs % rhs,
+                    _ => lhs.powf(rhs),
+                }
+            },
+            Expression::Call(ref name, ref args) => {
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args.iter() {
+                    values.push(arg.evaluate(value_of)?);
+                }
+                match name.as_str() {
+                    "abs" => values[0].abs(),
+                    "round" => values[0].round(),
+                    "min" => values[0].min(values[1]),
+                    _ => values[0].max(values[1]),
+                }
+            },
+        };
+
+        if value.is_finite() {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(input: &str) -> Option<f64> {
+        Expression::parse(input).unwrap().evaluate(&|id: &str| match id {
+            "00_0010_7E11_10_0100_000_2_0" => Some(21.5),
+            "energy_solar_day" => Some(1200.0),
+            _ => None,
+        })
+    }
+
+    fn parse_error(input: &str) -> String {
+        Expression::parse(input).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(Some(7.0), evaluate("1 + 2 * 3"));
+        assert_eq!(Some(3.0), evaluate("10 - 4 - 3"));
+        assert_eq!(Some(2.0), evaluate("12 / 3 / 2"));
+        assert_eq!(Some(5.0), evaluate("1 + 9 % 5"));
+        assert_eq!(Some(19.0), evaluate("1 + 2 * 3 ^ 2"));
+        assert_eq!(Some(512.0), evaluate("2 ^ 3 ^ 2"));
+    }
+
+    #[test]
+    fn test_unary_minus() {
+        assert_eq!(Some(-3.0), evaluate("-3"));
+        assert_eq!(Some(3.0), evaluate("- -3"));
+        assert_eq!(Some(-6.0), evaluate("2 * -3"));
+        assert_eq!(Some(-4.0), evaluate("-2 ^ 2"));
+        assert_eq!(Some(0.25), evaluate("2 ^ -2"));
+    }
+
+    #[test]
+    fn test_parentheses() {
+        assert_eq!(Some(9.0), evaluate("(1 + 2) * 3"));
+        assert_eq!(Some(4.0), evaluate("(-2) ^ 2"));
+        assert_eq!(Some(-1.0), evaluate("((1) - (2))"));
+    }
+
+    #[test]
+    fn test_functions() {
+        assert_eq!(Some(3.0), evaluate("abs(-3)"));
+        assert_eq!(Some(3.0), evaluate("round(2.5)"));
+        assert_eq!(Some(1.0), evaluate("min(1, 2)"));
+        assert_eq!(Some(2.0), evaluate("max(1, 2)"));
+    }
+
+    #[test]
+    fn test_fields() {
+        assert_eq!(Some(70.7), evaluate("00_0010_7E11_10_0100_000_2_0 * 1.8 + 32"));
+        assert_eq!(Some(1.2), evaluate("energy_solar_day / 1000"));
+
+        let expression = Expression::parse("energy_solar_day + max(energy_solar_day, 00_0010_7E11_10_0100_000_2_0)").unwrap();
+        assert_eq!(vec!["energy_solar_day", "00_0010_7E11_10_0100_000_2_0"], expression.field_ids());
+    }
+
+    #[test]
+    fn test_unknown_identifiers_are_unknown() {
+        assert_eq!(None, evaluate("unknown_field + 1"));
+        assert_eq!(None, evaluate("max(1, unknown_field)"));
+    }
+
+    #[test]
+    fn test_division_by_zero_is_unknown() {
+        assert_eq!(None, evaluate("1 / 0"));
+        assert_eq!(None, evaluate("0 / 0"));
+        assert_eq!(None, evaluate("5 % 0"));
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        assert_eq!("Unexpected end of expression", parse_error("1 +"));
+        assert_eq!("Unexpected end of expression", parse_error(""));
+        assert_eq!("Expected `)`, found end of expression", parse_error("(1 + 2"));
+        assert_eq!("Expected `)`, found `3`", parse_error("max(1, 2 3)"));
+        assert_eq!("Unexpected `*`", parse_error("1 + * 2"));
+        assert_eq!("Unexpected `)`", parse_error(")"));
+        assert_eq!("Unexpected `2` after end of expression", parse_error("1 2"));
+        assert_eq!("Unexpected character `$`", parse_error("1 $ 2"));
+        assert_eq!("Invalid number `1.2.3`", parse_error("1.2.3"));
+        assert_eq!("Unknown function `foo`", parse_error("foo(1)"));
+        assert_eq!("Function min expects 2 argument(s), found 1", parse_error("min(1)"));
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use resol_vbus::{
+    chrono::prelude::*,
+    DataSet,
+    Language,
+    Specification,
+};
+
+use tiny_http::{
+    Header,
+    Request,
+    Response,
+    Server,
+};
+
+
+use config::Config;
+use error::Result;
+use stats::Stats;
+use tick_source::TickSource;
+use virtual_fields::VirtualFields;
+
+
+struct SharedState {
+    data_set: DataSet,
+    virtual_fields: VirtualFields,
+    stats: Stats,
+}
+
+
+#[derive(Serialize)]
+struct LiveField {
+    packet_field_id: String,
+    packet_id: String,
+    field_id: String,
+    packet_name: String,
+    field_name: String,
+    raw_value: Option<f64>,
+    formatted_value: String,
+    unit: String,
+    timestamp: Option<String>,
+}
+
+
+#[derive(Serialize)]
+struct LiveVirtualField {
+    id: String,
+    name: String,
+    value: Option<f64>,
+    formatted_value: String,
+    unit: String,
+}
+
+
+#[derive(Serialize)]
+struct LiveResponse {
+    settled: bool,
+    fields: Vec<LiveField>,
+    virtual_fields: Vec<LiveVirtualField>,
+}
+
+
+#[derive(Serialize)]
+struct PacketInfo {
+    packet_id: String,
+    name: Option<String>,
+    last_seen: String,
+}
+
+
+#[derive(Serialize)]
+struct PacketsResponse {
+    settled: bool,
+    packets: Vec<PacketInfo>,
+}
+
+
+/// Serves Prometheus metrics and JSON endpoints from a background thread.
+pub struct HttpServer {
+    state: Option<Arc<Mutex<SharedState>>>,
+    tick_source: TickSource,
+}
+
+
+impl fmt::Debug for HttpServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HttpServer")
+            .field("enabled", &self.state.is_some())
+            .finish()
+    }
+}
+
+
+impl HttpServer {
+    /// Start the server if `http_address` is configured and no recorded data
+    /// is replayed.
+    pub fn from_config(config: &Config) -> Result<HttpServer> {
+        let address = match config.http_address {
+            Some(ref address) if !config.is_replaying() => address,
+            _ => return Ok(HttpServer {
+                state: None,
+                tick_source: TickSource::new(0, UTC::now()),
+            }),
+        };
+
+        let server = match Server::http(address) {
+            Ok(server) => server,
+            Err(err) => return Err(format!("Unable to start HTTP server on {}: {}", address, err).into()),
+        };
+
+        let spec_file = config.load_spec_file()?;
+
+        let state = Arc::new(Mutex::new(SharedState {
+            data_set: DataSet::new(),
+            virtual_fields: VirtualFields::new(),
+            stats: Stats::default(),
+        }));
+
+        let thread_state = state.clone();
+
+        thread::spawn(move || {
+            let spec = Specification::from_file(spec_file, Language::En);
+
+            for request in server.incoming_requests() {
+                if let Err(err) = handle_request(&spec, &thread_state, request) {
+                    warn!("Unable to handle HTTP request: {}", err);
+                }
+            }
+        });
+
+        Ok(HttpServer {
+            state: Some(state),
+            tick_source: TickSource::new(1, UTC::now()),
+        })
+    }
+
+    /// Update the values served with a snapshot of the settled `data_set`
+    /// and the `virtual_fields`, at most once per second.
+    pub fn set_data(&mut self, data_set: &DataSet, virtual_fields: &VirtualFields, now: DateTime<UTC>) {
+        if let Some(ref state) = self.state {
+            if self.tick_source.process(now) {
+                let mut state = state.lock().unwrap();
+                state.data_set = data_set.clone();
+                state.virtual_fields = virtual_fields.clone();
+            }
+        }
+    }
+
+    /// Update the statistics served.
+    pub fn set_stats(&self, stats: &Stats) {
+        if let Some(ref state) = self.state {
+            state.lock().unwrap().stats = stats.clone();
+        }
+    }
+}
+
+
+fn handle_request(spec: &Specification, state: &Mutex<SharedState>, request: Request) -> Result<()> {
+    let path = request.url().split('?').next().unwrap_or("").to_string();
+
+    let response = match path.as_str() {
+        "/metrics" => {
+            let body = render_metrics(spec, &state.lock().unwrap());
+            Response::from_string(body).with_header(content_type("text/plain; version=0.0.4; charset=utf-8"))
+        },
+        "/api/live" => {
+            let body = serde_json::to_string(&render_live(spec, &state.lock().unwrap()))?;
+            json_response(body)
+        },
+        "/api/packets" => {
+            let body = serde_json::to_string(&render_packets(spec, &state.lock().unwrap()))?;
+            json_response(body)
+        },
+        _ => Response::from_string("Not Found").with_status_code(404),
+    };
+
+    request.respond(response)?;
+
+    Ok(())
+}
+
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], value.as_bytes()).unwrap()
+}
+
+
+fn json_response(body: String) -> Response<::std::io::Cursor<Vec<u8>>> {
+    Response::from_string(body)
+        .with_header(content_type("application/json; charset=utf-8"))
+        .with_header(Header::from_bytes(&b"Access-Control-Allow-Origin"[..], &b"*"[..]).unwrap())
+}
+
+
+fn packet_timestamps(data_set: &DataSet) -> HashMap<String, DateTime<UTC>> {
+    data_set.iter().map(|data| (data.id_string(), data.as_header().timestamp)).collect()
+}
+
+
+fn render_live(spec: &Specification, state: &SharedState) -> LiveResponse {
+    let mut data_set = state.data_set.clone();
+    data_set.sort();
+
+    let timestamps = packet_timestamps(&data_set);
+
+    let fields = spec.fields_in_data_set(&data_set).map(|field| {
+        let packet_spec = field.packet_spec();
+        let field_spec = field.field_spec();
+
+        LiveField {
+            packet_field_id: format!("{}_{}", packet_spec.packet_id, field_spec.field_id),
+            packet_id: packet_spec.packet_id.clone(),
+            field_id: field_spec.field_id.clone(),
+            packet_name: packet_spec.name.clone(),
+            field_name: field_spec.name.clone(),
+            raw_value: field.raw_value_f64(),
+            formatted_value: format!("{}", field.fmt_raw_value(true)),
+            unit: field_spec.unit_text.trim().to_string(),
+            timestamp: timestamps.get(&packet_spec.packet_id).map(|timestamp| timestamp.to_rfc3339()),
+        }
+    }).collect();
+
+    let virtual_fields = state.virtual_fields.iter().map(|field| LiveVirtualField {
+        id: field.id.clone(),
+        name: field.name.clone(),
+        value: field.value,
+        formatted_value: field.fmt_value(true),
+        unit: field.unit.clone(),
+    }).collect();
+
+    LiveResponse {
+        settled: state.stats.data_set_is_settled,
+        fields,
+        virtual_fields,
+    }
+}
+
+
+fn render_packets(spec: &Specification, state: &SharedState) -> PacketsResponse {
+    let mut data_set = state.data_set.clone();
+    data_set.sort();
+
+    let mut packet_names = HashMap::new();
+    for field in spec.fields_in_data_set(&data_set) {
+        let packet_spec = field.packet_spec();
+        packet_names.entry(packet_spec.packet_id.clone()).or_insert_with(|| packet_spec.name.clone());
+    }
+
+    let packets = data_set.iter().map(|data| {
+        let packet_id = data.id_string();
+
+        PacketInfo {
+            name: packet_names.get(&packet_id).cloned(),
+            packet_id,
+            last_seen: data.as_header().timestamp.to_rfc3339(),
+        }
+    }).collect();
+
+    PacketsResponse {
+        settled: state.stats.data_set_is_settled,
+        packets,
+    }
+}
+
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+
+fn render_metrics(spec: &Specification, state: &SharedState) -> String {
+    let mut output = String::new();
+
+    let mut data_set = state.data_set.clone();
+    data_set.sort();
+
+    output.push_str("# HELP vbus_field_value Current value of a VBus packet field.\n");
+    output.push_str("# TYPE vbus_field_value gauge\n");
+
+    for field in spec.fields_in_data_set(&data_set) {
+        if let Some(raw_value) = field.raw_value_f64() {
+            let packet_spec = field.packet_spec();
+            let field_spec = field.field_spec();
+
+            output.push_str(&format!(
+                "vbus_field_value{{packet_id=\"{}\",field_id=\"{}\",packet_field_id=\"{}_{}\",packet_name=\"{}\",field_name=\"{}\",unit=\"{}\"}} {}\n",
+                packet_spec.packet_id,
+                field_spec.field_id,
+                packet_spec.packet_id,
+                field_spec.field_id,
+                escape_label_value(&packet_spec.name),
+                escape_label_value(&field_spec.name),
+                escape_label_value(field_spec.unit_text.trim()),
+                raw_value,
+            ));
+        }
+    }
+
+    output.push_str("# HELP vbus_virtual_field_value Current value of a field computed by the logger.\n");
+    output.push_str("# TYPE vbus_virtual_field_value gauge\n");
+
+    for field in state.virtual_fields.iter() {
+        if let Some(value) = field.value {
+            output.push_str(&format!(
+                "vbus_virtual_field_value{{id=\"{}\",name=\"{}\",unit=\"{}\"}} {}\n",
+                escape_label_value(&field.id),
+                escape_label_value(&field.name),
+                escape_label_value(&field.unit),
+                value,
+            ));
+        }
+    }
+
+    output.push_str("# HELP vbus_packet_timestamp_seconds Time the VBus packet was last received.\n");
+    output.push_str("# TYPE vbus_packet_timestamp_seconds gauge\n");
+
+    for data in data_set.iter() {
+        output.push_str(&format!(
+            "vbus_packet_timestamp_seconds{{packet_id=\"{}\"}} {}\n",
+            data.id_string(),
+            data.as_header().timestamp.timestamp(),
+        ));
+    }
+
+    let stats = &state.stats;
+
+    output.push_str("# HELP vbus_logger_packets_received_total Number of VBus packets received.\n");
+    output.push_str("# TYPE vbus_logger_packets_received_total counter\n");
+    output.push_str(&format!("vbus_logger_packets_received_total {}\n", stats.packets_received));
+
+    if let Some(last_receive_time) = stats.last_receive_time {
+        output.push_str("# HELP vbus_logger_last_receive_timestamp_seconds Time the last VBus packet was received.\n");
+        output.push_str("# TYPE vbus_logger_last_receive_timestamp_seconds gauge\n");
+        output.push_str(&format!("vbus_logger_last_receive_timestamp_seconds {}\n", last_receive_time.timestamp()));
+    }
+
+    output.push_str("# HELP vbus_logger_data_set_settled Whether the set of VBus packets has settled.\n");
+    output.push_str("# TYPE vbus_logger_data_set_settled gauge\n");
+    output.push_str(&format!("vbus_logger_data_set_settled {}\n", if stats.data_set_is_settled { 1 } else { 0 }));
+
+    output.push_str("# HELP vbus_logger_reconnects_total Number of successful reconnections to the VBus.\n");
+    output.push_str("# TYPE vbus_logger_reconnects_total counter\n");
+    output.push_str(&format!("vbus_logger_reconnects_total {}\n", stats.reconnect_count));
+
+    output.push_str("# HELP vbus_logger_generator_errors_total Number of errors reported by generators.\n");
+    output.push_str("# TYPE vbus_logger_generator_errors_total counter\n");
+    output.push_str(&format!("vbus_logger_generator_errors_total {}\n", stats.generator_error_count));
+
+    output.push_str("# HELP vbus_logger_stale_packets Number of VBus packets that exceeded the maximum packet age.\n");
+    output.push_str("# TYPE vbus_logger_stale_packets gauge\n");
+    output.push_str(&format!("vbus_logger_stale_packets {}\n", stats.stale_packet_count));
+
+    output.push_str("# HELP vbus_logger_active_alarms Number of alarms that are currently active.\n");
+    output.push_str("# TYPE vbus_logger_active_alarms gauge\n");
+    output.push_str(&format!("vbus_logger_active_alarms {}\n", stats.active_alarm_count));
+
+    output
+}
@@ -0,0 +1,359 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::io::Write;
+use std::result::Result as StdResult;
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use resol_vbus::{
+    chrono::prelude::*,
+    DataSet,
+    Language,
+    Specification,
+};
+
+
+use config::{Config, InfluxDbConfig};
+use error::Result;
+use output::Output;
+use timestamp_file_writer::TimestampFileWriter;
+use virtual_fields::VirtualFields;
+
+
+/// The settings needed to POST lines to the InfluxDB write API.
+struct WriteTarget {
+    url: String,
+    org: Option<String>,
+    bucket: Option<String>,
+    token: Option<String>,
+}
+
+
+enum WriteError {
+    /// The server is unreachable or has a temporary problem, the lines should
+    /// be sent again later.
+    Retry(String),
+    /// The server rejected the lines, sending them again would fail as well.
+    Reject(String),
+}
+
+
+impl WriteTarget {
+    fn write(&self, body: &str) -> StdResult<(), WriteError> {
+        let mut request = ureq::post(&self.url)
+            .timeout(Duration::from_secs(10))
+            .query("precision", "s")
+            .set("Content-Type", "text/plain; charset=utf-8");
+
+        if let Some(ref org) = self.org {
+            request = request.query("org", org);
+        }
+        if let Some(ref bucket) = self.bucket {
+            request = request.query("bucket", bucket);
+        }
+        if let Some(ref token) = self.token {
+            request = request.set("Authorization", &format!("Token {}", token));
+        }
+
+        match request.send_string(body) {
+            Ok(_) => Ok(()),
+            // 429 means "too many requests", so that one is worth another try
+            Err(ureq::Error::Status(code, response)) if code >= 400 && code < 500 && code != 429 => {
+                let message = response.into_string().unwrap_or(String::new());
+                Err(WriteError::Reject(format!("status {}: {}", code, message.trim())))
+            },
+            Err(err) => Err(WriteError::Retry(err.to_string())),
+        }
+    }
+}
+
+
+fn write_pending_lines(target: &WriteTarget, pending_lines: &mut VecDeque<String>) {
+    if pending_lines.is_empty() {
+        return;
+    }
+
+    let body = pending_lines.iter().cloned().collect::<Vec<_>>().join("\n");
+
+    match target.write(&body) {
+        Ok(()) => pending_lines.clear(),
+        Err(WriteError::Retry(err)) => {
+            warn!("Unable to write {} lines to InfluxDB, retrying on next tick: {}", pending_lines.len(), err);
+        },
+        Err(WriteError::Reject(err)) => {
+            error!("InfluxDB rejected {} lines, dropping them: {}", pending_lines.len(), err);
+            pending_lines.clear();
+        },
+    }
+}
+
+
+/// POSTs the lines in a background thread, so that a slow or unreachable
+/// server does not stall the reception of VBus data.
+struct BackgroundWriter {
+    url: String,
+    sender: Option<Sender<Vec<String>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+
+impl fmt::Debug for BackgroundWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BackgroundWriter")
+            .field("url", &self.url)
+            .field("is_running", &self.sender.is_some())
+            .finish()
+    }
+}
+
+
+impl BackgroundWriter {
+    fn start(target: WriteTarget, max_pending_lines: usize) -> BackgroundWriter {
+        let url = target.url.clone();
+
+        let (sender, receiver) = channel::<Vec<String>>();
+
+        let thread = thread::spawn(move || {
+            let mut pending_lines = VecDeque::new();
+
+            for lines in receiver.iter() {
+                pending_lines.extend(lines);
+
+                // Combine the ticks that queued up while the last write was in progress
+                while let Ok(lines) = receiver.try_recv() {
+                    pending_lines.extend(lines);
+                }
+
+                let mut dropped_line_count = 0;
+                while pending_lines.len() > max_pending_lines {
+                    pending_lines.pop_front();
+                    dropped_line_count += 1;
+                }
+
+                if dropped_line_count > 0 {
+                    warn!("InfluxDB buffer is full, dropped {} lines", dropped_line_count);
+                }
+
+                write_pending_lines(&target, &mut pending_lines);
+            }
+
+            // Try once more to get rid of lines buffered while the server was unreachable
+            write_pending_lines(&target, &mut pending_lines);
+        });
+
+        BackgroundWriter {
+            url,
+            sender: Some(sender),
+            thread: Some(thread),
+        }
+    }
+
+    fn send(&self, lines: Vec<String>) {
+        if let Some(ref sender) = self.sender {
+            if sender.send(lines).is_err() {
+                error!("InfluxDB writer thread has stopped");
+            }
+        }
+    }
+
+    fn shutdown(&mut self) {
+        self.sender.take();
+
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("InfluxDB writer thread panicked");
+            }
+        }
+    }
+}
+
+
+/// Writes the VBus values in the InfluxDB line protocol on every tick.
+pub struct InfluxDbGenerator {
+    spec: Specification,
+    file_writer: Option<TimestampFileWriter<Local>>,
+    use_field_names: bool,
+    writer: Option<BackgroundWriter>,
+}
+
+
+impl fmt::Debug for InfluxDbGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("InfluxDbGenerator")
+            .field("file_writer", &self.file_writer)
+            .field("use_field_names", &self.use_field_names)
+            .field("writer", &self.writer)
+            .finish()
+    }
+}
+
+
+impl InfluxDbGenerator {
+    /// Create a generator from the settings of an `[[influxdb]]` table.
+    pub fn from_config(config: &Config, influxdb_config: &InfluxDbConfig) -> Result<InfluxDbGenerator> {
+        let spec_file = config.load_spec_file()?;
+
+        let spec = Specification::from_file(spec_file, Language::En);
+
+        let file_writer = match influxdb_config.output_filename_pattern {
+            Some(ref pattern) => Some(TimestampFileWriter::new(pattern.clone(), Local::now())),
+            None => None,
+        };
+
+        let writer = match influxdb_config.url {
+            Some(ref url) if influxdb_config.tick_interval > 0 => {
+                let target = WriteTarget {
+                    url: url.clone(),
+                    org: influxdb_config.org.clone(),
+                    bucket: influxdb_config.bucket.clone(),
+                    token: influxdb_config.token.clone(),
+                };
+
+                Some(BackgroundWriter::start(target, influxdb_config.max_buffered_lines.unwrap_or(10000)))
+            },
+            _ => None,
+        };
+
+        Ok(InfluxDbGenerator {
+            spec,
+            file_writer,
+            use_field_names: influxdb_config.field_names.unwrap_or(false),
+            writer,
+        })
+    }
+
+    /// Write the `data_set` and the `virtual_fields` to the file and / or the InfluxDB server.
+    pub fn generate(&mut self, data_set: &DataSet, virtual_fields: &VirtualFields, now: &DateTime<UTC>) -> Result<()> {
+        let lines = self.format_lines(data_set, virtual_fields, now);
+
+        if let Some(ref mut output) = self.file_writer {
+            output.set_timestamp(now.with_timezone(&Local))?;
+
+            for line in lines.iter() {
+                writeln!(output, "{}", line)?;
+            }
+
+            output.flush()?;
+        }
+
+        if let Some(ref writer) = self.writer {
+            writer.send(lines);
+        }
+
+        Ok(())
+    }
+
+    fn format_lines(&self, data_set: &DataSet, virtual_fields: &VirtualFields, now: &DateTime<UTC>) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        let mut current_packet_id: Option<String> = None;
+        let mut current_line = String::new();
+        let mut current_field_count = 0;
+        let mut current_field_keys = HashSet::new();
+
+        for field in self.spec.fields_in_data_set(data_set) {
+            let packet_spec = field.packet_spec();
+            let field_spec = field.field_spec();
+
+            let raw_value = match field.raw_value_f64() {
+                Some(raw_value) => raw_value,
+                None => continue,
+            };
+
+            if current_packet_id.as_ref() != Some(&packet_spec.packet_id) {
+                if current_field_count > 0 {
+                    lines.push(format!("{} {}", current_line, now.timestamp()));
+                }
+
+                current_packet_id = Some(packet_spec.packet_id.clone());
+                current_line = format!(
+                    "{},packet_id={},channel={},source_address={:04X} ",
+                    escape_measurement(&packet_spec.name),
+                    packet_spec.packet_id,
+                    packet_spec.channel,
+                    packet_spec.source_address,
+                );
+                current_field_count = 0;
+                current_field_keys.clear();
+            }
+
+            let mut field_key = if self.use_field_names {
+                sanitize_name(&field_spec.name)
+            } else {
+                field_spec.field_id.clone()
+            };
+
+            // Different names can sanitize to the same key, append the field ID
+            // to keep them apart
+            if field_key.is_empty() || current_field_keys.contains(&field_key) {
+                field_key = format!("{}_{}", field_key, field_spec.field_id).trim_start_matches('_').to_string();
+            }
+            current_field_keys.insert(field_key.clone());
+
+            if current_field_count > 0 {
+                current_line.push(',');
+            }
+            current_line.push_str(&format!("{}={}", escape_key(&field_key), raw_value));
+            current_field_count += 1;
+        }
+
+        if current_field_count > 0 {
+            lines.push(format!("{} {}", current_line, now.timestamp()));
+        }
+
+        let virtual_values = virtual_fields.iter()
+            .filter_map(|field| field.value.map(|value| format!("{}={}", escape_key(&field.id), value)))
+            .collect::<Vec<_>>();
+
+        if !virtual_values.is_empty() {
+            lines.push(format!("virtual_fields {} {}", virtual_values.join(","), now.timestamp()));
+        }
+
+        lines
+    }
+}
+
+
+fn escape_measurement(name: &str) -> String {
+    name.replace(',', "\\,").replace(' ', "\\ ")
+}
+
+
+fn escape_key(key: &str) -> String {
+    key.replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+
+fn sanitize_name(name: &str) -> String {
+    let mut result = String::new();
+
+    for c in name.to_lowercase().chars() {
+        if c.is_ascii_alphanumeric() {
+            result.push(c);
+        } else if !result.is_empty() && !result.ends_with('_') {
+            result.push('_');
+        }
+    }
+
+    while result.ends_with('_') {
+        result.pop();
+    }
+
+    result
+}
+
+
+impl Output for InfluxDbGenerator {
+    fn on_tick(&mut self, data_set: &DataSet, virtual_fields: &VirtualFields, now: &DateTime<UTC>) -> Result<()> {
+        self.generate(data_set, virtual_fields, now)
+    }
+
+    fn on_shutdown(&mut self) -> Result<()> {
+        if let Some(ref mut writer) = self.writer {
+            writer.shutdown();
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+// This is part of resol-vbus.rs.
+// Copyright (c) 2017, Daniel Wippermann.
+// See README.md and LICENSE.txt for details.
+
+//! # resol_vbus_logger
+//!
+//! The library behind the `logger` application. It can be used to embed VBus
+//! logging into other applications.
+//!
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use resol_vbus_logger::{Config, ConnectionBuilder, Logger};
+//!
+//! let config = Config::load().unwrap();
+//!
+//! let mut logger = Logger::from_config(&config).unwrap();
+//!
+//! let source = ConnectionBuilder::from_config(&config).connect().unwrap();
+//!
+//! logger.stream(source, Some(10)).unwrap();
+//! ```
+//!
+//! Custom sinks can be added by implementing the `Output` trait and adding
+//! them to the `OutputRegistry` returned by `Logger::outputs_mut`.
+//!
+
+#![warn(missing_docs)]
+#![deny(missing_debug_implementations)]
+#![deny(warnings)]
+
+
+extern crate image;
+extern crate imageproc;
+extern crate lettre;
+#[macro_use]
+extern crate log;
+extern crate resol_vbus;
+extern crate rumqttc;
+extern crate rusttype;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate serialport;
+extern crate sqlite;
+extern crate tiny_http;
+extern crate toml;
+extern crate ureq;
+
+
+/// Alarms evaluated against the received data.
+pub mod alarms;
+/// Exponential backoff between reconnection attempts.
+pub mod backoff;
+/// Periodic correction of the controller's clock.
+pub mod clock_sync;
+/// Configuration loaded from the `config.toml`.
+pub mod config;
+/// Builder for connections to a live VBus.
+pub mod connection;
+/// Output writing VBus values to CSV files.
+pub mod csv_generator;
+/// Sources of VBus data.
+pub mod data_source;
+/// Output logging datagrams and telegrams.
+pub mod datagram_logger;
+/// Output computing virtual fields from expressions.
+pub mod derived_fields;
+/// Output aggregating energy yields into daily, monthly and yearly totals.
+pub mod energy_aggregator;
+/// Error and result types.
+pub mod error;
+/// Arithmetic expressions over field values.
+pub mod expression;
+/// HTTP server providing Prometheus metrics and JSON endpoints.
+pub mod http_server;
+/// Output writing VBus values in the InfluxDB line protocol.
+pub mod influxdb_generator;
+/// Output writing VBus values to a text file.
+pub mod live_data_text_generator;
+/// Dispatching of received VBus data to the outputs.
+pub mod logger;
+/// Output publishing VBus values to an MQTT broker.
+pub mod mqtt_publisher;
+/// Delivery of alarm notifications.
+pub mod notifiers;
+/// The `Output` trait and the registry of outputs.
+pub mod output;
+/// Reading and writing controller parameters.
+pub mod params;
+/// Output rendering VBus values onto a PNG image.
+pub mod png_generator;
+/// Output recording all VBus data into `.vbus` files.
+pub mod recording_generator;
+/// Output computing relay runtime statistics.
+pub mod relay_statistics;
+/// Data source replaying recordings or raw captures.
+pub mod replay_source;
+/// Serial port adapter for the `LiveDataStream`.
+pub mod serial_port_stream;
+/// Detection of the set of packets transmitted on the VBus.
+pub mod settle_tracker;
+/// Output storing VBus values in a SQLite database.
+pub mod sqlite_logger;
+/// Detection of packets that are no longer received.
+pub mod stale_packets;
+/// Persistence of the settled packets across restarts.
+pub mod state_file;
+/// Statistics about the received data.
+pub mod stats;
+/// Output storing per-interval minimum, maximum and average values.
+pub mod summary_logger;
+/// Fixed interval timers.
+pub mod tick_source;
+/// Files whose name depends on the current time.
+pub mod timestamp_file_writer;
+/// Checks of the configuration.
+pub mod validation;
+/// VBus-over-TCP server re-sharing the VBus connection.
+pub mod vbus_server;
+/// Values computed by outputs and passed to all other outputs.
+pub mod virtual_fields;
+
+
+pub use config::Config;
+pub use connection::ConnectionBuilder;
+pub use data_source::DataSource;
+pub use error::{Error, Result};
+pub use logger::Logger;
+pub use output::{Output, OutputRegistry};
+pub use settle_tracker::{SettleEvent, SettleTracker};
+pub use stats::Stats;
+pub use virtual_fields::VirtualFields;
@@ -1,3 +1,4 @@
+use std::fmt;
 use std::fs::File;
 use std::io::Write;
 
@@ -9,23 +10,36 @@ use resol_vbus::{
 };
 
 
-use config::Config;
+use config::{Config, LiveDataTextConfig};
 use error::{Result};
+use output::Output;
+use virtual_fields::VirtualFields;
 
 
+/// Overwrites a text file with the current VBus values on every tick.
 pub struct LiveDataTextGenerator {
-    pub spec: Specification,
-    pub filename: String,
+    spec: Specification,
+    filename: String,
+}
+
+
+impl fmt::Debug for LiveDataTextGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LiveDataTextGenerator")
+            .field("filename", &self.filename)
+            .finish()
+    }
 }
 
 
 impl LiveDataTextGenerator {
-    pub fn from_config(config: &Config) -> Result<LiveDataTextGenerator> {
+    /// Create a generator from the settings of a `[[live_data_text]]` table.
+    pub fn from_config(config: &Config, live_data_text_config: &LiveDataTextConfig) -> Result<LiveDataTextGenerator> {
         let spec_file = config.load_spec_file()?;
 
         let spec = Specification::from_file(spec_file, Language::En);
 
-        let filename = config.live_data_text_output_filename.clone();
+        let filename = live_data_text_config.output_filename.clone();
 
         Ok(LiveDataTextGenerator {
             spec,
@@ -33,7 +47,8 @@ impl LiveDataTextGenerator {
         })
     }
 
-    pub fn generate(&mut self, orig_data_set: &DataSet, _now: &DateTime<UTC>) -> Result<()> {
+    /// Write all values of the `data_set` and the `virtual_fields` to the text file.
+    pub fn generate(&mut self, orig_data_set: &DataSet, virtual_fields: &VirtualFields, _now: &DateTime<UTC>) -> Result<()> {
         let mut data_set = orig_data_set.clone();
 
         data_set.sort();
@@ -49,9 +64,20 @@ impl LiveDataTextGenerator {
             write!(output, "{}_{};{};{};{}: {}\n", field.packet_spec().packet_id, field.field_spec().field_id, value, unit_text, packet_name, field_name)?;
         }
 
+        for field in virtual_fields.iter() {
+            writeln!(output, "{};{};{};{}", field.id, field.fmt_value(false), field.unit, field.name)?;
+        }
+
         output.flush()?;
         drop(output);
 
         Ok(())
     }
 }
+
+
+impl Output for LiveDataTextGenerator {
+    fn on_tick(&mut self, data_set: &DataSet, virtual_fields: &VirtualFields, now: &DateTime<UTC>) -> Result<()> {
+        self.generate(data_set, virtual_fields, now)
+    }
+}
@@ -0,0 +1,323 @@
+use std::fmt;
+use std::cmp;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use resol_vbus::{
+    chrono::prelude::*,
+    Data,
+    DataSet,
+    Language,
+    Specification,
+};
+
+
+use alarms::AlarmManager;
+use backoff::Backoff;
+use clock_sync::ClockSync;
+use config::Config;
+use connection::ConnectionBuilder;
+use data_source::DataSource;
+use error::{Error, Result};
+use http_server::HttpServer;
+use output::OutputRegistry;
+use params::ParamClient;
+use replay_source::ReplaySource;
+use settle_tracker::{SettleEvent, SettleTracker};
+use stale_packets::StalePacketDetector;
+use stats::Stats;
+use vbus_server::VBusServer;
+
+
+/// Receives VBus data from a `DataSource` and dispatches it to all configured outputs.
+pub struct Logger<'a> {
+    config: &'a Config,
+
+    settle_tracker: SettleTracker,
+    stale_packet_detector: StalePacketDetector,
+    alarm_manager: AlarmManager,
+
+    outputs: OutputRegistry<'a>,
+
+    http_server: HttpServer,
+    vbus_server: Option<VBusServer>,
+    stats: Stats,
+
+    connection_lost_at: Option<DateTime<UTC>>,
+    is_started: bool,
+}
+
+
+impl<'a> fmt::Debug for Logger<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Logger")
+            .field("settle_tracker", &self.settle_tracker)
+            .field("outputs", &self.outputs)
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+
+impl<'a> Logger<'a> {
+    /// Create a logger including all outputs configured in the `config`.
+    pub fn from_config(config: &'a Config) -> Result<Logger<'a>> {
+        config.validate()?;
+
+        let settle_tracker = SettleTracker::from_config(config)?;
+        let stale_packet_detector = StalePacketDetector::from_config(config);
+
+        // Seeded again from the first timestamp of the data source in `stream`
+        let now = UTC::now();
+
+        let alarm_manager = AlarmManager::from_config(config, now)?;
+
+        let mut outputs = OutputRegistry::from_config(&config, now)?;
+        outputs.init()?;
+
+        debug!("Initializing HTTP server");
+        let http_server = HttpServer::from_config(&config)?;
+
+        let vbus_server = VBusServer::from_config(&config)?;
+
+        let mut stats = Stats::default();
+
+        // The data set restored from the state file is settled right away
+        if settle_tracker.is_settled() {
+            outputs.on_data_set_changed(settle_tracker.data_set(), &mut stats);
+            outputs.on_settled(settle_tracker.data_set(), &mut stats);
+            stats.data_set_is_settled = true;
+        }
+
+        Ok(Logger {
+            config,
+            settle_tracker,
+            stale_packet_detector,
+            alarm_manager,
+            outputs,
+            http_server,
+            vbus_server,
+            stats,
+            connection_lost_at: None,
+            is_started: false,
+        })
+    }
+
+    /// The outputs of this logger. Additional outputs can be added here, but
+    /// they must already be initialized.
+    pub fn outputs_mut(&mut self) -> &mut OutputRegistry<'a> {
+        &mut self.outputs
+    }
+
+    /// The packets received so far.
+    pub fn data_set(&self) -> &DataSet {
+        self.settle_tracker.data_set()
+    }
+
+    /// The statistics about the data received so far.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Start the tick sources and alarms at `now`, the first timestamp
+    /// delivered by the data source. Replayed data starts in the past.
+    pub fn start(&mut self, now: DateTime<UTC>) {
+        self.outputs.start(now);
+        self.alarm_manager.start(now);
+        self.is_started = true;
+    }
+
+    /// Run the tick sources of all outputs.
+    pub fn process_ticks(&mut self, now: DateTime<UTC>) {
+        if self.stale_packet_detector.process(self.settle_tracker.data_set_mut(), now) {
+            self.outputs.on_data_set_changed(self.settle_tracker.data_set(), &mut self.stats);
+        }
+        self.stats.stale_packet_count = self.stale_packet_detector.stale_packet_count() as u64;
+
+        self.outputs.on_tick(self.settle_tracker.data_set(), self.settle_tracker.is_settled(), now, &mut self.stats);
+
+        if self.settle_tracker.is_settled() {
+            self.http_server.set_data(self.settle_tracker.data_set(), self.outputs.virtual_fields(), now);
+        }
+
+        self.process_alarms(now);
+    }
+
+    /// Evaluate the alarms.
+    pub fn process_alarms(&mut self, now: DateTime<UTC>) {
+        self.alarm_manager.process(self.settle_tracker.data_set(), self.outputs.virtual_fields(), self.stats.last_receive_time, now);
+        self.stats.active_alarm_count = self.alarm_manager.active_alarm_count() as u64;
+
+        self.http_server.set_stats(&self.stats);
+    }
+
+    /// Process a received data.
+    pub fn process_data(&mut self, data: Data) -> Result<()> {
+        self.outputs.on_data(&data, &mut self.stats);
+
+        let is_packet = data.is_packet();
+
+        if is_packet {
+            self.stats.packets_received += 1;
+            self.stats.last_receive_time = Some(data.as_header().timestamp);
+        }
+
+        let settle_event = self.settle_tracker.add_data(data);
+
+        if is_packet {
+            self.outputs.on_data_set_changed(self.settle_tracker.data_set(), &mut self.stats);
+        }
+
+        if settle_event == SettleEvent::Settled {
+            let mut sorted_data_set = self.settle_tracker.data_set().clone();
+            sorted_data_set.sort();
+            debug!("Settled {:?}", sorted_data_set.iter().map(|data| data.id_string()).collect::<Vec<_>>());
+
+            let spec_file = self.config.load_spec_file()?;
+            let spec = Specification::from_file(spec_file, Language::De);
+            for field in spec.fields_in_data_set(&sorted_data_set) {
+                debug!("  - {}: {}: {}", field.packet_field_id().packet_field_id_string(), field.packet_spec().name, field.field_spec().name);
+            }
+
+            self.outputs.on_settled(self.settle_tracker.data_set(), &mut self.stats);
+        }
+
+        self.stats.data_set_is_settled = self.settle_tracker.is_settled();
+        self.http_server.set_stats(&self.stats);
+
+        Ok(())
+    }
+
+    /// Receive data from the `source` until it is finished, an error occurs or
+    /// no data was received for `timeout` seconds.
+    pub fn stream<S: DataSource>(&mut self, mut source: S, timeout: Option<i64>) -> Result<()> {
+        if !self.is_started {
+            let start_time = source.start_time()?;
+            self.start(start_time);
+        }
+
+        let mut last_data_received = source.now();
+
+        loop {
+            let now = source.now();
+
+            self.process_ticks(now);
+
+            if let Some(data) = source.receive_data()? {
+                let now = source.now();
+
+                last_data_received = now;
+
+                if let Some(connection_lost_at) = self.connection_lost_at.take() {
+                    let outage = now.signed_duration_since(connection_lost_at);
+                    info!("Connection restored after an outage of {} seconds", outage.num_seconds());
+
+                    self.stats.reconnect_count += 1;
+                }
+
+                self.process_data(data)?;
+            } else if source.is_finished() {
+                return Ok(());
+            }
+
+            if let Some(timeout) = timeout {
+                let diff = now.signed_duration_since(last_data_received);
+                if diff.num_seconds() > timeout {
+                    return Err("Timeout while receiving live data".into());
+                }
+            }
+        }
+    }
+
+    /// Connect using the configured connection method and receive data,
+    /// reconnecting whenever the connection is lost.
+    pub fn run(&mut self) -> Result<()> {
+        let mut connection_builder = ConnectionBuilder::from_config(self.config);
+        if let Some(ref vbus_server) = self.vbus_server {
+            connection_builder = connection_builder.server(vbus_server.clone());
+        }
+
+        if !connection_builder.is_configured() {
+            if self.config.is_replaying() {
+                debug!("Using replay files");
+
+                let source = ReplaySource::from_config(self.config)?;
+
+                // Replayed data must not trigger real emails or webhooks
+                info!("Notifications are disabled while replaying");
+                self.alarm_manager.set_notifications_enabled(false);
+
+                self.stream(source, None)?;
+
+                self.shutdown();
+
+                return Ok(());
+            }
+
+            return Err(Error::from("Unexpected connection method"));
+        }
+
+        let mut backoff = Backoff::from_config(self.config);
+
+        loop {
+            let source = if self.config.clock_sync_value.is_some() {
+                let vbus_server = self.vbus_server.clone();
+                connection_builder.connect_stream()
+                    .and_then(|stream| {
+                        let mut client = ParamClient::new(stream);
+                        if let Some(vbus_server) = vbus_server {
+                            client = client.server(vbus_server);
+                        }
+                        ClockSync::from_config(self.config, client)
+                    })
+                    .map(|source| Box::new(source) as Box<dyn DataSource>)
+            } else {
+                connection_builder.connect()
+            };
+
+            let result = match source {
+                Ok(source) => self.stream(source, self.config.timeout),
+                Err(err) => Err(err),
+            };
+
+            let err = match result {
+                Ok(()) => {
+                    self.shutdown();
+                    return Ok(());
+                },
+                Err(err) => err,
+            };
+
+            if self.connection_lost_at.is_none() {
+                warn!("Connection lost: {}", err);
+                self.connection_lost_at = Some(UTC::now());
+                backoff.reset();
+            } else {
+                debug!("Unable to reconnect: {}", err);
+            }
+
+            let delay = backoff.next_delay();
+
+            info!("Reconnecting in {:.1} seconds", delay.as_secs_f64());
+
+            // Keep evaluating the alarms, e.g. to notify about the connection loss
+            let reconnect_at = Instant::now() + delay;
+            loop {
+                self.process_alarms(UTC::now());
+
+                let now = Instant::now();
+                if now >= reconnect_at {
+                    break;
+                }
+
+                thread::sleep(cmp::min(reconnect_at - now, Duration::from_secs(1)));
+            }
+        }
+    }
+
+    /// Notify all outputs that no more data will be received.
+    pub fn shutdown(&mut self) {
+        self.outputs.on_shutdown(&mut self.stats);
+        self.alarm_manager.shutdown();
+    }
+}
@@ -12,7 +12,28 @@
 //!
 //! - Connected either to a serial port or VBus-over-TCP device
 //! - Writes data to CSV file at configurable intervals
-//! - Renders a PNG containing data at configurable intervals
+//! - Renders a PNG containing data at configurable intervals, using a configurable label layout
+//! - Records all received VBus data into `.vbus` recording files
+//! - Publishes data to an MQTT broker at configurable intervals
+//! - Announces all values to Home Assistant using MQTT discovery
+//! - Serves Prometheus metrics over HTTP
+//! - Serves current values as JSON over HTTP
+//! - Writes data to InfluxDB or line protocol files at configurable intervals
+//! - Automatically reconnects after the connection was lost
+//! - Replays VBus recording files or raw captures instead of live data
+//! - Persists the settled packets across restarts to start producing output immediately
+//! - Detects packets that are no longer received and stops logging their last values
+//! - Logs datagrams and telegrams to audit parameter changes on the controller
+//! - Reads and writes controller parameters by value index or ID name
+//! - Keeps the controller's clock in sync with the host's clock
+//! - Re-shares the VBus connection with VBus-over-TCP clients like RESOL ServiceCenter
+//! - Aggregates power and heat quantity fields into daily, monthly and yearly yields
+//! - Tracks daily runtime, switch cycles and average speed of relays and pumps
+//! - Stores minimum, maximum, average and last values per interval to catch spikes between ticks
+//! - Raises alarms on thresholds, sensor faults, missing packets or connection loss and notifies using a command, email or webhook
+//! - Computes derived fields from expressions over packet and virtual fields, usable in all outputs
+//! - Runs any number of independently configured instances of each generator
+//! - Usable as a library (`resol_vbus_logger`) to embed VBus logging into other applications
 //!
 //!
 //! ## First-time setup
@@ -42,238 +63,272 @@
 //!
 //! # Run the application from the folder where the `config.toml` is located.
 //! cargo run
+//!
+//! # Or pass the location of the `config.toml` and a subcommand explicitly
+//! cargo run -- --config /etc/vbus/config.toml run
 //! ```
 //!
+//! The following subcommands are available:
+//!
+//! - `run`: receive data and feed the configured outputs (default)
+//! - `check-config`: check the config and exit
+//! - `list-fields`: wait for the data set to settle and print all packet fields
+//! - `dump`: print the values of every received packet to stdout
+//! - `replay <file>...`: feed the configured outputs from recordings or raw captures
+//! - `params get <value>`: print the raw value of a controller parameter
+//! - `params set [--save] <value> <raw value>`: write the raw value of a controller parameter
+//!
 
 #![warn(missing_docs)]
 #![deny(missing_debug_implementations)]
 #![deny(warnings)]
 
 
+extern crate clap;
 extern crate env_logger;
-extern crate image;
-extern crate imageproc;
 #[macro_use]
 extern crate log;
 extern crate resol_vbus;
-extern crate rusttype;
-#[macro_use]
-extern crate serde_derive;
-extern crate serialport;
-extern crate sqlite;
-extern crate toml;
+extern crate resol_vbus_logger;
 
 
-mod config;
-mod csv_generator;
-mod error;
-mod live_data_text_generator;
-mod png_generator;
-mod serial_port_stream;
-mod sqlite_logger;
-mod tick_source;
-mod timestamp_file_writer;
+use std::process;
 
+use clap::{Arg, ArgMatches, Command};
 
-use std::io::{Read, Write};
-use std::net::TcpStream;
-
-use resol_vbus::{Specification, Language};
 use resol_vbus::{
-    chrono::prelude::*,
-    Data,
     DataSet,
-    Header,
-    LiveDataStream,
-    Packet,
-    ReadWithTimeout,
-    TcpConnector,
-    ToPacketId,
+    Language,
+    Specification,
 };
 
+use resol_vbus_logger::{
+    params::ParamClient,
+    replay_source::ReplaySource,
+    Config,
+    ConnectionBuilder,
+    DataSource,
+    Error,
+    Logger,
+    Result,
+    SettleTracker,
+};
 
-use config::Config;
-use csv_generator::CsvGenerator;
-use error::{Error, Result};
-use live_data_text_generator::LiveDataTextGenerator;
-use png_generator::PngGenerator;
-use serial_port_stream::SerialPortStream;
-use sqlite_logger::SqliteLogger;
-use tick_source::TickSource;
-
-
-fn stream_live_data<R: Read + ReadWithTimeout, W: Write>(config: &Config, mut lds: LiveDataStream<R, W>) -> Result<()> {
-    let mut data_set = DataSet::new();
-
-    for packet_id in config.known_packet_ids.iter() {
-        let packet_id = packet_id.to_packet_id()?;
-        let packet = Packet {
-            header: Header {
-                timestamp: UTC::now(),
-                channel: packet_id.0,
-                destination_address: packet_id.1,
-                source_address: packet_id.2,
-                protocol_version: 0x10,
-            },
-            command: packet_id.3,
-            frame_count: 0,
-            frame_data: [0; 508],
-        };
-        data_set.add_data(Data::Packet(packet));
+
+fn open_source(config: &Config) -> Result<Box<dyn DataSource>> {
+    let connection_builder = ConnectionBuilder::from_config(config);
+
+    if connection_builder.is_configured() {
+        connection_builder.connect()
+    } else if config.replay_filenames.is_some() {
+        Ok(Box::new(ReplaySource::from_config(config)?))
+    } else {
+        Err(Error::from("Unexpected connection method"))
     }
+}
 
-    let mut data_set_is_settled = false;
-    let mut data_set_settled_max_count = data_set.len() * 3;
-    let mut data_set_settled_count = 0;
-
-    debug!("Initializing PNG");
-    let png_generator = PngGenerator::from_config(&config)?;
-    debug!("Initializing CSV");
-    let mut csv_generator = CsvGenerator::from_config(&config)?;
-    debug!("Initializing Live Data Text");
-    let mut live_data_text_generator = LiveDataTextGenerator::from_config(&config)?;
-    debug!("Initializing SQLite");
-    let mut sqlite_logger = SqliteLogger::from_config(&config)?;
-
-    let now = UTC::now();
-
-    debug!("Initializing tick sources");
-    let mut png_tick_source = TickSource::new(config.png_tick_interval, now);
-    let mut csv_tick_source = TickSource::new(config.csv_tick_interval, now);
-    let mut live_data_text_tick_source = TickSource::new(config.live_data_text_tick_interval, now);
-    let mut sqlite_tick_source = TickSource::new(config.sqlite_tick_interval, now);
-
-    let mut last_data_received = now;
-
-    loop {
-        let now = UTC::now();
-        if png_tick_source.process(now) {
-            if data_set_is_settled {
-                debug!("PNG Tick");
-                png_generator.generate(&data_set, &now)?;
-            }
-        }
 
-        if csv_tick_source.process(now) {
-            if data_set_is_settled {
-                debug!("CSV tick");
-                csv_generator.generate(&data_set, &now)?;
-            }
-        }
+fn run(config: &Config) -> Result<()> {
+    let mut logger = Logger::from_config(config)?;
 
-        if live_data_text_tick_source.process(now) {
-            if data_set_is_settled {
-                debug!("Live Data Text tick");
-                live_data_text_generator.generate(&data_set, &now)?;
-            }
-        }
+    logger.run()
+}
 
-        if sqlite_tick_source.process(now) {
-            if data_set_is_settled {
-                debug!("SQlite tick");
-                sqlite_logger.log(&data_set, &now)?;
-            }
-        }
 
-        if let Some(data) = lds.receive(500)? {
-            last_data_received = now;
-
-            if !data.is_packet() {
-                // nop
-            } else if data_set_is_settled {
-                data_set.add_data(data);
-            } else {
-                let len_before = data_set.len();
-
-                data_set.add_data(data);
-
-                let len_after = data_set.len();
-
-                if len_before != len_after {
-                    debug!("Received new packet, need to resettle...");
-                    data_set_settled_max_count = len_after * 3;
-                    data_set_settled_count = 0;
-                } else if data_set_settled_count < data_set_settled_max_count {
-                    data_set_settled_count += 1;
-                    let percent = 100.0f32 * data_set_settled_count as f32 / data_set_settled_max_count as f32;
-                    debug!("Settling: {} / {} -> {:.2}%", data_set_settled_count, data_set_settled_max_count, percent);
-                } else {
-                    data_set_is_settled = true;
-
-                    let mut sorted_data_set = data_set.clone();
-                    sorted_data_set.sort();
-                    debug!("Settled {:?}", sorted_data_set.iter().map(|data| data.id_string()).collect::<Vec<_>>());
-
-                    let spec_file = config.load_spec_file()?;
-                    let spec = Specification::from_file(spec_file, Language::De);
-                    for field in spec.fields_in_data_set(&sorted_data_set) {
-                        debug!("  - {}: {}: {}", field.packet_field_id().packet_field_id_string(), field.packet_spec().name, field.field_spec().name);
-                    }
-                }
-            }
-        }
+fn check_config(config: &Config) -> Result<()> {
+    config.validate()?;
+
+    println!("Config is valid");
+
+    Ok(())
+}
 
-        if let Some(timeout) = config.timeout {
-            let diff = now.signed_duration_since(last_data_received);
-            if diff.num_seconds() > timeout {
-                return Err("Timeout while receiving live data".into());
-            }
+
+fn list_fields(config: &Config) -> Result<()> {
+    let mut source = open_source(config)?;
+
+    let mut settle_tracker = SettleTracker::from_known_packet_ids(config)?;
+
+    let timeout = config.timeout.unwrap_or(60);
+    let mut last_data_received = source.now();
+
+    while !settle_tracker.is_settled() {
+        if let Some(data) = source.receive_data()? {
+            last_data_received = source.now();
+
+            settle_tracker.add_data(data);
+        } else if source.is_finished() {
+            break;
+        } else if source.now().signed_duration_since(last_data_received).num_seconds() > timeout {
+            return Err("Timeout while receiving live data".into());
         }
     }
-}
 
+    let mut data_set = settle_tracker.data_set().clone();
+    data_set.sort();
 
-fn run_main() -> Result<()> {
-    env_logger::init();
+    let spec_file = config.load_spec_file()?;
+    let spec = Specification::from_file(spec_file, Language::De);
+
+    for field in spec.fields_in_data_set(&data_set) {
+        println!("{}\t{}\t{}\t{}", field.packet_field_id().packet_field_id_string(), field.packet_spec().name, field.field_spec().name, field.field_spec().unit_text.trim());
+    }
 
-    debug!("Loading config");
-    let config = Config::load()?;
+    Ok(())
+}
 
-    let channel = config.channel.unwrap_or(0);
 
-    if let Some(ref path) = config.path {
-        debug!("Using serial port");
+fn dump(config: &Config) -> Result<()> {
+    let mut source = open_source(config)?;
 
-        debug!("Connecting serial port");
-        let port = serialport::new(path, 9600).open()?;
+    let spec_file = config.load_spec_file()?;
+    let spec = Specification::from_file(spec_file, Language::De);
 
-        let reader = SerialPortStream::new(port.try_clone()?);
-        let writer = SerialPortStream::new(port);
+    while !source.is_finished() {
+        let data = match source.receive_data()? {
+            Some(data) => data,
+            None => continue,
+        };
+
+        if !data.is_packet() {
+            continue;
+        }
 
-        debug!("Creating live data stream");
-        let lds = LiveDataStream::new(channel, 0x0020, reader, writer)?;
+        let timestamp = data.as_header().timestamp;
 
-        stream_live_data(&config, lds)?;
+        let mut data_set = DataSet::new();
+        data_set.add_data(data);
 
-        Ok(())
-    } else if let Some(ref address) = config.address {
-        debug!("Using TCP stream");
+        for field in spec.fields_in_data_set(&data_set) {
+            println!("{} {} {}: {} = {}", timestamp.to_rfc3339(), field.packet_field_id().packet_field_id_string(), field.packet_spec().name, field.field_spec().name, field.fmt_raw_value(true));
+        }
+    }
 
-        debug!("Connection TCP stream");
-        let stream = TcpStream::connect(address)?;
+    Ok(())
+}
 
-        debug!("Performing VBus-over-TCP handshake");
-        let mut tcp_connector = TcpConnector::new(stream);
-        tcp_connector.via_tag = config.via_tag.clone();
-        tcp_connector.password = config.password.clone().unwrap_or("vbus".to_string());
-        tcp_connector.channel = config.channel.clone();
-        tcp_connector.connect()?;
 
-        let reader = tcp_connector.into_inner();
-        let writer = reader.try_clone()?;
+fn replay(mut config: Config, matches: &ArgMatches) -> Result<()> {
+    config.path = None;
+    config.address = None;
+    config.replay_filenames = matches.values_of("file").map(|files| files.map(|file| file.to_string()).collect());
 
-        debug!("Creating live data stream");
-        let lds = LiveDataStream::new(channel, 0x0020, reader, writer)?;
+    run(&config)
+}
 
-        stream_live_data(&config, lds)?;
 
-        Ok(())
+fn parse_address(address: &str) -> Result<u16> {
+    let result = if address.starts_with("0x") || address.starts_with("0X") {
+        u16::from_str_radix(&address[2..], 16)
     } else {
-        Err(Error::from("Unexpected connection method"))
+        address.parse()
+    };
+
+    result.map_err(|_| Error::from(format!("Invalid address {:?}", address)))
+}
+
+
+fn params(config: &Config, matches: &ArgMatches) -> Result<()> {
+    let mut client = ParamClient::from_config(config)?;
+    if let Some(address) = matches.value_of("address") {
+        client = client.address(parse_address(address)?);
+    }
+
+    match matches.subcommand() {
+        Some(("get", sub_matches)) => {
+            let value = sub_matches.value_of("value").unwrap();
+
+            println!("{}", client.get(value)?);
+        },
+        Some(("set", sub_matches)) => {
+            let value = sub_matches.value_of("value").unwrap();
+            let new_value = sub_matches.value_of("new-value").unwrap();
+            let new_value = match new_value.parse() {
+                Ok(new_value) => new_value,
+                Err(_) => return Err(format!("Invalid raw value {:?}", new_value).into()),
+            };
+
+            println!("{}", client.set(value, new_value, sub_matches.is_present("save"))?);
+        },
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+
+fn run_main() -> Result<()> {
+    env_logger::init();
+
+    let matches = Command::new("logger")
+        .about("Logs and visualizes RESOL VBus data")
+        .arg(Arg::new("config")
+            .long("config")
+            .short('c')
+            .value_name("PATH")
+            .takes_value(true)
+            .default_value("config.toml")
+            .help("Location of the config file"))
+        .subcommand(Command::new("run")
+            .about("Receive data and feed the configured outputs (default)"))
+        .subcommand(Command::new("check-config")
+            .about("Check the config and exit"))
+        .subcommand(Command::new("list-fields")
+            .about("Wait for the data set to settle and print all packet fields"))
+        .subcommand(Command::new("dump")
+            .about("Print the values of every received packet"))
+        .subcommand(Command::new("replay")
+            .about("Feed the configured outputs from recordings or raw captures")
+            .arg(Arg::new("file")
+                .required(true)
+                .multiple_values(true)
+                .help("VBus recording (.vbus) or raw capture to replay")))
+        .subcommand(Command::new("params")
+            .about("Read or write controller parameters")
+            .subcommand_required(true)
+            .arg(Arg::new("address")
+                .long("address")
+                .value_name("ADDRESS")
+                .takes_value(true)
+                .help("VBus address of the controller (defaults to the one offering the bus)"))
+            .subcommand(Command::new("get")
+                .about("Print the raw value of a parameter")
+                .arg(Arg::new("value")
+                    .required(true)
+                    .help("Value index or ID name of the parameter")))
+            .subcommand(Command::new("set")
+                .about("Write the raw value of a parameter and print the value reported back")
+                .arg(Arg::new("save")
+                    .long("save")
+                    .help("Persist the value on the controller"))
+                .arg(Arg::new("value")
+                    .required(true)
+                    .help("Value index or ID name of the parameter"))
+                .arg(Arg::new("new-value")
+                    .required(true)
+                    .allow_hyphen_values(true)
+                    .help("Raw value to write"))))
+        .get_matches();
+
+    let config_filename = matches.value_of("config").unwrap();
+
+    debug!("Loading config from {}", config_filename);
+    let config = Config::load_from(config_filename)?;
+
+    match matches.subcommand() {
+        Some(("check-config", _)) => check_config(&config),
+        Some(("list-fields", _)) => list_fields(&config),
+        Some(("dump", _)) => dump(&config),
+        Some(("replay", sub_matches)) => replay(config, sub_matches),
+        Some(("params", sub_matches)) => params(&config, sub_matches),
+        _ => run(&config),
     }
 }
 
 
 fn main() {
-    run_main().unwrap();
+    if let Err(err) = run_main() {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,363 @@
+use std::fmt;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use resol_vbus::{
+    chrono::prelude::*,
+    id_hash,
+    DataSet,
+    Language,
+    Specification,
+};
+
+use rumqttc::{
+    Client,
+    MqttOptions,
+    QoS,
+    Transport,
+};
+
+
+use config::{Config, MqttConfig};
+use error::{Error, Result};
+use output::Output;
+use virtual_fields::VirtualFields;
+
+
+/// Number of publications that can be queued while the broker is slow or
+/// unreachable. This is enough for the discovery configs and values of
+/// several hundred fields, further publications are dropped until the queue
+/// has drained.
+const REQUEST_CAPACITY: usize = 1000;
+
+
+/// Publishes the VBus values to an MQTT broker on every tick.
+pub struct MqttPublisher {
+    spec: Specification,
+    options: Option<MqttOptions>,
+    client: Option<Client>,
+    topic_prefix: String,
+    qos: QoS,
+    retain: bool,
+    discovery_prefix: Option<String>,
+    discovery_key: Option<(u64, Vec<String>)>,
+}
+
+
+#[derive(Serialize)]
+struct DiscoveryDevice {
+    identifiers: Vec<String>,
+    name: String,
+    manufacturer: &'static str,
+}
+
+
+#[derive(Serialize)]
+struct DiscoveryConfig<'a> {
+    name: &'a str,
+    unique_id: String,
+    object_id: String,
+    state_topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_class: Option<&'static str>,
+    device: DiscoveryDevice,
+}
+
+
+impl fmt::Debug for MqttPublisher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MqttPublisher")
+            .field("topic_prefix", &self.topic_prefix)
+            .field("qos", &self.qos)
+            .field("retain", &self.retain)
+            .field("discovery_prefix", &self.discovery_prefix)
+            .finish()
+    }
+}
+
+
+impl MqttPublisher {
+    /// Create a publisher from the settings of an `[[mqtt]]` table.
+    pub fn from_config(config: &Config, mqtt_config: &MqttConfig) -> Result<MqttPublisher> {
+        let spec_file = config.load_spec_file()?;
+
+        let spec = Specification::from_file(spec_file, Language::En);
+
+        let options = if mqtt_config.tick_interval > 0 {
+            Some(mqtt_options(mqtt_config)?)
+        } else {
+            None
+        };
+
+        let topic_prefix = mqtt_config.topic_prefix.clone().unwrap_or("vbus".to_string());
+
+        let qos = match mqtt_config.qos.unwrap_or(0) {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            qos => return Err(format!("Unsupported MQTT QoS {}", qos).into()),
+        };
+
+        let retain = mqtt_config.retain.unwrap_or(false);
+
+        let discovery_prefix = mqtt_config.discovery_prefix.clone();
+
+        Ok(MqttPublisher {
+            spec,
+            options,
+            client: None,
+            topic_prefix,
+            qos,
+            retain,
+            discovery_prefix,
+            discovery_key: None,
+        })
+    }
+
+    /// Publish Home Assistant discovery configs for all fields, if the set of
+    /// packets or virtual fields has changed.
+    pub fn announce(&mut self, data_set: &DataSet, virtual_fields: &VirtualFields) -> Result<()> {
+        let client = match self.client {
+            Some(ref mut client) => client,
+            None => return Ok(()),
+        };
+
+        let discovery_prefix = match self.discovery_prefix {
+            Some(ref discovery_prefix) => discovery_prefix,
+            None => return Ok(()),
+        };
+
+        let current_key = (id_hash(data_set), virtual_fields.iter().map(|field| field.id.clone()).collect::<Vec<_>>());
+        if self.discovery_key.as_ref() == Some(&current_key) {
+            return Ok(());
+        }
+
+        let mut dropped_count = 0;
+
+        for field in self.spec.fields_in_data_set(data_set) {
+            let packet_spec = field.packet_spec();
+            let field_spec = field.field_spec();
+
+            let packet_field_id = format!("{}_{}", packet_spec.packet_id, field_spec.field_id);
+            let object_id = format!("vbus_{}", packet_field_id.to_lowercase());
+
+            let unit_text = field_spec.unit_text.trim();
+            let (device_class, state_class) = device_and_state_class(&field_spec.unit_code);
+
+            let discovery_config = DiscoveryConfig {
+                name: &field_spec.name,
+                unique_id: object_id.clone(),
+                object_id: object_id.clone(),
+                state_topic: format!("{}/{}", self.topic_prefix, packet_field_id),
+                unit_of_measurement: if unit_text.len() > 0 { Some(unit_text) } else { None },
+                device_class,
+                state_class,
+                device: DiscoveryDevice {
+                    identifiers: vec![format!("vbus_{:02x}_{:04x}", packet_spec.channel, packet_spec.source_address)],
+                    name: packet_spec.name.clone(),
+                    manufacturer: "RESOL",
+                },
+            };
+
+            let topic = format!("{}/sensor/{}/config", discovery_prefix, object_id);
+            let payload = serde_json::to_string(&discovery_config)?;
+
+            // Discovery configs must be retained so that they survive restarts of Home Assistant
+            if !try_publish(client, topic, self.qos, true, payload) {
+                dropped_count += 1;
+            }
+        }
+
+        for field in virtual_fields.iter() {
+            let object_id = format!("vbus_{}", field.id.to_lowercase());
+
+            let (device_class, state_class) = virtual_device_and_state_class(&field.unit);
+
+            let discovery_config = DiscoveryConfig {
+                name: &field.name,
+                unique_id: object_id.clone(),
+                object_id: object_id.clone(),
+                state_topic: format!("{}/{}", self.topic_prefix, field.id),
+                unit_of_measurement: if field.unit.len() > 0 { Some(&field.unit) } else { None },
+                device_class,
+                state_class,
+                device: DiscoveryDevice {
+                    identifiers: vec!["vbus_logger".to_string()],
+                    name: "VBus Logger".to_string(),
+                    manufacturer: "RESOL",
+                },
+            };
+
+            let topic = format!("{}/sensor/{}/config", discovery_prefix, object_id);
+            let payload = serde_json::to_string(&discovery_config)?;
+
+            if !try_publish(client, topic, self.qos, true, payload) {
+                dropped_count += 1;
+            }
+        }
+
+        if dropped_count > 0 {
+            // Try again on the next tick
+            warn!("Dropped {} MQTT discovery configs, the broker may be unreachable", dropped_count);
+            return Ok(());
+        }
+
+        debug!("Published Home Assistant discovery configs");
+
+        self.discovery_key = Some(current_key);
+
+        Ok(())
+    }
+
+    /// Publish all values of the `data_set` and the `virtual_fields`.
+    pub fn publish(&mut self, data_set: &DataSet, virtual_fields: &VirtualFields, _now: &DateTime<UTC>) -> Result<()> {
+        // A failed announcement must not keep the values from being published
+        if let Err(err) = self.announce(data_set, virtual_fields) {
+            error!("Unable to publish Home Assistant discovery configs: {}", err);
+        }
+
+        let client = match self.client {
+            Some(ref mut client) => client,
+            None => return Err(Error::from("No MQTT client connected")),
+        };
+
+        let mut dropped_count = 0;
+
+        for field in self.spec.fields_in_data_set(data_set) {
+            if field.raw_value_f64().is_none() {
+                continue;
+            }
+
+            let topic = format!("{}/{}_{}", self.topic_prefix, field.packet_spec().packet_id, field.field_spec().field_id);
+            let payload = format!("{}", field.fmt_raw_value(false));
+
+            if !try_publish(client, topic, self.qos, self.retain, payload) {
+                dropped_count += 1;
+            }
+        }
+
+        for field in virtual_fields.iter() {
+            if field.value.is_none() {
+                continue;
+            }
+
+            let topic = format!("{}/{}", self.topic_prefix, field.id);
+
+            if !try_publish(client, topic, self.qos, self.retain, field.fmt_value(false)) {
+                dropped_count += 1;
+            }
+        }
+
+        if dropped_count > 0 {
+            warn!("Dropped {} MQTT publications, the broker may be unreachable", dropped_count);
+        }
+
+        Ok(())
+    }
+}
+
+
+impl Output for MqttPublisher {
+    fn init(&mut self) -> Result<()> {
+        if let Some(options) = self.options.take() {
+            self.client = Some(connect(options));
+        }
+
+        Ok(())
+    }
+
+    fn on_tick(&mut self, data_set: &DataSet, virtual_fields: &VirtualFields, now: &DateTime<UTC>) -> Result<()> {
+        self.publish(data_set, virtual_fields, now)
+    }
+}
+
+
+fn device_and_state_class(unit_code: &str) -> (Option<&'static str>, Option<&'static str>) {
+    match unit_code {
+        "DegreesCelsius" | "DegreesFahrenheit" => (Some("temperature"), Some("measurement")),
+        "Watts" | "Kilowatts" => (Some("power"), Some("measurement")),
+        "WattHours" | "KilowattHours" | "MegawattHours" => (Some("energy"), Some("total_increasing")),
+        "Bars" => (Some("pressure"), Some("measurement")),
+        "Volts" => (Some("voltage"), Some("measurement")),
+        "Milliamperes" => (Some("current"), Some("measurement")),
+        "Hours" | "Days" => (Some("duration"), Some("total_increasing")),
+        "Seconds" | "Minutes" => (Some("duration"), Some("measurement")),
+        // Fields without a unit are often times, dates or bit fields, so they are not treated as numeric
+        "" | "None" => (None, None),
+        _ => (None, Some("measurement")),
+    }
+}
+
+
+/// Virtual fields have no VSF unit code, so their unit text is used instead.
+fn virtual_device_and_state_class(unit: &str) -> (Option<&'static str>, Option<&'static str>) {
+    match unit {
+        "°C" | "°F" => (Some("temperature"), Some("measurement")),
+        "W" | "kW" => (Some("power"), Some("measurement")),
+        "Wh" | "kWh" | "MWh" => (Some("energy"), Some("total_increasing")),
+        "h" => (Some("duration"), Some("total_increasing")),
+        _ => (None, Some("measurement")),
+    }
+}
+
+
+fn mqtt_options(mqtt_config: &MqttConfig) -> Result<MqttOptions> {
+    let host = mqtt_config.host.clone().unwrap_or("localhost".to_string());
+    let port = match (mqtt_config.port, &mqtt_config.tls_ca_filename) {
+        (Some(port), _) => port,
+        (None, Some(_)) => 8883,
+        (None, None) => 1883,
+    };
+    let client_id = mqtt_config.client_id.clone().unwrap_or("resol-vbus-logger".to_string());
+
+    let mut options = MqttOptions::new(client_id, host, port);
+    options.set_keep_alive(Duration::from_secs(30));
+
+    if let Some(ref username) = mqtt_config.username {
+        let password = mqtt_config.password.clone().unwrap_or(String::new());
+        options.set_credentials(username.clone(), password);
+    }
+
+    if let Some(ref ca_filename) = mqtt_config.tls_ca_filename {
+        let ca = fs::read(ca_filename)?;
+        options.set_transport(Transport::tls(ca, None, None));
+    }
+
+    Ok(options)
+}
+
+
+/// Queue a publication without blocking. Returns `false` if it was dropped,
+/// e.g. because the queue is full.
+fn try_publish(client: &mut Client, topic: String, qos: QoS, retain: bool, payload: String) -> bool {
+    match client.try_publish(topic, qos, retain, payload) {
+        Ok(()) => true,
+        Err(err) => {
+            debug!("Dropping MQTT publication: {}", err);
+            false
+        },
+    }
+}
+
+
+fn connect(options: MqttOptions) -> Client {
+    let (client, mut connection) = Client::new(options, REQUEST_CAPACITY);
+
+    // The connection must be polled continuously to drive the MQTT event loop,
+    // it automatically reconnects on the next iteration after an error.
+    thread::spawn(move || {
+        for notification in connection.iter() {
+            if let Err(err) = notification {
+                warn!("MQTT connection error: {}", err);
+                thread::sleep(Duration::from_secs(5));
+            }
+        }
+    });
+
+    client
+}
@@ -0,0 +1,274 @@
+use std::fmt;
+use std::process::Command;
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use lettre::{
+    transport::smtp::authentication::Credentials,
+    Message,
+    SmtpTransport,
+    Transport,
+};
+
+
+use config::{Config, NotifierConfig, NotifierKind, SmtpSecurity};
+use error::{Error, Result};
+
+
+/// A notification about an alarm that became active or recovered.
+#[derive(Clone, Debug, Serialize)]
+pub struct Notification {
+    /// The name of the alarm.
+    pub alarm: String,
+    /// Whether the alarm is active or has recovered.
+    pub is_active: bool,
+    /// A human-readable description of the alarm's condition.
+    pub message: String,
+    /// RFC3339 timestamp of the notification.
+    pub timestamp: String,
+}
+
+
+impl Notification {
+    /// A one-line summary, e.g. used as the email subject.
+    pub fn subject(&self) -> String {
+        if self.is_active {
+            format!("[ALARM] {}", self.alarm)
+        } else {
+            format!("[RECOVERED] {}", self.alarm)
+        }
+    }
+}
+
+
+/// A way to deliver notifications.
+pub trait Notifier: Send {
+    /// Deliver the `notification`.
+    fn notify(&self, notification: &Notification) -> Result<()>;
+}
+
+
+/// Runs a local command for each notification.
+///
+/// The notification is passed in the environment variables `ALARM_NAME`,
+/// `ALARM_STATE` ("active" or "recovered"), `ALARM_MESSAGE` and
+/// `ALARM_TIMESTAMP`.
+#[derive(Debug)]
+pub struct CommandNotifier {
+    command: String,
+    args: Vec<String>,
+}
+
+
+impl Notifier for CommandNotifier {
+    fn notify(&self, notification: &Notification) -> Result<()> {
+        let status = Command::new(&self.command)
+            .args(&self.args)
+            .env("ALARM_NAME", &notification.alarm)
+            .env("ALARM_STATE", if notification.is_active { "active" } else { "recovered" })
+            .env("ALARM_MESSAGE", &notification.message)
+            .env("ALARM_TIMESTAMP", &notification.timestamp)
+            .status()?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("Command {:?} failed with {}", self.command, status).into())
+        }
+    }
+}
+
+
+/// POSTs each notification as a JSON object to a URL.
+#[derive(Debug)]
+pub struct WebhookNotifier {
+    url: String,
+}
+
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, notification: &Notification) -> Result<()> {
+        let body = serde_json::to_string(notification)?;
+
+        let result = ureq::post(&self.url)
+            .timeout(Duration::from_secs(10))
+            .set("Content-Type", "application/json")
+            .send_string(&body);
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => Err(Error::from(format!("Unable to POST notification to {}: {}", self.url, err))),
+        }
+    }
+}
+
+
+/// Sends an email for each notification.
+pub struct SmtpNotifier {
+    transport: SmtpTransport,
+    from: String,
+    to: Vec<String>,
+}
+
+
+impl fmt::Debug for SmtpNotifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SmtpNotifier")
+            .field("from", &self.from)
+            .field("to", &self.to)
+            .finish()
+    }
+}
+
+
+impl Notifier for SmtpNotifier {
+    fn notify(&self, notification: &Notification) -> Result<()> {
+        let mut builder = Message::builder()
+            .from(self.from.parse()?)
+            .subject(notification.subject());
+
+        for to in self.to.iter() {
+            builder = builder.to(to.parse()?);
+        }
+
+        let message = builder.body(format!("{}\n\n{}\n", notification.message, notification.timestamp))?;
+
+        self.transport.send(&message)?;
+
+        Ok(())
+    }
+}
+
+
+/// Create a notifier from the settings of a `[[notifier]]` table.
+pub fn notifier_from_config(notifier_config: &NotifierConfig) -> Result<Box<dyn Notifier>> {
+    let name = &notifier_config.name;
+
+    let notifier: Box<dyn Notifier> = match notifier_config.kind {
+        NotifierKind::Command => Box::new(CommandNotifier {
+            command: match notifier_config.command {
+                Some(ref command) => command.clone(),
+                None => return Err(format!("Notifier {} requires a `command`", name).into()),
+            },
+            args: notifier_config.args.clone().unwrap_or(Vec::new()),
+        }),
+        NotifierKind::Webhook => Box::new(WebhookNotifier {
+            url: match notifier_config.url {
+                Some(ref url) => url.clone(),
+                None => return Err(format!("Notifier {} requires a `url`", name).into()),
+            },
+        }),
+        NotifierKind::Smtp => {
+            let (host, from, to) = match (&notifier_config.smtp_host, &notifier_config.from, &notifier_config.to) {
+                (Some(host), Some(from), Some(to)) => (host, from, to),
+                _ => return Err(format!("Notifier {} requires `smtp_host`, `from` and `to`", name).into()),
+            };
+
+            let mut builder = match notifier_config.smtp_security.unwrap_or(SmtpSecurity::Starttls) {
+                SmtpSecurity::Tls => SmtpTransport::relay(host)?,
+                SmtpSecurity::Starttls => SmtpTransport::starttls_relay(host)?,
+                SmtpSecurity::None => SmtpTransport::builder_dangerous(host),
+            };
+
+            if let Some(port) = notifier_config.smtp_port {
+                builder = builder.port(port);
+            }
+
+            if let Some(ref username) = notifier_config.smtp_username {
+                let password = notifier_config.smtp_password.clone().unwrap_or(String::new());
+                builder = builder.credentials(Credentials::new(username.clone(), password));
+            }
+
+            Box::new(SmtpNotifier {
+                transport: builder.build(),
+                from: from.clone(),
+                to: to.clone(),
+            })
+        },
+    };
+
+    Ok(notifier)
+}
+
+
+/// Delivers notifications in a background thread, so that slow notifiers do
+/// not stall the reception of VBus data.
+pub struct NotificationDispatcher {
+    sender: Option<Sender<(Notification, Option<Vec<String>>)>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+
+impl fmt::Debug for NotificationDispatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NotificationDispatcher")
+            .field("is_running", &self.sender.is_some())
+            .finish()
+    }
+}
+
+
+impl NotificationDispatcher {
+    /// Create the notifiers configured in the `[[notifier]]` tables and start
+    /// the background thread.
+    pub fn from_config(config: &Config) -> Result<NotificationDispatcher> {
+        let mut notifiers = Vec::new();
+        for notifier_config in config.notifier.iter() {
+            notifiers.push((notifier_config.name.clone(), notifier_from_config(notifier_config)?));
+        }
+
+        if notifiers.is_empty() {
+            return Ok(NotificationDispatcher {
+                sender: None,
+                thread: None,
+            });
+        }
+
+        let (sender, receiver) = channel::<(Notification, Option<Vec<String>>)>();
+
+        let thread = thread::spawn(move || {
+            for (notification, names) in receiver.iter() {
+                for &(ref name, ref notifier) in notifiers.iter() {
+                    if let Some(ref names) = names {
+                        if !names.contains(name) {
+                            continue;
+                        }
+                    }
+
+                    match notifier.notify(&notification) {
+                        Ok(()) => debug!("Notified {} about alarm {}", name, notification.alarm),
+                        Err(err) => error!("Unable to notify {} about alarm {}: {}", name, notification.alarm, err),
+                    }
+                }
+            }
+        });
+
+        Ok(NotificationDispatcher {
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+
+    /// Deliver the `notification` using the notifiers with the given `names`
+    /// or all notifiers if `names` is `None`.
+    pub fn dispatch(&self, notification: Notification, names: &Option<Vec<String>>) {
+        if let Some(ref sender) = self.sender {
+            if sender.send((notification, names.clone())).is_err() {
+                error!("Notification thread has stopped");
+            }
+        }
+    }
+
+    /// Wait for all pending notifications to be delivered and stop the
+    /// background thread.
+    pub fn shutdown(&mut self) {
+        self.sender.take();
+
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Notification thread panicked");
+            }
+        }
+    }
+}
@@ -0,0 +1,527 @@
+use std::fmt;
+
+use resol_vbus::{
+    chrono::prelude::*,
+    Data,
+    DataSet,
+};
+
+
+use config::Config;
+use csv_generator::CsvGenerator;
+use datagram_logger::DatagramLogger;
+use derived_fields::DerivedFields;
+use energy_aggregator::EnergyAggregator;
+use error::Result;
+use influxdb_generator::InfluxDbGenerator;
+use live_data_text_generator::LiveDataTextGenerator;
+use mqtt_publisher::MqttPublisher;
+use png_generator::PngGenerator;
+use recording_generator::RecordingGenerator;
+use relay_statistics::RelayStatistics;
+use sqlite_logger::SqliteLogger;
+use state_file::StateFile;
+use summary_logger::SummaryLogger;
+use stats::Stats;
+use tick_source::TickSource;
+use virtual_fields::VirtualFields;
+
+
+/// A sink for VBus data.
+///
+/// All methods have empty default implementations, so that an output only
+/// has to implement the events it is interested in.
+pub trait Output {
+    /// Called once before any data is received.
+    fn init(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for every received data, including datagrams and telegrams.
+    fn on_data(&mut self, _data: &Data) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once the data set has settled after new packets were discovered.
+    fn on_settled(&mut self, _data_set: &DataSet) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called whenever the data set changed, e.g. because a packet was received
+    /// or packets became stale.
+    fn on_data_set_changed(&mut self, _data_set: &DataSet, _virtual_fields: &VirtualFields) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called whenever the output's tick interval elapsed while the data set is settled.
+    fn on_tick(&mut self, _data_set: &DataSet, _virtual_fields: &VirtualFields, _now: &DateTime<UTC>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called after `init` and after every `on_data_set_changed` and `on_tick`
+    /// to collect the virtual fields this output computes.
+    fn provide_virtual_fields(&self, _virtual_fields: &mut VirtualFields) {
+    }
+
+    /// Called once after the data source has finished.
+    fn on_shutdown(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+
+struct OutputEntry<'a> {
+    name: String,
+    tick_source: Option<TickSource>,
+    output: Box<dyn Output + 'a>,
+}
+
+
+/// The list of outputs that receive the VBus data.
+pub struct OutputRegistry<'a> {
+    entries: Vec<OutputEntry<'a>>,
+    virtual_fields: VirtualFields,
+}
+
+
+impl<'a> fmt::Debug for OutputRegistry<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.entries.iter().map(|entry| &entry.name))
+            .finish()
+    }
+}
+
+
+impl<'a> OutputRegistry<'a> {
+    /// Create an empty registry.
+    pub fn new() -> OutputRegistry<'a> {
+        OutputRegistry {
+            entries: Vec::new(),
+            virtual_fields: VirtualFields::new(),
+        }
+    }
+
+    /// Create a registry containing all outputs configured in the `config`.
+    pub fn from_config(config: &Config, now: DateTime<UTC>) -> Result<OutputRegistry<'a>> {
+        let mut registry = OutputRegistry::new();
+
+        // Outputs providing virtual fields come first, so that the other
+        // outputs see their values of the same tick
+        for (index, energy_config) in config.energy.iter().enumerate() {
+            debug!("Initializing energy aggregator {}", index);
+            let output = EnergyAggregator::from_config(config, energy_config)?;
+            registry.add("energy", index, &Some(energy_config.name.clone()), Some(energy_config.tick_interval), now, Box::new(output));
+        }
+
+        for (index, relay_config) in config.relay.iter().enumerate() {
+            debug!("Initializing relay statistics {}", index);
+            let output = RelayStatistics::from_config(config, relay_config)?;
+            registry.add("relay", index, &Some(relay_config.name.clone()), None, now, Box::new(output));
+        }
+
+        // Derived fields come after the outputs above, so that their
+        // expressions can reference the energy and relay fields. They are
+        // evaluated whenever the data set changes instead of on ticks.
+        if !config.derived.is_empty() {
+            debug!("Initializing derived fields");
+            let output = DerivedFields::from_config(config)?;
+            registry.add("derived", 0, &None, None, now, Box::new(output));
+        }
+
+        for (index, png_config) in config.png.iter().enumerate() {
+            debug!("Initializing PNG {}", index);
+            let output = PngGenerator::from_config(config, png_config)?;
+            registry.add("png", index, &png_config.name, Some(png_config.tick_interval), now, Box::new(output));
+        }
+
+        for (index, csv_config) in config.csv.iter().enumerate() {
+            debug!("Initializing CSV {}", index);
+            let output = CsvGenerator::from_config(config, csv_config)?;
+            registry.add("csv", index, &csv_config.name, Some(csv_config.tick_interval), now, Box::new(output));
+        }
+
+        for (index, live_data_text_config) in config.live_data_text.iter().enumerate() {
+            debug!("Initializing Live Data Text {}", index);
+            let output = LiveDataTextGenerator::from_config(config, live_data_text_config)?;
+            registry.add("live_data_text", index, &live_data_text_config.name, Some(live_data_text_config.tick_interval), now, Box::new(output));
+        }
+
+        for (index, sqlite_config) in config.sqlite.iter().enumerate() {
+            debug!("Initializing SQLite {}", index);
+            let output = SqliteLogger::from_config(config, sqlite_config)?;
+            registry.add("sqlite", index, &sqlite_config.name, Some(sqlite_config.tick_interval), now, Box::new(output));
+        }
+
+        for (index, summary_config) in config.summary.iter().enumerate() {
+            debug!("Initializing summary {}", index);
+            let output = SummaryLogger::from_config(config, summary_config)?;
+            registry.add("summary", index, &summary_config.name, Some(summary_config.tick_interval), now, Box::new(output));
+        }
+
+        for (index, recording_config) in config.recording.iter().enumerate() {
+            debug!("Initializing recording {}", index);
+            let output = RecordingGenerator::from_config(recording_config)?;
+            registry.add("recording", index, &recording_config.name, None, now, Box::new(output));
+        }
+
+        for (index, mqtt_config) in config.mqtt.iter().enumerate() {
+            debug!("Initializing MQTT {}", index);
+            let output = MqttPublisher::from_config(config, mqtt_config)?;
+            registry.add("mqtt", index, &mqtt_config.name, Some(mqtt_config.tick_interval), now, Box::new(output));
+        }
+
+        for (index, influxdb_config) in config.influxdb.iter().enumerate() {
+            debug!("Initializing InfluxDB {}", index);
+            let output = InfluxDbGenerator::from_config(config, influxdb_config)?;
+            registry.add("influxdb", index, &influxdb_config.name, Some(influxdb_config.tick_interval), now, Box::new(output));
+        }
+
+        for (index, datagrams_config) in config.datagrams.iter().enumerate() {
+            debug!("Initializing datagram logger {}", index);
+            let output = DatagramLogger::from_config(datagrams_config)?;
+            registry.add("datagrams", index, &datagrams_config.name, None, now, Box::new(output));
+        }
+
+        if let Some(state_file) = StateFile::from_config(config) {
+            debug!("Initializing state file");
+            registry.add("state", 0, &None, Some(config.state_tick_interval.unwrap_or(60)), now, Box::new(state_file));
+        }
+
+        Ok(registry)
+    }
+
+    /// Add an output. Outputs without a tick interval never receive `on_tick` calls.
+    pub fn add(&mut self, kind: &str, index: usize, name: &Option<String>, tick_interval: Option<i64>, now: DateTime<UTC>, output: Box<dyn Output + 'a>) {
+        let name = match *name {
+            Some(ref name) => name.clone(),
+            None => format!("{}[{}]", kind, index),
+        };
+
+        let tick_source = tick_interval.map(|tick_interval| TickSource::new(tick_interval, now));
+
+        self.entries.push(OutputEntry {
+            name,
+            tick_source,
+            output,
+        });
+    }
+
+    /// Start the tick sources of all outputs at `now`, the first timestamp
+    /// delivered by the data source.
+    pub fn start(&mut self, now: DateTime<UTC>) {
+        for entry in self.entries.iter_mut() {
+            if let Some(ref mut tick_source) = entry.tick_source {
+                tick_source.reset(now);
+            }
+        }
+    }
+
+    /// Initialize all outputs.
+    pub fn init(&mut self) -> Result<()> {
+        for entry in self.entries.iter_mut() {
+            debug!("Initializing output {}", entry.name);
+            if let Err(err) = entry.output.init() {
+                return Err(format!("Unable to initialize output {}: {}", entry.name, err).into());
+            }
+
+            entry.output.provide_virtual_fields(&mut self.virtual_fields);
+        }
+
+        Ok(())
+    }
+
+    /// The virtual fields provided by the outputs.
+    pub fn virtual_fields(&self) -> &VirtualFields {
+        &self.virtual_fields
+    }
+
+    /// Pass the `data` to all outputs.
+    pub fn on_data(&mut self, data: &Data, stats: &mut Stats) {
+        for entry in self.entries.iter_mut() {
+            if let Err(err) = entry.output.on_data(data) {
+                error!("Unable to process data for {}: {}", entry.name, err);
+                stats.generator_error_count += 1;
+            }
+        }
+    }
+
+    /// Notify all outputs that the `data_set` has settled.
+    pub fn on_settled(&mut self, data_set: &DataSet, stats: &mut Stats) {
+        for entry in self.entries.iter_mut() {
+            if let Err(err) = entry.output.on_settled(data_set) {
+                error!("Unable to process settled data set for {}: {}", entry.name, err);
+                stats.generator_error_count += 1;
+            }
+        }
+    }
+
+    /// Pass the changed `data_set` to all outputs.
+    pub fn on_data_set_changed(&mut self, data_set: &DataSet, stats: &mut Stats) {
+        for entry in self.entries.iter_mut() {
+            if let Err(err) = entry.output.on_data_set_changed(data_set, &self.virtual_fields) {
+                error!("Unable to process changed data set for {}: {}", entry.name, err);
+                stats.generator_error_count += 1;
+            }
+
+            entry.output.provide_virtual_fields(&mut self.virtual_fields);
+        }
+    }
+
+    /// Pass the `data_set` to all outputs whose tick interval has elapsed.
+    pub fn on_tick(&mut self, data_set: &DataSet, data_set_is_settled: bool, now: DateTime<UTC>, stats: &mut Stats) {
+        for entry in self.entries.iter_mut() {
+            let ticked = match entry.tick_source {
+                Some(ref mut tick_source) => tick_source.process(now),
+                None => false,
+            };
+
+            if ticked && data_set_is_settled {
+                debug!("Tick for {}", entry.name);
+                if let Err(err) = entry.output.on_tick(data_set, &self.virtual_fields, &now) {
+                    error!("Unable to process tick for {}: {}", entry.name, err);
+                    stats.generator_error_count += 1;
+                }
+
+                entry.output.provide_virtual_fields(&mut self.virtual_fields);
+            }
+        }
+    }
+
+    /// Notify all outputs that no more data will be received.
+    pub fn on_shutdown(&mut self, stats: &mut Stats) {
+        for entry in self.entries.iter_mut() {
+            if let Err(err) = entry.output.on_shutdown() {
+                error!("Unable to shut down {}: {}", entry.name, err);
+                stats.generator_error_count += 1;
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use resol_vbus::{
+        chrono::Duration,
+        Header,
+        Packet,
+    };
+
+    use error::Error;
+    use virtual_fields::VirtualField;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MockOutput {
+        name: &'static str,
+        events: Rc<RefCell<Vec<String>>>,
+        fail_init: bool,
+        fail_tick: bool,
+        tick_count: usize,
+    }
+
+    impl MockOutput {
+        fn new(name: &'static str, events: &Rc<RefCell<Vec<String>>>) -> MockOutput {
+            MockOutput {
+                name,
+                events: events.clone(),
+                ..MockOutput::default()
+            }
+        }
+
+        fn log(&self, event: String) {
+            self.events.borrow_mut().push(format!("{}: {}", self.name, event));
+        }
+    }
+
+    impl Output for MockOutput {
+        fn init(&mut self) -> Result<()> {
+            self.log("init".to_string());
+            if self.fail_init {
+                return Err(Error::from("init failed"));
+            }
+            Ok(())
+        }
+
+        fn on_data(&mut self, data: &Data) -> Result<()> {
+            self.log(format!("data {}", data.id_string()));
+            Ok(())
+        }
+
+        fn on_settled(&mut self, _data_set: &DataSet) -> Result<()> {
+            self.log("settled".to_string());
+            Ok(())
+        }
+
+        fn on_data_set_changed(&mut self, data_set: &DataSet, _virtual_fields: &VirtualFields) -> Result<()> {
+            self.log(format!("data set changed {}", data_set.len()));
+            Ok(())
+        }
+
+        fn on_tick(&mut self, _data_set: &DataSet, virtual_fields: &VirtualFields, _now: &DateTime<UTC>) -> Result<()> {
+            self.tick_count += 1;
+            self.log(format!("tick {:?}", virtual_fields.value("mock_ticks")));
+            if self.fail_tick {
+                return Err(Error::from("tick failed"));
+            }
+            Ok(())
+        }
+
+        fn provide_virtual_fields(&self, virtual_fields: &mut VirtualFields) {
+            if self.name == "provider" {
+                virtual_fields.set(VirtualField {
+                    id: "mock_ticks".to_string(),
+                    name: "Mock ticks".to_string(),
+                    unit: String::new(),
+                    precision: 0,
+                    value: Some(self.tick_count as f64),
+                });
+            }
+        }
+
+        fn on_shutdown(&mut self) -> Result<()> {
+            self.log("shutdown".to_string());
+            Ok(())
+        }
+    }
+
+    fn start() -> DateTime<UTC> {
+        UTC.ymd(2024, 6, 1).and_hms(12, 0, 0)
+    }
+
+    fn packet() -> Data {
+        Data::Packet(Packet {
+            header: Header {
+                timestamp: start(),
+                channel: 0,
+                destination_address: 0x0010,
+                source_address: 0x7E11,
+                protocol_version: 0x10,
+            },
+            command: 0x0100,
+            frame_count: 0,
+            frame_data: [0; 508],
+        })
+    }
+
+    fn take_events(events: &Rc<RefCell<Vec<String>>>) -> Vec<String> {
+        events.borrow_mut().drain(..).collect()
+    }
+
+    #[test]
+    fn test_events_are_dispatched_to_all_outputs() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut stats = Stats::default();
+
+        let mut registry = OutputRegistry::new();
+        registry.add("mock", 0, &Some("first".to_string()), Some(10), start(), Box::new(MockOutput::new("first", &events)));
+        registry.add("mock", 1, &None, None, start(), Box::new(MockOutput::new("second", &events)));
+
+        let mut data_set = DataSet::new();
+        data_set.add_data(packet());
+
+        registry.init().unwrap();
+        registry.on_data(&packet(), &mut stats);
+        registry.on_data_set_changed(&data_set, &mut stats);
+        registry.on_settled(&data_set, &mut stats);
+        registry.on_shutdown(&mut stats);
+
+        assert_eq!(vec![
+            "first: init",
+            "second: init",
+            "first: data 00_0010_7E11_10_0100",
+            "second: data 00_0010_7E11_10_0100",
+            "first: data set changed 1",
+            "second: data set changed 1",
+            "first: settled",
+            "second: settled",
+            "first: shutdown",
+            "second: shutdown",
+        ], take_events(&events));
+        assert_eq!(0, stats.generator_error_count);
+    }
+
+    #[test]
+    fn test_ticks_respect_interval_and_settled_state() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut stats = Stats::default();
+
+        let mut registry = OutputRegistry::new();
+        registry.add("mock", 0, &Some("fast".to_string()), Some(10), start(), Box::new(MockOutput::new("fast", &events)));
+        registry.add("mock", 1, &Some("slow".to_string()), Some(60), start(), Box::new(MockOutput::new("slow", &events)));
+        registry.add("mock", 2, &Some("never".to_string()), None, start(), Box::new(MockOutput::new("never", &events)));
+
+        let data_set = DataSet::new();
+
+        registry.on_tick(&data_set, true, start() + Duration::seconds(5), &mut stats);
+        assert!(take_events(&events).is_empty());
+
+        registry.on_tick(&data_set, true, start() + Duration::seconds(10), &mut stats);
+        assert_eq!(vec!["fast: tick None"], take_events(&events));
+
+        // The interval elapsed, but the data set has not settled
+        registry.on_tick(&data_set, false, start() + Duration::seconds(20), &mut stats);
+        assert!(take_events(&events).is_empty());
+
+        registry.on_tick(&data_set, true, start() + Duration::seconds(60), &mut stats);
+        assert_eq!(vec!["fast: tick None", "slow: tick None"], take_events(&events));
+    }
+
+    #[test]
+    fn test_virtual_fields_are_visible_in_the_same_tick() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut stats = Stats::default();
+
+        let mut registry = OutputRegistry::new();
+        registry.add("mock", 0, &Some("provider".to_string()), Some(10), start(), Box::new(MockOutput::new("provider", &events)));
+        registry.add("mock", 1, &Some("consumer".to_string()), Some(10), start(), Box::new(MockOutput::new("consumer", &events)));
+
+        registry.init().unwrap();
+        take_events(&events);
+
+        assert_eq!(Some(0.0), registry.virtual_fields().value("mock_ticks"));
+
+        registry.on_tick(&DataSet::new(), true, start() + Duration::seconds(10), &mut stats);
+
+        assert_eq!(vec!["provider: tick Some(0.0)", "consumer: tick Some(1.0)"], take_events(&events));
+        assert_eq!(Some(1.0), registry.virtual_fields().value("mock_ticks"));
+    }
+
+    #[test]
+    fn test_errors_are_counted_and_do_not_stop_other_outputs() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut stats = Stats::default();
+
+        let mut failing = MockOutput::new("failing", &events);
+        failing.fail_tick = true;
+
+        let mut registry = OutputRegistry::new();
+        registry.add("mock", 0, &Some("failing".to_string()), Some(10), start(), Box::new(failing));
+        registry.add("mock", 1, &Some("working".to_string()), Some(10), start(), Box::new(MockOutput::new("working", &events)));
+
+        registry.on_tick(&DataSet::new(), true, start() + Duration::seconds(10), &mut stats);
+
+        assert_eq!(vec!["failing: tick None", "working: tick None"], take_events(&events));
+        assert_eq!(1, stats.generator_error_count);
+    }
+
+    #[test]
+    fn test_init_errors_name_the_output() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+
+        let mut failing = MockOutput::new("failing", &events);
+        failing.fail_init = true;
+
+        let mut registry = OutputRegistry::new();
+        registry.add("mock", 3, &None, None, start(), Box::new(failing));
+
+        let err = registry.init().unwrap_err();
+
+        assert!(format!("{}", err).contains("mock[3]"));
+    }
+}
@@ -0,0 +1,206 @@
+use std::fmt;
+
+use resol_vbus::{
+    chrono::prelude::*,
+    Data,
+};
+
+
+use config::Config;
+use connection::{ConnectionBuilder, LiveStream};
+use data_source::DataSource;
+use error::{Error, Result};
+use vbus_server::VBusServer;
+
+
+/// Calculate the ID hash of a controller value from its ID name
+/// (e.g. "Relais_Handbetrieb_R1").
+pub fn value_id_hash(name: &str) -> i32 {
+    let mut hash = 0i32;
+    for c in name.bytes() {
+        hash = (hash.wrapping_mul(0x21).wrapping_add(c as i32)) & 0x7FFF_FFFF;
+    }
+    hash
+}
+
+
+/// Reads and writes controller parameters using VBus protocol 2.0 datagrams.
+///
+/// Every operation waits for the controller to offer the bus, sends its
+/// requests and gives the bus back to the controller afterwards. Values are
+/// referenced either by their numeric value index or by their ID name and are
+/// transferred as raw integers (e.g. `655` for 65.5 °C).
+///
+/// If the connection is shared using a `VBusServer`, the bus is reserved for
+/// the duration of each operation, so that client writes do not interfere.
+///
+/// ```no_run
+/// use resol_vbus_logger::{params::ParamClient, ConnectionBuilder};
+///
+/// let stream = ConnectionBuilder::new()
+///     .path("/dev/tty.usbmodem")
+///     .connect_stream()
+///     .unwrap();
+///
+/// let mut client = ParamClient::new(stream);
+/// let value = client.get("Tmax_S1").unwrap();
+/// client.set("Tmax_S1", value + 10, true).unwrap();
+/// ```
+pub struct ParamClient {
+    stream: LiveStream,
+    address: Option<u16>,
+    server: Option<VBusServer>,
+}
+
+
+impl fmt::Debug for ParamClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ParamClient")
+            .field("address", &self.address)
+            .finish()
+    }
+}
+
+
+impl ParamClient {
+    /// Create a client communicating over the `stream`.
+    pub fn new(stream: LiveStream) -> ParamClient {
+        ParamClient {
+            stream,
+            address: None,
+            server: None,
+        }
+    }
+
+    /// Create a client using the connection settings of the `config`.
+    pub fn from_config(config: &Config) -> Result<ParamClient> {
+        let connection_builder = ConnectionBuilder::from_config(config);
+        if !connection_builder.is_configured() {
+            return Err("Reading parameters requires a `path` or `address` to connect to".into());
+        }
+
+        Ok(ParamClient::new(connection_builder.connect_stream()?))
+    }
+
+    /// Talk to the controller at `address` instead of the one offering the bus.
+    pub fn address(mut self, address: u16) -> ParamClient {
+        self.address = Some(address);
+        self
+    }
+
+    /// Reserve the bus of the `server` sharing the connection during each operation.
+    pub fn server(mut self, server: VBusServer) -> ParamClient {
+        self.server = Some(server);
+        self
+    }
+
+    /// Read the raw value of the parameter `value`.
+    pub fn get(&mut self, value: &str) -> Result<i32> {
+        self.with_bus(|stream, address| {
+            let index = resolve_value_index(stream, address, value)?;
+
+            debug!("Getting value index {} from 0x{:04X}", index, address);
+            let reply = stream.get_value_by_index(address, index, 0)?;
+            reply_value(reply, value)
+        })
+    }
+
+    /// Write the raw `new_value` to the parameter `value` and return the value
+    /// reported back by the controller.
+    ///
+    /// If `save` is `true` the value is written in a bulk value transaction,
+    /// which makes the controller persist it across power cycles.
+    pub fn set(&mut self, value: &str, new_value: i32, save: bool) -> Result<i32> {
+        self.with_bus(|stream, address| {
+            let index = resolve_value_index(stream, address, value)?;
+
+            if save {
+                debug!("Setting and saving value index {} on 0x{:04X} to {}", index, address, new_value);
+                if stream.begin_bulk_value_transaction(address, 60)?.is_none() {
+                    return Err("Controller did not begin the bulk value transaction".into());
+                }
+                let result = stream.set_bulk_value_by_index(address, index, 0, new_value)
+                    .map_err(Error::from)
+                    .and_then(|reply| reply_value(reply, value));
+                let result = match result {
+                    Ok(result) => result,
+                    Err(err) => {
+                        debug!("Rolling back bulk value transaction");
+                        if let Err(rollback_err) = stream.rollback_bulk_value_transaction(address) {
+                            warn!("Unable to roll back bulk value transaction: {}", rollback_err);
+                        }
+                        return Err(err);
+                    },
+                };
+                if stream.commit_bulk_value_transaction(address)?.is_none() {
+                    return Err("Controller did not commit the bulk value transaction".into());
+                }
+                Ok(result)
+            } else {
+                debug!("Setting value index {} on 0x{:04X} to {}", index, address, new_value);
+                let reply = stream.set_value_by_index(address, index, 0, new_value)?;
+                reply_value(reply, value)
+            }
+        })
+    }
+
+    fn with_bus<T, F>(&mut self, f: F) -> Result<T> where F: FnOnce(&mut LiveStream, u16) -> Result<T> {
+        let _bus_lock = match self.server {
+            Some(ref server) => Some(server.lock_bus()?),
+            None => None,
+        };
+
+        debug!("Waiting for free bus");
+        let offer = match self.stream.wait_for_free_bus()? {
+            Some(data) => data,
+            None => return Err("Controller did not offer the bus".into()),
+        };
+
+        let master_address = offer.as_header().source_address;
+        let address = self.address.unwrap_or(master_address);
+
+        let result = f(&mut self.stream, address);
+
+        // Give the bus back to the master that offered it, even if a different controller was addressed
+        debug!("Releasing bus to 0x{:04X}", master_address);
+        if let Err(err) = self.stream.release_bus(master_address) {
+            warn!("Unable to release bus: {}", err);
+        }
+
+        result
+    }
+}
+
+
+impl DataSource for ParamClient {
+    fn receive_data(&mut self) -> Result<Option<Data>> {
+        self.stream.receive_data()
+    }
+
+    fn now(&self) -> DateTime<UTC> {
+        self.stream.now()
+    }
+}
+
+
+fn resolve_value_index(stream: &mut LiveStream, address: u16, value: &str) -> Result<i16> {
+    if let Ok(index) = value.parse::<i16>() {
+        return Ok(index);
+    }
+
+    let id_hash = value_id_hash(value);
+
+    debug!("Looking up value index of {} (ID hash 0x{:08X})", value, id_hash);
+    match stream.get_value_index_by_id_hash(address, id_hash)? {
+        Some(Data::Datagram(ref dgram)) => Ok(dgram.param16),
+        _ => Err(Error::from(format!("Controller does not know value {:?}", value))),
+    }
+}
+
+
+fn reply_value(reply: Option<Data>, value: &str) -> Result<i32> {
+    match reply {
+        Some(Data::Datagram(ref dgram)) => Ok(dgram.param32),
+        _ => Err(Error::from(format!("Controller did not answer request for value {:?}", value))),
+    }
+}
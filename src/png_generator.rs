@@ -1,8 +1,9 @@
 use std::collections::HashMap;
+use std::fmt;
 
 use image::{DynamicImage, Rgba};
 
-use imageproc::drawing::draw_text_mut;
+use imageproc::drawing::{draw_text_mut, text_size};
 
 use resol_vbus::{
     chrono::prelude::*,
@@ -14,28 +15,43 @@ use resol_vbus::{
 use rusttype::{Font, Scale};
 
 
-use config::Config;
+use config::{Config, PngConfig, PngLabelAlign, PngLabelConfig};
 
 use error::{Error, Result};
+use output::Output;
+use virtual_fields::VirtualFields;
 
 
+/// Renders the VBus values onto a background image on every tick.
 pub struct PngGenerator<'a> {
-    pub spec: Specification,
+    spec: Specification,
 
-    pub img: Option<DynamicImage>,
-    pub font: Font<'a>,
-    pub png_output_filename: String,
+    img: Option<DynamicImage>,
+    font: Font<'a>,
+    png_output_filename: String,
+    labels: Vec<PngLabelConfig>,
+}
+
+
+impl<'a> fmt::Debug for PngGenerator<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PngGenerator")
+            .field("png_output_filename", &self.png_output_filename)
+            .field("labels", &self.labels)
+            .finish()
+    }
 }
 
 
 impl<'a> PngGenerator<'a> {
-    pub fn from_config(config: &Config) -> Result<PngGenerator<'a>> {
+    /// Create a generator from the settings of a `[[png]]` table.
+    pub fn from_config(config: &Config, png_config: &PngConfig) -> Result<PngGenerator<'a>> {
         let spec_file = config.load_spec_file()?;
 
         let spec = Specification::from_file(spec_file, Language::De);
 
-        let img = if config.png_tick_interval > 0 {
-            Some(image::open(&config.png_input_filename)?)
+        let img = if png_config.tick_interval > 0 {
+            Some(image::open(&png_config.input_filename)?)
         } else {
             None
         };
@@ -47,25 +63,40 @@ impl<'a> PngGenerator<'a> {
             None => return Err("Unable to parse font".into()),
         };
 
-        let png_output_filename = config.png_output_filename.clone();
+        let png_output_filename = png_config.output_filename.clone();
+
+        let labels = png_config.labels.clone();
 
         Ok(PngGenerator {
             spec,
             img,
             font,
             png_output_filename,
+            labels,
         })
     }
 
-    pub fn generate(&self, data_set: &DataSet, now: &DateTime<UTC>) -> Result<()> {
+    /// Draw all labels and save the image.
+    pub fn generate(&self, data_set: &DataSet, virtual_fields: &VirtualFields, now: &DateTime<UTC>) -> Result<()> {
         let local_now = now.with_timezone(&Local);
 
         let mut field_map = HashMap::new();
 
         for field in self.spec.fields_in_data_set(data_set) {
+            if field.raw_value_f64().is_none() {
+                continue;
+            }
+
             let key = format!("{}_{}", field.packet_spec().packet_id, field.field_spec().field_id);
-            let value = format!("{}", field.fmt_raw_value(true));
-            field_map.insert(key, value);
+            let value_with_unit = format!("{}", field.fmt_raw_value(true));
+            let value_without_unit = format!("{}", field.fmt_raw_value(false));
+            field_map.insert(key, (value_with_unit, value_without_unit));
+        }
+
+        for field in virtual_fields.iter() {
+            if field.value.is_some() {
+                field_map.insert(field.id.clone(), (field.fmt_value(true), field.fmt_value(false)));
+            }
         }
 
         let mut img = match self.img {
@@ -73,131 +104,53 @@ impl<'a> PngGenerator<'a> {
             None => return Err(Error::from("No image loaded")),
         };
 
-        let default_str = "---".to_string();
-
-        // let scale = Scale { x: 26.0, y: 26.0 };
-        // draw_text_mut(&mut img, Rgba([0u8, 0u8, 0u8, 255u8]), 495, 11, scale, &self.font, "Gesamtertrag: ");
-
-        // let scale = Scale { x: 26.0, y: 26.0 };
-        // draw_text_mut(&mut img, Rgba([0u8, 0u8, 0u8, 255u8]), 15, 7, scale, &self.font, "Messzeitpunkt:");
-
-        // let scale = Scale { x: 20.0, y: 20.0 };
-        // draw_text_mut(&mut img, Rgba([0u8, 0u8, 0u8, 255u8]), 742, 13, scale, &self.font, "seit 29.08.2015");
-
-        // let scale = Scale { x: 22.0, y: 22.0 };
-        // draw_text_mut(&mut img, Rgba([0u8, 0u8, 0u8, 255u8]), 621, 487, scale, &self.font, "S4:");
-
-        // let scale = Scale { x: 20.0, y: 20.0 };
-        // draw_text_mut(&mut img, Rgba([0u8, 0u8, 0u8, 255u8]), 765, 303, scale, &self.font, "Soll");
-
-        let scale = Scale { x: 22.0, y: 22.0 };
-        let value = field_map.get("00_7210_6521_10_0100_002_2_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 526, 88, scale, &self.font, value);
-
-        let scale = Scale { x: 22.0, y: 22.0 };
-        let value = field_map.get("00_7210_6521_10_0100_000_2_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 753, 374, scale, &self.font, value);
-
-        let scale = Scale { x: 22.0, y: 22.0 };
-        let value = field_map.get("00_0010_7210_10_0100_000_2_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 261, 64, scale, &self.font, value);
-
-        let scale = Scale { x: 22.0, y: 22.0 };
-        let value = field_map.get("00_0010_7210_10_0100_010_2_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 374, 174, scale, &self.font, value);
-
-        let scale = Scale { x: 22.0, y: 22.0 };
-        let value = field_map.get("00_0010_7210_10_0100_004_2_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 357, 599, scale, &self.font, value);
-
-        let scale = Scale { x: 22.0, y: 22.0 };
-        let value = field_map.get("00_7210_6521_10_0100_004_2_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 269, 332, scale, &self.font, value);
-
-        let scale = Scale { x: 24.0, y: 24.0 };
-        let value = format!("{}", local_now.format("%d.%m.%Y %H:%M:%S"));
-        // draw_text_mut(&mut img, Rgba([0u8, 0u8, 0u8, 255u8]), 144, 8, scale, &self.font, &value);
-        draw_text_mut(&mut img, Rgba([0u8, 0u8, 0u8, 255u8]), 164, 12, scale, &self.font, &value);
-
-        let scale = Scale { x: 24.0, y: 24.0 };
-        let value = field_map.get("00_0010_7210_10_0100_024_2_0").unwrap_or(&default_str);
-        // draw_text_mut(&mut img, Rgba([0u8, 0u8, 0u8, 255u8]), 620, 12, scale, &self.font, value);
-        draw_text_mut(&mut img, Rgba([0u8, 0u8, 0u8, 255u8]), 620, 15, scale, &self.font, value);
-
-        let scale = Scale { x: 24.0, y: 24.0 };
-        let value = field_map.get("00_6521_7210_10_0200_032_1_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([0u8, 0u8, 0u8, 255u8]), 180, 717, scale, &self.font, value);
-
-        let scale = Scale { x: 20.0, y: 20.0 };
-        let value = field_map.get("00_6521_7210_10_0200_000_1_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 715, 352, scale, &self.font, value);
-
-        let scale = Scale { x: 20.0, y: 20.0 };
-        let value = field_map.get("00_6521_7210_10_0200_024_1_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 197, 378, scale, &self.font, value);
-
-        let scale = Scale { x: 20.0, y: 20.0 };
-        let value = field_map.get("00_0010_7210_10_0100_020_1_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 498, 276, scale, &self.font, value);
-
-        let scale = Scale { x: 20.0, y: 20.0 };
-        let value = field_map.get("00_0010_7210_10_0100_021_1_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 331, 415, scale, &self.font, value);
-
-        let scale = Scale { x: 20.0, y: 20.0 };
-        let value = field_map.get("00_0010_7210_10_0100_023_1_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 271, 421, scale, &self.font, value);
-
-        let scale = Scale { x: 22.0, y: 22.0 };
-        let value = field_map.get("00_6521_7210_10_0200_032_1_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 654, 612, scale, &self.font, value);
-
-        let scale = Scale { x: 22.0, y: 22.0 };
-        let value = field_map.get("00_0010_7210_10_0100_002_2_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([128u8, 0u8, 0u8, 255u8]), 457, 329, scale, &self.font, value);
-
-        let scale = Scale { x: 22.0, y: 22.0 };
-        let value = field_map.get("00_0010_7210_10_0100_006_2_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([128u8, 0u8, 0u8, 255u8]), 386, 329, scale, &self.font, value);
-
-        let scale = Scale { x: 22.0, y: 22.0 };
-        let value = field_map.get("00_0010_7210_10_0100_008_2_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([128u8, 0u8, 0u8, 255u8]), 382, 396, scale, &self.font, value);
-
-        let scale = Scale { x: 20.0, y: 20.0 };
-        let value = field_map.get("00_6521_7210_10_0200_008_1_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 671, 317, scale, &self.font, value);
-
-        let scale = Scale { x: 20.0, y: 20.0 };
-        let value = field_map.get("00_6521_7210_10_0200_016_1_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 667, 359, scale, &self.font, value);
-
-        let scale = Scale { x: 22.0, y: 22.0 };
-        let value = field_map.get("00_7210_6521_10_0100_008_2_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 523, 143, scale, &self.font, value);
-
-        let scale = Scale { x: 22.0, y: 22.0 };
-        let value = field_map.get("00_0010_7210_10_0100_018_2_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 496, 291, scale, &self.font, value);
-
-        let scale = Scale { x: 22.0, y: 22.0 };
-        let value = field_map.get("00_0010_7210_10_0100_004_2_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 376, 523, scale, &self.font, value);
-
-        let scale = Scale { x: 20.0, y: 20.0 };
-        let value = field_map.get("00_6521_7210_10_0200_032_1_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 188, 450, scale, &self.font, value);
-
-        let scale = Scale { x: 22.0, y: 22.0 };
-        let value = field_map.get("00_7210_6521_10_0100_006_2_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([255u8, 0u8, 0u8, 255u8]), 650, 487, scale, &self.font, value);
-
-        let scale = Scale { x: 20.0, y: 20.0 };
-        let value = field_map.get("00_0010_7211_10_0100_000_2_0").unwrap_or(&default_str);
-        draw_text_mut(&mut img, Rgba([0u8, 0u8, 0u8, 255u8]), 762, 321, scale, &self.font, value);
+        for label in self.labels.iter() {
+            let value = if let Some(ref field) = label.field {
+                match field_map.get(field) {
+                    Some(&(ref value_with_unit, ref value_without_unit)) => if label.unit.unwrap_or(true) {
+                        Some(value_with_unit.clone())
+                    } else {
+                        Some(value_without_unit.clone())
+                    },
+                    None => None,
+                }
+            } else if let Some(ref timestamp) = label.timestamp {
+                Some(local_now.format(timestamp).to_string())
+            } else {
+                label.text.clone()
+            };
+
+            let text = match value {
+                Some(value) => match label.format {
+                    Some(ref format) => format.replace("{}", &value),
+                    None => value,
+                },
+                None => label.fallback.clone().unwrap_or("---".to_string()),
+            };
+
+            let size = label.size.unwrap_or(22.0);
+            let scale = Scale { x: size, y: size };
+
+            let color = Rgba(label.color.unwrap_or([0, 0, 0, 255]));
+
+            let x = match label.align.unwrap_or(PngLabelAlign::Left) {
+                PngLabelAlign::Left => label.x,
+                PngLabelAlign::Center => label.x - text_size(scale, &self.font, &text).0 / 2,
+                PngLabelAlign::Right => label.x - text_size(scale, &self.font, &text).0,
+            };
+
+            draw_text_mut(&mut img, color, x, label.y, scale, &self.font, &text);
+        }
 
         img.save(&self.png_output_filename)?;
 
         Ok(())
     }
 }
+
+
+impl<'a> Output for PngGenerator<'a> {
+    fn on_tick(&mut self, data_set: &DataSet, virtual_fields: &VirtualFields, now: &DateTime<UTC>) -> Result<()> {
+        self.generate(data_set, virtual_fields, now)
+    }
+}
@@ -0,0 +1,69 @@
+use std::fmt;
+use std::io::Write;
+
+use resol_vbus::{
+    chrono::prelude::*,
+    Data,
+    DataSet,
+    RecordingWriter,
+};
+
+
+use config::RecordingConfig;
+use error::{Result};
+use output::Output;
+use timestamp_file_writer::TimestampFileWriter;
+
+
+/// Records every received data into VBus recording files.
+pub struct RecordingGenerator {
+    file_writer: TimestampFileWriter<Local>,
+}
+
+
+impl fmt::Debug for RecordingGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RecordingGenerator")
+            .field("file_writer", &self.file_writer)
+            .finish()
+    }
+}
+
+
+impl RecordingGenerator {
+    /// Create a generator from the settings of a `[[recording]]` table.
+    pub fn from_config(recording_config: &RecordingConfig) -> Result<RecordingGenerator> {
+        let file_writer = TimestampFileWriter::new(recording_config.output_filename_pattern.clone(), Local::now());
+
+        Ok(RecordingGenerator {
+            file_writer,
+        })
+    }
+
+    /// Append the `data` to the current recording file.
+    pub fn record(&mut self, data: &Data) -> Result<()> {
+        let output = &mut self.file_writer;
+
+        let local_timestamp = data.as_header().timestamp.with_timezone(&Local);
+
+        if output.set_timestamp(local_timestamp)? {
+            debug!("Recording to new file {}", output.filename().unwrap());
+        }
+
+        let mut data_set = DataSet::new();
+        data_set.add_data(data.clone());
+
+        RecordingWriter::new(&mut *output).write_data_set(&data_set)?;
+
+        output.flush()?;
+
+        Ok(())
+    }
+}
+
+
+impl Output for RecordingGenerator {
+    fn on_data(&mut self, data: &Data) -> Result<()> {
+        self.record(data)
+    }
+}
@@ -0,0 +1,390 @@
+use std::cmp;
+use std::fmt;
+
+use resol_vbus::{
+    chrono::prelude::*,
+    chrono::Duration,
+    DataSet,
+    Language,
+    Specification,
+    ToPacketFieldId,
+};
+
+use sqlite::{
+    Connection,
+    State,
+};
+
+
+use config::{Config, RelayConfig};
+use error::Result;
+use output::Output;
+use virtual_fields::{sample_field, VirtualField, VirtualFields};
+
+
+/// Computes the daily runtime, number of switch cycles and average speed of
+/// a relay, e.g. to spot short-cycling pumps.
+///
+/// The relay is considered on while its field is greater than 0, so both
+/// on / off fields and speed fields in percent are supported. The relay is
+/// sampled every time the data set changes, so that short switch cycles are
+/// not missed. The days are determined by the timestamps of the samples and
+/// runtime spanning midnight is split between both days. The statistics of each day are stored in a
+/// SQLite table whenever they change, from which they are restored when the
+/// day is entered again, and provided as virtual fields named
+/// `relay_<name>_runtime` (in hours), `relay_<name>_cycles` and
+/// `relay_<name>_average_speed`.
+pub struct RelayStatistics {
+    spec: Specification,
+    name: String,
+    field_id: String,
+    unit: String,
+    max_gap: Duration,
+    connection: Connection,
+    table: String,
+    last_sample: Option<(DateTime<UTC>, f64)>,
+    day: Option<String>,
+    runtime: f64,
+    cycle_count: i64,
+    speed_sum: f64,
+    is_changed: bool,
+}
+
+
+impl fmt::Debug for RelayStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RelayStatistics")
+            .field("name", &self.name)
+            .field("field_id", &self.field_id)
+            .field("table", &self.table)
+            .field("day", &self.day)
+            .finish()
+    }
+}
+
+
+impl RelayStatistics {
+    /// Create the statistics from the settings of a `[[relay]]` table.
+    pub fn from_config(config: &Config, relay_config: &RelayConfig) -> Result<RelayStatistics> {
+        let spec_file = config.load_spec_file()?;
+
+        let spec = Specification::from_file(spec_file, Language::En);
+
+        let id = relay_config.field.to_packet_field_id()?;
+
+        let unit = match spec.get_packet_spec_by_id(id.packet_id).get_field_spec(id.field_id) {
+            Some(field_spec) => field_spec.unit_text.trim().to_string(),
+            None => return Err(format!("Field {} is not described in the VSF", relay_config.field).into()),
+        };
+
+        let table = relay_config.sqlite_table.clone().unwrap_or("relays".to_string());
+
+        let connection = sqlite::open(&relay_config.sqlite_filename)?;
+
+        let statistics = RelayStatistics {
+            spec,
+            name: relay_config.name.clone(),
+            field_id: relay_config.field.clone(),
+            unit,
+            max_gap: Duration::seconds(relay_config.max_gap.unwrap_or(300)),
+            connection,
+            table,
+            last_sample: None,
+            day: None,
+            runtime: 0.0,
+            cycle_count: 0,
+            speed_sum: 0.0,
+            is_changed: false,
+        };
+
+        statistics.create_table()?;
+
+        Ok(statistics)
+    }
+
+    fn create_table(&self) -> Result<()> {
+        let stmt = format!("CREATE TABLE IF NOT EXISTS {} (name TEXT, day TEXT, runtime_hours REAL, cycle_count INTEGER, average_speed REAL, speed_sum REAL, PRIMARY KEY (name, day))", self.table);
+        self.connection.execute(stmt)?;
+        Ok(())
+    }
+
+    /// The IDs of the virtual fields provided by statistics created from the
+    /// `relay_config`.
+    pub fn field_ids(relay_config: &RelayConfig) -> Vec<String> {
+        vec![
+            format!("relay_{}_runtime", relay_config.name),
+            format!("relay_{}_cycles", relay_config.name),
+            format!("relay_{}_average_speed", relay_config.name),
+        ]
+    }
+
+    /// Sample the relay field in the `data_set` and update the statistics.
+    pub fn update(&mut self, data_set: &DataSet) -> Result<()> {
+        let (timestamp, value) = match sample_field(&self.spec, data_set, &self.field_id) {
+            Some(sample) => sample,
+            None => return Ok(()),
+        };
+
+        self.add_sample(timestamp, value)
+    }
+
+    fn add_sample(&mut self, timestamp: DateTime<UTC>, value: f64) -> Result<()> {
+        if let Some((last_timestamp, last_value)) = self.last_sample {
+            if timestamp <= last_timestamp {
+                // Packet has not been received again since the last sample
+                return Ok(());
+            }
+
+            let gap = timestamp.signed_duration_since(last_timestamp);
+            if gap > self.max_gap {
+                debug!("Not counting relay {} over a gap of {} seconds", self.name, gap.num_seconds());
+            } else if last_value > 0.0 {
+                // Split the runtime at midnight, so that each day gets its share
+                let mut start = last_timestamp;
+                while start < timestamp {
+                    self.enter_day(&start)?;
+
+                    let end = cmp::min(next_midnight(&start), timestamp);
+                    let seconds = end.signed_duration_since(start).num_milliseconds() as f64 / 1000.0;
+                    self.runtime += seconds;
+                    self.speed_sum += last_value * seconds;
+                    self.is_changed = true;
+
+                    start = end;
+                }
+            }
+
+            self.enter_day(&timestamp)?;
+
+            if last_value <= 0.0 && value > 0.0 {
+                self.cycle_count += 1;
+                self.is_changed = true;
+            }
+        } else {
+            self.enter_day(&timestamp)?;
+        }
+
+        self.last_sample = Some((timestamp, value));
+
+        if self.is_changed {
+            self.store()?;
+            self.is_changed = false;
+        }
+
+        Ok(())
+    }
+
+    /// Switch to the day containing the `timestamp`, storing the statistics
+    /// of the previous day and restoring the ones of the new day.
+    fn enter_day(&mut self, timestamp: &DateTime<UTC>) -> Result<()> {
+        let day = day_string(timestamp);
+        if self.day.as_ref() == Some(&day) {
+            return Ok(());
+        }
+
+        if self.is_changed {
+            self.store()?;
+            self.is_changed = false;
+        }
+
+        self.runtime = 0.0;
+        self.cycle_count = 0;
+        self.speed_sum = 0.0;
+        self.load(&day)?;
+        self.day = Some(day);
+
+        Ok(())
+    }
+
+    fn average_speed(&self) -> Option<f64> {
+        if self.runtime > 0.0 {
+            Some(self.speed_sum / self.runtime)
+        } else {
+            None
+        }
+    }
+
+    fn load(&mut self, day: &str) -> Result<()> {
+        let stmt = format!("SELECT runtime_hours, cycle_count, speed_sum FROM {} WHERE name = ? AND day = ?", self.table);
+        let mut stmt = self.connection.prepare(stmt)?;
+        stmt.bind(1, self.name.as_str())?;
+        stmt.bind(2, day)?;
+
+        while stmt.next()? == State::Row {
+            self.runtime = stmt.read::<f64>(0)? * 3600.0;
+            self.cycle_count = stmt.read::<i64>(1)?;
+            self.speed_sum = stmt.read::<f64>(2)?;
+        }
+
+        Ok(())
+    }
+
+    fn store(&self) -> Result<()> {
+        let day = match self.day {
+            Some(ref day) => day,
+            None => return Ok(()),
+        };
+
+        let stmt = format!("INSERT OR REPLACE INTO {} (name, day, runtime_hours, cycle_count, average_speed, speed_sum) VALUES (?, ?, ?, ?, ?, ?)", self.table);
+        let mut stmt = self.connection.prepare(stmt)?;
+        stmt.bind(1, self.name.as_str())?;
+        stmt.bind(2, day.as_str())?;
+        stmt.bind(3, self.runtime / 3600.0)?;
+        stmt.bind(4, self.cycle_count)?;
+        match self.average_speed() {
+            Some(average_speed) => stmt.bind(5, average_speed)?,
+            None => stmt.bind(5, ())?,
+        }
+        stmt.bind(6, self.speed_sum)?;
+
+        while stmt.next()? != State::Done {
+            // repeat
+        }
+
+        Ok(())
+    }
+}
+
+
+impl Output for RelayStatistics {
+    fn on_data_set_changed(&mut self, data_set: &DataSet, _virtual_fields: &VirtualFields) -> Result<()> {
+        self.update(data_set)
+    }
+
+    fn provide_virtual_fields(&self, virtual_fields: &mut VirtualFields) {
+        let has_day = self.day.is_some();
+
+        virtual_fields.set(VirtualField {
+            id: format!("relay_{}_runtime", self.name),
+            name: format!("{} runtime", self.name),
+            unit: "h".to_string(),
+            precision: 2,
+            value: if has_day { Some(self.runtime / 3600.0) } else { None },
+        });
+        virtual_fields.set(VirtualField {
+            id: format!("relay_{}_cycles", self.name),
+            name: format!("{} cycles", self.name),
+            unit: String::new(),
+            precision: 0,
+            value: if has_day { Some(self.cycle_count as f64) } else { None },
+        });
+        virtual_fields.set(VirtualField {
+            id: format!("relay_{}_average_speed", self.name),
+            name: format!("{} average speed", self.name),
+            unit: self.unit.clone(),
+            precision: 0,
+            value: self.average_speed(),
+        });
+    }
+}
+
+
+fn day_string(timestamp: &DateTime<UTC>) -> String {
+    timestamp.with_timezone(&Local).format("%Y-%m-%d").to_string()
+}
+
+
+fn next_midnight(timestamp: &DateTime<UTC>) -> DateTime<UTC> {
+    let next_day = timestamp.with_timezone(&Local).date().succ();
+
+    // Midnight does not exist on days where DST starts at midnight
+    let midnight = match next_day.and_hms_opt(0, 0, 0) {
+        Some(midnight) => midnight,
+        None => next_day.and_hms(1, 0, 0),
+    };
+
+    midnight.with_timezone(&UTC)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use resol_vbus::SpecificationFile;
+
+    use super::*;
+
+    fn statistics() -> RelayStatistics {
+        let statistics = RelayStatistics {
+            spec: Specification::from_file(SpecificationFile::new_default(), Language::En),
+            name: "pump".to_string(),
+            field_id: "00_0010_7E11_10_0100_000_2_0".to_string(),
+            unit: "%".to_string(),
+            max_gap: Duration::seconds(300),
+            connection: sqlite::open(":memory:").unwrap(),
+            table: "relays".to_string(),
+            last_sample: None,
+            day: None,
+            runtime: 0.0,
+            cycle_count: 0,
+            speed_sum: 0.0,
+            is_changed: false,
+        };
+        statistics.create_table().unwrap();
+        statistics
+    }
+
+    fn local(day: u32, hour: u32, minute: u32, second: u32) -> DateTime<UTC> {
+        Local.ymd(2024, 6, day).and_hms(hour, minute, second).with_timezone(&UTC)
+    }
+
+    fn assert_seconds(expected: f64, actual: f64) {
+        assert!((expected - actual).abs() < 0.001, "expected {} seconds, got {}", expected, actual);
+    }
+
+    #[test]
+    fn test_next_midnight() {
+        assert_eq!(local(2, 0, 0, 0), next_midnight(&local(1, 13, 0, 0)));
+        assert_eq!(local(2, 0, 0, 0), next_midnight(&local(1, 23, 59, 59)));
+        assert_eq!(local(3, 0, 0, 0), next_midnight(&local(2, 0, 0, 0)));
+    }
+
+    #[test]
+    fn test_cycles_runtime_and_average_speed_are_counted() {
+        let mut statistics = statistics();
+
+        statistics.add_sample(local(1, 12, 0, 0), 0.0).unwrap();
+        statistics.add_sample(local(1, 12, 0, 10), 50.0).unwrap();
+        statistics.add_sample(local(1, 12, 1, 10), 100.0).unwrap();
+        statistics.add_sample(local(1, 12, 2, 10), 0.0).unwrap();
+        statistics.add_sample(local(1, 12, 2, 20), 100.0).unwrap();
+        statistics.add_sample(local(1, 12, 2, 25), 0.0).unwrap();
+
+        // A packet that has not been received again is not counted twice
+        statistics.add_sample(local(1, 12, 2, 25), 0.0).unwrap();
+
+        assert_eq!(2, statistics.cycle_count);
+        assert_seconds(125.0, statistics.runtime);
+        assert_eq!(Some(9500.0 / 125.0), statistics.average_speed());
+    }
+
+    #[test]
+    fn test_gaps_are_skipped() {
+        let mut statistics = statistics();
+
+        statistics.add_sample(local(1, 12, 0, 0), 100.0).unwrap();
+        statistics.add_sample(local(1, 12, 10, 0), 100.0).unwrap();
+        assert_seconds(0.0, statistics.runtime);
+
+        statistics.add_sample(local(1, 12, 11, 0), 100.0).unwrap();
+        assert_seconds(60.0, statistics.runtime);
+        assert_eq!(0, statistics.cycle_count);
+    }
+
+    #[test]
+    fn test_runtime_is_split_at_midnight() {
+        let mut statistics = statistics();
+
+        statistics.add_sample(local(1, 23, 59, 30), 0.0).unwrap();
+        statistics.add_sample(local(1, 23, 59, 40), 100.0).unwrap();
+        statistics.add_sample(local(2, 0, 0, 30), 100.0).unwrap();
+
+        assert_eq!(Some("2024-06-02".to_string()), statistics.day);
+        assert_seconds(30.0, statistics.runtime);
+        assert_eq!(0, statistics.cycle_count);
+
+        statistics.load("2024-06-01").unwrap();
+        assert_seconds(20.0, statistics.runtime);
+        assert_eq!(1, statistics.cycle_count);
+    }
+}
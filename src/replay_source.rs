@@ -0,0 +1,428 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read, Result as IoResult};
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+use resol_vbus::{
+    chrono::prelude::*,
+    chrono::Duration,
+    Data,
+    LiveDataReader,
+    RecordingReader,
+};
+
+
+use config::Config;
+use data_source::DataSource;
+use error::Result;
+
+
+/// Number of bytes transmitted per second on a 9600 baud VBus (8N1).
+const BYTES_PER_SECOND: i64 = 960;
+
+
+/// A reader that hands out data in small chunks and counts the bytes consumed.
+///
+/// Raw captures do not contain timestamps, so the byte offset is used to
+/// estimate when each data was originally received.
+struct CountingReader {
+    reader: BufReader<File>,
+    offset: Rc<Cell<u64>>,
+}
+
+
+impl Read for CountingReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let len = buf.len().min(64);
+        let len = self.reader.read(&mut buf[0..len])?;
+        self.offset.set(self.offset.get() + len as u64);
+        Ok(len)
+    }
+}
+
+
+enum ReplayReader {
+    Recording(RecordingReader<BufReader<File>>),
+    Raw {
+        reader: LiveDataReader<CountingReader>,
+        offset: Rc<Cell<u64>>,
+        start_time: DateTime<UTC>,
+    },
+}
+
+
+/// Replays VBus recordings and raw captures as if they were received live.
+pub struct ReplaySource {
+    channel: u8,
+    raw_start_time: Option<DateTime<UTC>>,
+    filenames: VecDeque<String>,
+    reader: Option<ReplayReader>,
+    pending: VecDeque<Data>,
+    now: DateTime<UTC>,
+    finished: bool,
+}
+
+
+impl fmt::Debug for ReplaySource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReplaySource")
+            .field("filenames", &self.filenames)
+            .field("now", &self.now)
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
+
+
+impl ReplaySource {
+    /// Create a source replaying the `replay_filenames`.
+    pub fn from_config(config: &Config) -> Result<ReplaySource> {
+        let filenames = match config.replay_filenames {
+            Some(ref filenames) => filenames.iter().cloned().collect(),
+            None => VecDeque::new(),
+        };
+
+        let raw_start_time = match config.replay_raw_start_time {
+            Some(ref start_time) => match DateTime::parse_from_rfc3339(start_time) {
+                Ok(start_time) => Some(start_time.with_timezone(&UTC)),
+                Err(err) => return Err(format!("Unable to parse replay_raw_start_time {:?}: {}", start_time, err).into()),
+            },
+            None => None,
+        };
+
+        Ok(ReplaySource {
+            channel: config.channel.unwrap_or(0),
+            raw_start_time,
+            filenames,
+            reader: None,
+            pending: VecDeque::new(),
+            now: UTC::now(),
+            finished: false,
+        })
+    }
+
+    fn open_next_file(&mut self) -> Result<bool> {
+        let filename = match self.filenames.pop_front() {
+            Some(filename) => filename,
+            None => return Ok(false),
+        };
+
+        debug!("Replaying {:?}", filename);
+
+        let file = File::open(&filename)?;
+
+        let reader = if filename.to_lowercase().ends_with(".vbus") {
+            ReplayReader::Recording(RecordingReader::new(BufReader::new(file)))
+        } else {
+            let start_time = match self.raw_start_time {
+                Some(start_time) => start_time,
+                None => {
+                    // Assume the capture was written live and ended at the file's modification time
+                    let metadata = file.metadata()?;
+                    let duration = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+                    let end_time = UTC.timestamp(duration.as_secs() as i64, duration.subsec_nanos());
+                    end_time - Duration::seconds(metadata.len() as i64 / BYTES_PER_SECOND)
+                }
+            };
+
+            let offset = Rc::new(Cell::new(0));
+
+            let counting_reader = CountingReader {
+                reader: BufReader::new(file),
+                offset: offset.clone(),
+            };
+
+            ReplayReader::Raw {
+                reader: LiveDataReader::new(self.channel, counting_reader),
+                offset,
+                start_time,
+            }
+        };
+
+        self.reader = Some(reader);
+
+        Ok(true)
+    }
+
+    fn read_next_data(&mut self) -> Result<Option<Data>> {
+        loop {
+            if let Some(data) = self.pending.pop_front() {
+                return Ok(Some(data));
+            }
+
+            let next = match self.reader {
+                Some(ReplayReader::Recording(ref mut reader)) => {
+                    match reader.read_data_set()? {
+                        Some(data_set) => {
+                            let mut data = data_set.iter().cloned().collect::<Vec<_>>();
+                            data.sort_by_key(|data| data.as_header().timestamp);
+                            self.pending.extend(data);
+                            continue;
+                        },
+                        None => None,
+                    }
+                },
+                Some(ReplayReader::Raw { ref mut reader, ref offset, start_time }) => {
+                    match reader.read_data()? {
+                        Some(mut data) => {
+                            let millis = offset.get() as i64 * 1000 / BYTES_PER_SECOND;
+                            set_timestamp(&mut data, start_time + Duration::milliseconds(millis));
+                            Some(data)
+                        },
+                        None => None,
+                    }
+                },
+                None => None,
+            };
+
+            if next.is_some() {
+                return Ok(next);
+            }
+
+            self.reader = None;
+
+            if !self.open_next_file()? {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+
+impl DataSource for ReplaySource {
+    fn receive_data(&mut self) -> Result<Option<Data>> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        match self.read_next_data()? {
+            Some(data) => {
+                self.now = data.as_header().timestamp;
+                Ok(Some(data))
+            },
+            None => {
+                self.finished = true;
+                Ok(None)
+            },
+        }
+    }
+
+    fn now(&self) -> DateTime<UTC> {
+        self.now
+    }
+
+    fn start_time(&mut self) -> Result<DateTime<UTC>> {
+        // Peek at the first data, it is handed out again by `receive_data`
+        if let Some(data) = self.read_next_data()? {
+            self.now = data.as_header().timestamp;
+            self.pending.push_front(data);
+        }
+
+        Ok(self.now)
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+
+fn set_timestamp(data: &mut Data, timestamp: DateTime<UTC>) {
+    match *data {
+        Data::Packet(ref mut packet) => packet.header.timestamp = timestamp,
+        Data::Datagram(ref mut dgram) => dgram.header.timestamp = timestamp,
+        Data::Telegram(ref mut tgram) => tgram.header.timestamp = timestamp,
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use resol_vbus::{
+        DataSet,
+        Header,
+        Packet,
+        RecordingWriter,
+    };
+
+    use super::*;
+
+    fn temp_filename(name: &str) -> String {
+        let path = env::temp_dir().join(format!("resol_vbus_logger_replay_{}_{}", process::id(), name));
+        path.to_string_lossy().into_owned()
+    }
+
+    fn config(toml: &str) -> Config {
+        ::toml::from_str(&format!("known_packet_ids = []\n{}", toml)).unwrap()
+    }
+
+    fn packet(source_address: u16, timestamp: DateTime<UTC>) -> Data {
+        Data::Packet(Packet {
+            header: Header {
+                timestamp,
+                channel: 0,
+                destination_address: 0x0010,
+                source_address,
+                protocol_version: 0x10,
+            },
+            command: 0x0100,
+            frame_count: 0,
+            frame_data: [0; 508],
+        })
+    }
+
+    fn write_recording(filename: &str, data: Vec<Data>) {
+        let mut data_set = DataSet::new();
+        for data in data {
+            data_set.add_data(data);
+        }
+
+        let mut file = File::create(filename).unwrap();
+        RecordingWriter::new(&mut file).write_data_set(&data_set).unwrap();
+    }
+
+    /// Encode a VBus protocol 1.0 packet without frames.
+    fn raw_packet(destination_address: u16, source_address: u16, command: u16) -> Vec<u8> {
+        let mut bytes = vec![
+            0xAA,
+            (destination_address & 0xFF) as u8,
+            (destination_address >> 8) as u8,
+            (source_address & 0xFF) as u8,
+            (source_address >> 8) as u8,
+            0x10,
+            (command & 0xFF) as u8,
+            (command >> 8) as u8,
+            0,
+        ];
+        let checksum = bytes[1..].iter().fold(0x7Fu8, |acc, &byte| (0x80 + acc - byte) & 0x7F);
+        bytes.push(checksum);
+        bytes
+    }
+
+    fn receive_all(source: &mut ReplaySource) -> Vec<Data> {
+        let mut result = Vec::new();
+        while let Some(data) = source.receive_data().unwrap() {
+            result.push(data);
+        }
+        result
+    }
+
+    #[test]
+    fn test_recordings_are_replayed_in_order() {
+        let start = UTC.ymd(2024, 6, 1).and_hms(12, 0, 0);
+
+        let filename1 = temp_filename("1.vbus");
+        let filename2 = temp_filename("2.vbus");
+        write_recording(&filename1, vec![
+            packet(0x7E11, start + Duration::seconds(2)),
+            packet(0x7E12, start + Duration::seconds(1)),
+        ]);
+        write_recording(&filename2, vec![
+            packet(0x7E11, start + Duration::seconds(3)),
+        ]);
+
+        let mut source = ReplaySource::from_config(&config(&format!("replay_filenames = [{:?}, {:?}]", filename1, filename2))).unwrap();
+
+        let data = receive_all(&mut source);
+
+        fs::remove_file(&filename1).unwrap();
+        fs::remove_file(&filename2).unwrap();
+
+        let ids = data.iter().map(|data| data.id_string()).collect::<Vec<_>>();
+        assert_eq!(vec![
+            "00_0010_7E12_10_0100",
+            "00_0010_7E11_10_0100",
+            "00_0010_7E11_10_0100",
+        ], ids);
+
+        let timestamps = data.iter().map(|data| data.as_header().timestamp).collect::<Vec<_>>();
+        assert_eq!(vec![
+            start + Duration::seconds(1),
+            start + Duration::seconds(2),
+            start + Duration::seconds(3),
+        ], timestamps);
+
+        assert!(source.is_finished());
+        assert_eq!(start + Duration::seconds(3), source.now());
+        assert!(source.receive_data().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_start_time_is_the_first_timestamp() {
+        let start = UTC.ymd(2024, 6, 1).and_hms(12, 0, 0);
+
+        let filename = temp_filename("start.vbus");
+        write_recording(&filename, vec![
+            packet(0x7E11, start + Duration::seconds(1)),
+            packet(0x7E12, start + Duration::seconds(2)),
+        ]);
+
+        let mut source = ReplaySource::from_config(&config(&format!("replay_filenames = [{:?}]", filename))).unwrap();
+
+        assert_eq!(start + Duration::seconds(1), source.start_time().unwrap());
+        assert_eq!(start + Duration::seconds(1), source.now());
+
+        let data = receive_all(&mut source);
+
+        fs::remove_file(&filename).unwrap();
+
+        assert_eq!(2, data.len());
+        assert_eq!("00_0010_7E11_10_0100", data[0].id_string());
+    }
+
+    #[test]
+    fn test_raw_captures_use_configured_start_time() {
+        let filename = temp_filename("capture.bin");
+
+        let mut bytes = Vec::new();
+        for _ in 0..3 {
+            bytes.extend(raw_packet(0x0010, 0x7E11, 0x0100));
+        }
+        fs::write(&filename, &bytes).unwrap();
+
+        let mut source = ReplaySource::from_config(&config(&format!("replay_filenames = [{:?}]\nreplay_raw_start_time = \"2024-06-01T12:00:00Z\"\nchannel = 1", filename))).unwrap();
+
+        let data = receive_all(&mut source);
+
+        fs::remove_file(&filename).unwrap();
+
+        let start = UTC.ymd(2024, 6, 1).and_hms(12, 0, 0);
+        let end = start + Duration::milliseconds(bytes.len() as i64 * 1000 / BYTES_PER_SECOND);
+
+        assert_eq!(3, data.len());
+
+        let mut last_timestamp = start;
+        for data in data.iter() {
+            assert_eq!("01_0010_7E11_10_0100", data.id_string());
+
+            let timestamp = data.as_header().timestamp;
+            assert!(timestamp >= last_timestamp);
+            assert!(timestamp <= end);
+            last_timestamp = timestamp;
+        }
+    }
+
+    #[test]
+    fn test_invalid_raw_start_time_is_rejected() {
+        let result = ReplaySource::from_config(&config("replay_filenames = []\nreplay_raw_start_time = \"yesterday\""));
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_missing_file_is_an_error() {
+        let filename = temp_filename("missing.vbus");
+
+        let mut source = ReplaySource::from_config(&config(&format!("replay_filenames = [{:?}]", filename))).unwrap();
+
+        assert!(source.receive_data().is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use std::fmt;
 use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::time::Duration;
 
@@ -6,10 +7,21 @@ use serialport::{SerialPort};
 use resol_vbus::ReadWithTimeout;
 
 
+/// Adapts a serial port so that it can be used by a `LiveDataStream`.
 pub struct SerialPortStream(Box<dyn SerialPort>);
 
 
+impl fmt::Debug for SerialPortStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("SerialPortStream")
+            .field(&self.0.name())
+            .finish()
+    }
+}
+
+
 impl SerialPortStream {
+    /// Wrap the `port`.
     pub fn new(port: Box<dyn SerialPort>) -> SerialPortStream {
         SerialPortStream(port)
     }
@@ -0,0 +1,326 @@
+use std::collections::HashSet;
+
+use resol_vbus::{
+    chrono::prelude::*,
+    Data,
+    DataSet,
+    Header,
+    Packet,
+    ToPacketId,
+};
+
+
+use config::Config;
+use error::Result;
+use state_file::StateFile;
+
+
+/// What happened to the data set of a `SettleTracker` when adding a data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettleEvent {
+    /// The data set is still settling or stayed settled.
+    None,
+    /// The data set has settled because of this data.
+    Settled,
+    /// Restored packets that were not received again were removed from the
+    /// settled data set.
+    DataSetChanged,
+}
+
+
+/// Collects the packets transmitted on the VBus and decides when the set of
+/// packets is complete.
+///
+/// Every time a new packet is discovered the tracker waits for three times the
+/// number of known packets to be received again before considering the data
+/// set "settled". A new packet appearing after the data set has settled means
+/// that the bus topology has changed, so the tracker starts settling again.
+/// Packets of the settled data set that were removed as stale are added back
+/// without settling again.
+///
+/// Packets restored from the state file must be received again within the
+/// same number of packets, otherwise they are removed from the data set.
+#[derive(Debug)]
+pub struct SettleTracker {
+    data_set: DataSet,
+    is_settled: bool,
+    settled_max_count: usize,
+    settled_count: usize,
+    settled_packet_ids: HashSet<String>,
+    unconfirmed_packet_ids: HashSet<String>,
+    confirmation_count: usize,
+}
+
+
+impl SettleTracker {
+    /// Create a tracker that has not seen any packets yet.
+    pub fn new() -> SettleTracker {
+        SettleTracker {
+            data_set: DataSet::new(),
+            is_settled: false,
+            settled_max_count: 0,
+            settled_count: 0,
+            settled_packet_ids: HashSet::new(),
+            unconfirmed_packet_ids: HashSet::new(),
+            confirmation_count: 0,
+        }
+    }
+
+    /// Create a tracker that is pre-populated with the `known_packet_ids`,
+    /// ignoring the state file.
+    pub fn from_known_packet_ids(config: &Config) -> Result<SettleTracker> {
+        let mut tracker = SettleTracker::new();
+
+        for packet_id in config.known_packet_ids.iter() {
+            let packet_id = packet_id.to_packet_id()?;
+            let packet = Packet {
+                header: Header {
+                    timestamp: UTC::now(),
+                    channel: packet_id.0,
+                    destination_address: packet_id.1,
+                    source_address: packet_id.2,
+                    protocol_version: 0x10,
+                },
+                command: packet_id.3,
+                frame_count: 0,
+                frame_data: [0; 508],
+            };
+            tracker.data_set.add_data(Data::Packet(packet));
+        }
+
+        tracker.settled_max_count = tracker.data_set.len() * 3;
+
+        Ok(tracker)
+    }
+
+    /// Create a tracker that is pre-populated with the `known_packet_ids`.
+    ///
+    /// If a state file is configured, the packets persisted in it are added
+    /// as well and the data set is considered settled right away.
+    pub fn from_config(config: &Config) -> Result<SettleTracker> {
+        let mut tracker = SettleTracker::from_known_packet_ids(config)?;
+
+        if let Some(state_file) = StateFile::from_config(config) {
+            match state_file.load() {
+                Ok(data) => if !data.is_empty() {
+                    debug!("Restored {} packets from state file", data.len());
+
+                    for data in data {
+                        tracker.unconfirmed_packet_ids.insert(data.id_string());
+                        tracker.data_set.add_data(data);
+                    }
+
+                    tracker.is_settled = true;
+                    tracker.settled_packet_ids = tracker.packet_ids();
+                },
+                Err(err) => warn!("Unable to load state file, need to settle: {}", err),
+            }
+        }
+
+        tracker.settled_max_count = tracker.data_set.len() * 3;
+
+        Ok(tracker)
+    }
+
+    /// The packets collected so far.
+    pub fn data_set(&self) -> &DataSet {
+        &self.data_set
+    }
+
+    /// The packets collected so far, e.g. to remove stale packets.
+    pub fn data_set_mut(&mut self) -> &mut DataSet {
+        &mut self.data_set
+    }
+
+    /// Whether the data set has settled.
+    pub fn is_settled(&self) -> bool {
+        self.is_settled
+    }
+
+    /// Add a received data to the data set.
+    ///
+    /// Returns `SettleEvent::Settled` only once when the data set settles.
+    /// Datagrams and telegrams are ignored.
+    pub fn add_data(&mut self, data: Data) -> SettleEvent {
+        if !data.is_packet() {
+            SettleEvent::None
+        } else {
+            let id = data.id_string();
+
+            let len_before = self.data_set.len();
+
+            self.data_set.add_data(data);
+
+            let len_after = self.data_set.len();
+
+            let removed_packets = self.confirm_restored_packet(&id);
+
+            let is_new_packet = len_before != len_after;
+
+            if is_new_packet && self.settled_packet_ids.contains(&id) {
+                debug!("Packet {} of the settled data set is received again", id);
+            }
+
+            if is_new_packet && !self.settled_packet_ids.contains(&id) {
+                if self.is_settled {
+                    info!("Bus topology changed, need to resettle...");
+                    self.is_settled = false;
+                } else {
+                    debug!("Received new packet, need to resettle...");
+                }
+                self.settled_max_count = len_after * 3;
+                self.settled_count = 0;
+                SettleEvent::None
+            } else if self.is_settled {
+                if removed_packets {
+                    SettleEvent::DataSetChanged
+                } else {
+                    SettleEvent::None
+                }
+            } else if self.settled_count < self.settled_max_count {
+                self.settled_count += 1;
+                let percent = 100.0f32 * self.settled_count as f32 / self.settled_max_count as f32;
+                debug!("Settling: {} / {} -> {:.2}%", self.settled_count, self.settled_max_count, percent);
+                SettleEvent::None
+            } else {
+                self.is_settled = true;
+                self.settled_packet_ids = self.packet_ids();
+                SettleEvent::Settled
+            }
+        }
+    }
+
+    fn packet_ids(&self) -> HashSet<String> {
+        self.data_set.iter().map(|data| data.id_string()).collect()
+    }
+
+    /// Mark the restored packet with the `id` as received again and remove
+    /// the restored packets that were not received within the settle count.
+    ///
+    /// Returns `true` if packets were removed from the data set.
+    fn confirm_restored_packet(&mut self, id: &str) -> bool {
+        if self.unconfirmed_packet_ids.is_empty() {
+            return false;
+        }
+
+        self.unconfirmed_packet_ids.remove(id);
+        self.confirmation_count += 1;
+
+        if self.unconfirmed_packet_ids.is_empty() || self.confirmation_count < self.settled_max_count {
+            return false;
+        }
+
+        let mut removed_packet_ids = self.unconfirmed_packet_ids.drain().collect::<Vec<_>>();
+        removed_packet_ids.sort();
+        info!("Bus topology changed, packets {:?} are no longer received", removed_packet_ids);
+
+        let mut data_set = DataSet::new();
+        for data in self.data_set.iter() {
+            if !removed_packet_ids.contains(&data.id_string()) {
+                data_set.add_data(data.clone());
+            }
+        }
+
+        for id in removed_packet_ids.iter() {
+            self.settled_packet_ids.remove(id);
+        }
+
+        self.data_set = data_set;
+        self.settled_max_count = self.data_set.len() * 3;
+
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(source_address: u16, timestamp: i64) -> Data {
+        Data::Packet(Packet {
+            header: Header {
+                timestamp: UTC.timestamp(timestamp, 0),
+                channel: 0,
+                destination_address: 0x0010,
+                source_address,
+                protocol_version: 0x10,
+            },
+            command: 0x0100,
+            frame_count: 0,
+            frame_data: [0; 508],
+        })
+    }
+
+    fn settle(tracker: &mut SettleTracker, source_addresses: &[u16]) {
+        for timestamp in 0..10 {
+            for &source_address in source_addresses {
+                tracker.add_data(packet(source_address, timestamp));
+            }
+        }
+        assert!(tracker.is_settled());
+    }
+
+    #[test]
+    fn test_settles_after_packets_are_repeated() {
+        let mut tracker = SettleTracker::new();
+
+        assert_eq!(SettleEvent::None, tracker.add_data(packet(0x7E11, 0)));
+        assert_eq!(SettleEvent::None, tracker.add_data(packet(0x7E21, 0)));
+
+        for index in 0..6 {
+            assert_eq!(SettleEvent::None, tracker.add_data(packet(0x7E11 + (index % 2) * 0x10, 1)));
+        }
+
+        assert!(!tracker.is_settled());
+        assert_eq!(SettleEvent::Settled, tracker.add_data(packet(0x7E11, 2)));
+        assert!(tracker.is_settled());
+        assert_eq!(SettleEvent::None, tracker.add_data(packet(0x7E21, 2)));
+    }
+
+    #[test]
+    fn test_new_packet_resettles() {
+        let mut tracker = SettleTracker::new();
+        settle(&mut tracker, &[0x7E11, 0x7E21]);
+
+        assert_eq!(SettleEvent::None, tracker.add_data(packet(0x7E31, 20)));
+        assert!(!tracker.is_settled());
+    }
+
+    #[test]
+    fn test_stale_packet_is_added_back_without_resettling() {
+        let mut tracker = SettleTracker::new();
+        settle(&mut tracker, &[0x7E11, 0x7E21]);
+
+        tracker.add_data(packet(0x7E11, 30));
+        tracker.data_set_mut().remove_data_older_than(UTC.timestamp(20, 0));
+        assert_eq!(1, tracker.data_set().len());
+
+        assert_eq!(SettleEvent::None, tracker.add_data(packet(0x7E21, 40)));
+        assert!(tracker.is_settled());
+        assert_eq!(2, tracker.data_set().len());
+    }
+
+    #[test]
+    fn test_restored_packets_that_are_gone_are_removed() {
+        let mut tracker = SettleTracker::new();
+        for &source_address in &[0x7E11, 0x7E21] {
+            let data = packet(source_address, 0);
+            tracker.unconfirmed_packet_ids.insert(data.id_string());
+            tracker.data_set.add_data(data);
+        }
+        tracker.is_settled = true;
+        tracker.settled_packet_ids = tracker.packet_ids();
+        tracker.settled_max_count = 6;
+
+        for timestamp in 1..6 {
+            assert_eq!(SettleEvent::None, tracker.add_data(packet(0x7E11, timestamp)));
+        }
+
+        assert_eq!(SettleEvent::DataSetChanged, tracker.add_data(packet(0x7E11, 6)));
+        assert!(tracker.is_settled());
+        assert_eq!(1, tracker.data_set().len());
+
+        assert_eq!(SettleEvent::None, tracker.add_data(packet(0x7E11, 7)));
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::fmt;
 
 use resol_vbus::{
     chrono::prelude::*,
@@ -13,8 +14,10 @@ use sqlite::{
 };
 
 use crate::{
-    config::Config,
+    config::{Config, SqliteConfig},
     error::{Error, Result},
+    output::Output,
+    virtual_fields::VirtualFields,
 };
 
 
@@ -29,6 +32,7 @@ enum Mode {
     },
 }
 
+/// Stores the VBus values in a SQLite database on every tick.
 pub struct SqliteLogger {
     spec: Specification,
     connection: Connection,
@@ -36,15 +40,24 @@ pub struct SqliteLogger {
 }
 
 
+impl fmt::Debug for SqliteLogger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SqliteLogger")
+            .finish()
+    }
+}
+
+
 impl SqliteLogger {
-    pub fn from_config(config: &Config) -> Result<SqliteLogger> {
+    /// Create a logger from the settings of a `[[sqlite]]` table.
+    pub fn from_config(config: &Config, sqlite_config: &SqliteConfig) -> Result<SqliteLogger> {
         let spec_file = config.load_spec_file()?;
 
         let spec = Specification::from_file(spec_file, Language::En);
 
-        let connection = sqlite::open(&config.sqlite_filename)?;
+        let connection = sqlite::open(&sqlite_config.filename)?;
 
-        let mode = match (&config.sqlite_datasets_table, &config.sqlite_fields_table, &config.sqlite_statement, &config.sqlite_fields) {
+        let mode = match (&sqlite_config.datasets_table, &sqlite_config.fields_table, &sqlite_config.statement, &sqlite_config.fields) {
             (Some(datasets_table), Some(fields_table), None, None) => {
                 let stmt = format!("CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY, timestamp TEXT); CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY, dataset_id INTEGER, packet_field_id TEXT, value REAL)", datasets_table, fields_table);
                 connection.execute(stmt)?;
@@ -58,7 +71,7 @@ impl SqliteLogger {
                 statement: statement.clone(),
                 fields: fields.clone(),
             },
-            _ => return Err(Error::from("Unsupported combination of SQLlite logger configuration")),
+            _ => return Err(Error::from("Unsupported combination of SQLite logger configuration, set either `datasets_table` and `fields_table` or `statement` and `fields`")),
         };
 
         Ok(SqliteLogger{
@@ -68,7 +81,8 @@ impl SqliteLogger {
         })
     }
 
-    pub fn log(&mut self, data_set: &DataSet, now: &DateTime<UTC>) -> Result<()> {
+    /// Store the values of the `data_set` and the `virtual_fields`.
+    pub fn log(&mut self, data_set: &DataSet, virtual_fields: &VirtualFields, now: &DateTime<UTC>) -> Result<()> {
         let local_now = now.with_timezone(&Local);
 
         let mut field_map = HashMap::new();
@@ -109,6 +123,19 @@ impl SqliteLogger {
                         }
                     }
                 }
+
+                for field in virtual_fields.iter() {
+                    if let Some(value) = field.value {
+                        let mut stmt = self.connection.prepare(&stmt_string)?;
+                        stmt.bind(1, dataset_id)?;
+                        stmt.bind(2, field.id.as_str())?;
+                        stmt.bind(3, value)?;
+
+                        while stmt.next()? != State::Done {
+                            // repeat
+                        }
+                    }
+                }
             }
             Mode::Tabular { statement, fields } => {
                 for field in self.spec.fields_in_data_set(data_set) {
@@ -117,6 +144,10 @@ impl SqliteLogger {
                     field_map.insert(key, value);
                 }
 
+                for field in virtual_fields.iter() {
+                    field_map.insert(field.id.clone(), field.value);
+                }
+
                 let mut stmt = self.connection.prepare(&statement)?;
 
                 for (idx, field) in fields.iter().enumerate() {
@@ -140,3 +171,10 @@ impl SqliteLogger {
         Ok(())
     }
 }
+
+
+impl Output for SqliteLogger {
+    fn on_tick(&mut self, data_set: &DataSet, virtual_fields: &VirtualFields, now: &DateTime<UTC>) -> Result<()> {
+        self.log(data_set, virtual_fields, now)
+    }
+}
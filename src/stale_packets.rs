@@ -0,0 +1,95 @@
+use std::collections::HashSet;
+
+use resol_vbus::{
+    chrono::prelude::*,
+    chrono::Duration,
+    Data,
+    DataSet,
+};
+
+
+use config::{Config, StalePacketMode};
+
+
+/// Detects packets that have not been received for longer than the
+/// configured `max_packet_age`, e.g. because a module dropped off the bus.
+///
+/// Depending on the `stale_packet_mode` stale packets are either removed from
+/// the data set or their values are cleared, so that outputs emit empty values
+/// instead of repeating the last ones indefinitely.
+#[derive(Debug)]
+pub struct StalePacketDetector {
+    max_age: Option<Duration>,
+    mode: StalePacketMode,
+    stale_packet_ids: HashSet<String>,
+}
+
+
+impl StalePacketDetector {
+    /// Create a detector from the `max_packet_age` and `stale_packet_mode` settings.
+    pub fn from_config(config: &Config) -> StalePacketDetector {
+        StalePacketDetector {
+            max_age: config.max_packet_age.map(Duration::seconds),
+            mode: config.stale_packet_mode.unwrap_or(StalePacketMode::Null),
+            stale_packet_ids: HashSet::new(),
+        }
+    }
+
+    /// The number of packets that are currently stale.
+    pub fn stale_packet_count(&self) -> usize {
+        self.stale_packet_ids.len()
+    }
+
+    /// Check the packets in the `data_set` and handle the stale ones.
+    ///
+    /// Returns `true` if the set of stale packets changed.
+    pub fn process(&mut self, data_set: &mut DataSet, now: DateTime<UTC>) -> bool {
+        let max_age = match self.max_age {
+            Some(max_age) => max_age,
+            None => return false,
+        };
+
+        let min_timestamp = now - max_age;
+
+        let mut present_packet_ids = HashSet::new();
+        let mut current_stale_packet_ids = HashSet::new();
+
+        for data in data_set.iter() {
+            let packet = match *data {
+                Data::Packet(ref packet) => packet,
+                _ => continue,
+            };
+
+            let id = data.id_string();
+
+            if packet.header.timestamp < min_timestamp {
+                if !self.stale_packet_ids.contains(&id) {
+                    warn!("Packet {} was not received for more than {} seconds", id, max_age.num_seconds());
+                }
+                current_stale_packet_ids.insert(id.clone());
+            } else if self.stale_packet_ids.contains(&id) {
+                info!("Packet {} is received again", id);
+            }
+
+            present_packet_ids.insert(id);
+        }
+
+        // Removed packets stay stale until they are received again
+        for id in self.stale_packet_ids.iter() {
+            if !present_packet_ids.contains(id) {
+                current_stale_packet_ids.insert(id.clone());
+            }
+        }
+
+        match self.mode {
+            StalePacketMode::Remove => data_set.remove_data_older_than(min_timestamp),
+            StalePacketMode::Null => data_set.clear_packets_older_than(min_timestamp),
+        }
+
+        let changed = current_stale_packet_ids != self.stale_packet_ids;
+
+        self.stale_packet_ids = current_stale_packet_ids;
+
+        changed
+    }
+}
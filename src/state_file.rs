@@ -0,0 +1,189 @@
+use std::fs;
+use std::path::Path;
+
+use resol_vbus::{
+    chrono::prelude::*,
+    Data,
+    DataSet,
+    Header,
+    Packet,
+    ToPacketId,
+};
+
+
+use config::Config;
+use error::Result;
+use output::Output;
+use virtual_fields::VirtualFields;
+
+
+#[derive(Deserialize, Serialize)]
+struct PacketState {
+    packet_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frame_data: Option<String>,
+}
+
+
+#[derive(Default, Deserialize, Serialize)]
+struct State {
+    packets: Vec<PacketState>,
+}
+
+
+/// Persists the settled set of packets, so that the logger does not have to
+/// settle again after a restart.
+///
+/// The packets are saved together with the time they were last received
+/// whenever the data set settles. If `state_save_values` is enabled, the
+/// packets including their frame data are also saved on every tick, so that
+/// outputs can start with the last known values.
+#[derive(Debug)]
+pub struct StateFile {
+    filename: String,
+    save_values: bool,
+}
+
+
+impl StateFile {
+    /// Create a state file if `state_filename` is configured. Replaying
+    /// recorded data neither restores nor saves the state.
+    pub fn from_config(config: &Config) -> Option<StateFile> {
+        if config.is_replaying() {
+            return None;
+        }
+
+        match config.state_filename {
+            Some(ref filename) => Some(StateFile {
+                filename: filename.clone(),
+                save_values: config.state_save_values.unwrap_or(false),
+            }),
+            None => None,
+        }
+    }
+
+    /// Load the packets from the state file. Returns an empty list if the file
+    /// does not exist yet.
+    pub fn load(&self) -> Result<Vec<Data>> {
+        if !Path::new(&self.filename).exists() {
+            return Ok(Vec::new());
+        }
+
+        let content = fs::read_to_string(&self.filename)?;
+        let state: State = serde_json::from_str(&content)?;
+
+        let mut result = Vec::new();
+
+        for packet_state in state.packets.iter() {
+            let packet_id = packet_state.packet_id.to_packet_id()?;
+
+            let timestamp = match packet_state.timestamp {
+                Some(ref timestamp) => match DateTime::parse_from_rfc3339(timestamp) {
+                    Ok(timestamp) => timestamp.with_timezone(&UTC),
+                    Err(err) => return Err(format!("Unable to parse timestamp {:?} in state file: {}", timestamp, err).into()),
+                },
+                // Older state files do not contain timestamps, treat these packets as stale
+                None => UTC.timestamp(0, 0),
+            };
+
+            let mut frame_data = [0; 508];
+            let mut frame_count = 0;
+
+            if let Some(ref hex) = packet_state.frame_data {
+                let bytes = parse_hex(hex)?;
+                if bytes.len() > frame_data.len() || bytes.len() % 4 != 0 {
+                    return Err(format!("Invalid frame data length {} for packet {} in state file", bytes.len(), packet_state.packet_id).into());
+                }
+                frame_data[0..bytes.len()].copy_from_slice(&bytes);
+                frame_count = (bytes.len() / 4) as u8;
+            }
+
+            result.push(Data::Packet(Packet {
+                header: Header {
+                    timestamp,
+                    channel: packet_id.0,
+                    destination_address: packet_id.1,
+                    source_address: packet_id.2,
+                    protocol_version: 0x10,
+                },
+                command: packet_id.3,
+                frame_count,
+                frame_data,
+            }));
+        }
+
+        Ok(result)
+    }
+
+    /// Save the packets of the `data_set` to the state file.
+    pub fn save(&self, data_set: &DataSet) -> Result<()> {
+        let mut state = State::default();
+
+        for data in data_set.iter() {
+            let packet = match *data {
+                Data::Packet(ref packet) => packet,
+                _ => continue,
+            };
+
+            let frame_data = if self.save_values && packet.frame_count > 0 {
+                Some(format_hex(&packet.frame_data[0..packet.frame_count as usize * 4]))
+            } else {
+                None
+            };
+
+            state.packets.push(PacketState {
+                packet_id: data.id_string(),
+                timestamp: Some(packet.header.timestamp.to_rfc3339()),
+                frame_data,
+            });
+        }
+
+        // Write to a temporary file first, so that a crash does not leave a truncated state behind
+        let tmp_filename = format!("{}.tmp", self.filename);
+        fs::write(&tmp_filename, serde_json::to_string_pretty(&state)?)?;
+        fs::rename(&tmp_filename, &self.filename)?;
+
+        debug!("Saved {} packets to state file {}", state.packets.len(), self.filename);
+
+        Ok(())
+    }
+}
+
+
+impl Output for StateFile {
+    fn on_settled(&mut self, data_set: &DataSet) -> Result<()> {
+        self.save(data_set)
+    }
+
+    fn on_tick(&mut self, data_set: &DataSet, _virtual_fields: &VirtualFields, _now: &DateTime<UTC>) -> Result<()> {
+        if self.save_values {
+            self.save(data_set)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+fn format_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(format!("Invalid frame data {:?} in state file", hex).into());
+    }
+
+    let mut bytes = Vec::with_capacity(hex.len() / 2);
+    for index in (0..hex.len()).step_by(2) {
+        match u8::from_str_radix(&hex[index..index + 2], 16) {
+            Ok(byte) => bytes.push(byte),
+            Err(_) => return Err(format!("Invalid frame data {:?} in state file", hex).into()),
+        }
+    }
+
+    Ok(bytes)
+}
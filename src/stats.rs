@@ -0,0 +1,21 @@
+use resol_vbus::chrono::prelude::*;
+
+
+/// Statistics about the received data and the health of the outputs.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Number of packets received.
+    pub packets_received: u64,
+    /// Time the last packet was received.
+    pub last_receive_time: Option<DateTime<UTC>>,
+    /// Whether the data set has settled.
+    pub data_set_is_settled: bool,
+    /// Number of successful reconnections after the connection was lost.
+    pub reconnect_count: u64,
+    /// Number of errors reported by the outputs.
+    pub generator_error_count: u64,
+    /// Number of packets that exceeded the `max_packet_age`.
+    pub stale_packet_count: u64,
+    /// Number of alarms that are currently active.
+    pub active_alarm_count: u64,
+}
@@ -0,0 +1,373 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use resol_vbus::{
+    chrono::prelude::*,
+    chrono::Duration,
+    Data,
+    DataSet,
+    Language,
+    Specification,
+};
+
+use sqlite::{
+    Connection,
+    State,
+};
+
+
+use config::{Config, SummaryConfig};
+use error::Result;
+use output::Output;
+use virtual_fields::VirtualFields;
+
+
+struct Accumulator {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: i64,
+    last: f64,
+}
+
+
+impl Accumulator {
+    fn new(value: f64) -> Accumulator {
+        Accumulator {
+            min: value,
+            max: value,
+            sum: value,
+            count: 1,
+            last: value,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+        self.last = value;
+    }
+}
+
+
+/// Stores the minimum, maximum, average and last value of each field per
+/// interval in a SQLite table.
+///
+/// In contrast to the `SqliteLogger`, which only stores the values at tick
+/// time, every received packet is taken into account, so that short spikes
+/// between two ticks are not missed.
+///
+/// The intervals are aligned to multiples of the `tick_interval` and
+/// determined by the timestamps of the packets. An interval is written as
+/// soon as a packet of a later interval is received or a tick occurs after
+/// its end, the last partial interval is written on shutdown.
+pub struct SummaryLogger {
+    spec: Specification,
+    connection: Connection,
+    table: String,
+    fields: Option<HashSet<String>>,
+    interval: i64,
+    interval_start: Option<DateTime<UTC>>,
+    last_timestamp: Option<DateTime<UTC>>,
+    accumulators: BTreeMap<String, Accumulator>,
+}
+
+
+impl fmt::Debug for SummaryLogger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SummaryLogger")
+            .field("table", &self.table)
+            .field("fields", &self.fields)
+            .field("interval_start", &self.interval_start)
+            .finish()
+    }
+}
+
+
+impl SummaryLogger {
+    /// Create a logger from the settings of a `[[summary]]` table.
+    pub fn from_config(config: &Config, summary_config: &SummaryConfig) -> Result<SummaryLogger> {
+        let spec_file = config.load_spec_file()?;
+
+        let spec = Specification::from_file(spec_file, Language::En);
+
+        let table = summary_config.sqlite_table.clone().unwrap_or("summary".to_string());
+
+        let connection = sqlite::open(&summary_config.sqlite_filename)?;
+
+        let fields = summary_config.fields.as_ref().map(|fields| fields.iter().cloned().collect());
+
+        let logger = SummaryLogger {
+            spec,
+            connection,
+            table,
+            fields,
+            interval: summary_config.tick_interval,
+            interval_start: None,
+            last_timestamp: None,
+            accumulators: BTreeMap::new(),
+        };
+
+        logger.create_table()?;
+
+        Ok(logger)
+    }
+
+    fn create_table(&self) -> Result<()> {
+        let stmt = format!("CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY, start TEXT, end TEXT, packet_field_id TEXT, min REAL, max REAL, avg REAL, last REAL, count INTEGER)", self.table);
+        self.connection.execute(stmt)?;
+        Ok(())
+    }
+
+    /// Add the values of the `data` to the interval containing its timestamp.
+    pub fn accumulate(&mut self, data: &Data) -> Result<()> {
+        if !data.is_packet() {
+            return Ok(());
+        }
+
+        let mut data_set = DataSet::new();
+        data_set.add_data(data.clone());
+
+        let mut values = Vec::new();
+        for field in self.spec.fields_in_data_set(&data_set) {
+            let value = match field.raw_value_f64() {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let packet_field_id = field.packet_field_id().packet_field_id_string();
+
+            if let Some(ref fields) = self.fields {
+                if !fields.contains(&packet_field_id) {
+                    continue;
+                }
+            }
+
+            values.push((packet_field_id, value));
+        }
+
+        self.add_values(data.as_header().timestamp, values)
+    }
+
+    fn add_values(&mut self, timestamp: DateTime<UTC>, values: Vec<(String, f64)>) -> Result<()> {
+        if self.interval <= 0 {
+            return Ok(());
+        }
+
+        self.close_ended_interval(&timestamp)?;
+
+        if self.interval_start.is_none() {
+            let start = timestamp.timestamp() - timestamp.timestamp().rem_euclid(self.interval);
+            self.interval_start = Some(UTC.timestamp(start, 0));
+        }
+
+        self.last_timestamp = Some(timestamp);
+
+        for (packet_field_id, value) in values {
+            if let Some(accumulator) = self.accumulators.get_mut(&packet_field_id) {
+                accumulator.add(value);
+                continue;
+            }
+
+            self.accumulators.insert(packet_field_id, Accumulator::new(value));
+        }
+
+        Ok(())
+    }
+
+    /// Write the current interval if it does not contain `now`, e.g. because
+    /// it has ended or the replay of another file jumped back in time.
+    fn close_ended_interval(&mut self, now: &DateTime<UTC>) -> Result<()> {
+        if let Some(start) = self.interval_start {
+            let end = start + Duration::seconds(self.interval);
+            if *now >= end || *now < start {
+                self.flush(&end)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write a row for every field that received values in the current
+    /// interval, which ends at `end`.
+    fn flush(&mut self, end: &DateTime<UTC>) -> Result<()> {
+        let start = match self.interval_start.take() {
+            Some(start) => start,
+            None => return Ok(()),
+        };
+
+        self.connection.execute("BEGIN")?;
+
+        match self.insert_rows(&start, end) {
+            Ok(()) => self.connection.execute("COMMIT")?,
+            Err(err) => {
+                self.connection.execute("ROLLBACK")?;
+                return Err(err);
+            },
+        }
+
+        self.accumulators.clear();
+
+        Ok(())
+    }
+
+    fn insert_rows(&self, start: &DateTime<UTC>, end: &DateTime<UTC>) -> Result<()> {
+        let start = start.to_rfc3339();
+        let end = end.to_rfc3339();
+
+        let stmt_string = format!("INSERT INTO {} (start, end, packet_field_id, min, max, avg, last, count) VALUES (?, ?, ?, ?, ?, ?, ?, ?)", self.table);
+
+        for (packet_field_id, accumulator) in self.accumulators.iter() {
+            let mut stmt = self.connection.prepare(&stmt_string)?;
+            stmt.bind(1, start.as_str())?;
+            stmt.bind(2, end.as_str())?;
+            stmt.bind(3, packet_field_id.as_str())?;
+            stmt.bind(4, accumulator.min)?;
+            stmt.bind(5, accumulator.max)?;
+            stmt.bind(6, accumulator.sum / accumulator.count as f64)?;
+            stmt.bind(7, accumulator.last)?;
+            stmt.bind(8, accumulator.count)?;
+
+            while stmt.next()? != State::Done {
+                // repeat
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+impl Output for SummaryLogger {
+    fn on_data(&mut self, data: &Data) -> Result<()> {
+        self.accumulate(data)
+    }
+
+    fn on_tick(&mut self, _data_set: &DataSet, _virtual_fields: &VirtualFields, now: &DateTime<UTC>) -> Result<()> {
+        // Do not wait for the next packet to write the ended interval
+        self.close_ended_interval(now)
+    }
+
+    fn on_shutdown(&mut self) -> Result<()> {
+        // Do not lose the partial interval at the end of a replay
+        if let Some(last_timestamp) = self.last_timestamp {
+            self.flush(&last_timestamp)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use resol_vbus::SpecificationFile;
+
+    use super::*;
+
+    type Row = (String, String, String, f64, f64, f64, f64, i64);
+
+    fn logger(interval: i64) -> SummaryLogger {
+        let logger = SummaryLogger {
+            spec: Specification::from_file(SpecificationFile::new_default(), Language::En),
+            connection: sqlite::open(":memory:").unwrap(),
+            table: "summary".to_string(),
+            fields: None,
+            interval,
+            interval_start: None,
+            last_timestamp: None,
+            accumulators: BTreeMap::new(),
+        };
+        logger.create_table().unwrap();
+        logger
+    }
+
+    fn rows(logger: &SummaryLogger) -> Vec<Row> {
+        let mut stmt = logger.connection.prepare("SELECT start, end, packet_field_id, min, max, avg, last, count FROM summary ORDER BY id").unwrap();
+
+        let mut rows = Vec::new();
+        while stmt.next().unwrap() == State::Row {
+            rows.push((
+                stmt.read::<String>(0).unwrap(),
+                stmt.read::<String>(1).unwrap(),
+                stmt.read::<String>(2).unwrap(),
+                stmt.read::<f64>(3).unwrap(),
+                stmt.read::<f64>(4).unwrap(),
+                stmt.read::<f64>(5).unwrap(),
+                stmt.read::<f64>(6).unwrap(),
+                stmt.read::<i64>(7).unwrap(),
+            ));
+        }
+        rows
+    }
+
+    fn values(value: f64) -> Vec<(String, f64)> {
+        vec![("field".to_string(), value)]
+    }
+
+    fn at(hour: u32, min: u32, sec: u32) -> DateTime<UTC> {
+        UTC.ymd(2024, 6, 1).and_hms(hour, min, sec)
+    }
+
+    fn row(start: DateTime<UTC>, end: DateTime<UTC>, min: f64, max: f64, avg: f64, last: f64, count: i64) -> Row {
+        (start.to_rfc3339(), end.to_rfc3339(), "field".to_string(), min, max, avg, last, count)
+    }
+
+    #[test]
+    fn test_intervals_are_aligned_to_the_sample_timestamps() {
+        let mut logger = logger(60);
+
+        logger.add_values(at(12, 0, 10), values(1.0)).unwrap();
+        logger.add_values(at(12, 0, 50), values(5.0)).unwrap();
+        logger.add_values(at(12, 0, 59), values(3.0)).unwrap();
+        assert_eq!(0, rows(&logger).len());
+
+        logger.add_values(at(12, 1, 0), values(7.0)).unwrap();
+        assert_eq!(vec![
+            row(at(12, 0, 0), at(12, 1, 0), 1.0, 5.0, 3.0, 3.0, 3),
+        ], rows(&logger));
+    }
+
+    #[test]
+    fn test_intervals_are_closed_without_ticks() {
+        let mut logger = logger(60);
+
+        // No ticks occur while the data set is not settled
+        logger.add_values(at(12, 0, 10), values(1.0)).unwrap();
+        logger.add_values(at(12, 3, 20), values(2.0)).unwrap();
+        logger.add_values(at(12, 3, 40), values(4.0)).unwrap();
+        assert_eq!(vec![
+            row(at(12, 0, 0), at(12, 1, 0), 1.0, 1.0, 1.0, 1.0, 1),
+        ], rows(&logger));
+
+        // The partial interval ends at the last sample
+        logger.on_shutdown().unwrap();
+        assert_eq!(vec![
+            row(at(12, 0, 0), at(12, 1, 0), 1.0, 1.0, 1.0, 1.0, 1),
+            row(at(12, 3, 0), at(12, 3, 40), 2.0, 4.0, 3.0, 4.0, 2),
+        ], rows(&logger));
+    }
+
+    #[test]
+    fn test_tick_closes_an_ended_interval() {
+        let mut logger = logger(60);
+        let data_set = DataSet::new();
+        let virtual_fields = VirtualFields::new();
+
+        logger.add_values(at(12, 0, 10), values(1.0)).unwrap();
+
+        logger.on_tick(&data_set, &virtual_fields, &at(12, 0, 30)).unwrap();
+        assert_eq!(0, rows(&logger).len());
+
+        logger.on_tick(&data_set, &virtual_fields, &at(12, 1, 0)).unwrap();
+        assert_eq!(vec![
+            row(at(12, 0, 0), at(12, 1, 0), 1.0, 1.0, 1.0, 1.0, 1),
+        ], rows(&logger));
+
+        // Nothing left to write
+        logger.on_shutdown().unwrap();
+        assert_eq!(1, rows(&logger).len());
+    }
+}
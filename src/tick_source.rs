@@ -1,6 +1,8 @@
 use resol_vbus::chrono::prelude::*;
 
 
+/// Fires once per interval, aligned to multiples of the interval since the epoch.
+#[derive(Debug)]
 pub struct TickSource {
     interval: i64,
     last_interval: i64,
@@ -8,6 +10,7 @@ pub struct TickSource {
 
 
 impl TickSource {
+    /// Create a tick source, an `interval` of 0 or less never fires.
     pub fn new(interval: i64, now: DateTime<UTC>) -> TickSource {
         let last_interval = if interval > 0 {
             now.timestamp() / interval
@@ -21,6 +24,12 @@ impl TickSource {
         }
     }
 
+    /// Start counting the intervals from `now` again.
+    pub fn reset(&mut self, now: DateTime<UTC>) {
+        *self = TickSource::new(self.interval, now);
+    }
+
+    /// Return `true` if a new interval has started since the last call.
     pub fn process(&mut self, now: DateTime<UTC>) -> bool {
         if self.interval > 0 {
             let current_interval = now.timestamp() / self.interval;
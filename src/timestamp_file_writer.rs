@@ -1,10 +1,12 @@
-use std::fmt::Display;
+use std::fmt::{self, Debug, Display};
 use std::fs::{File, OpenOptions};
 use std::io::{Error, ErrorKind, Result, Write};
 
 use resol_vbus::chrono::{DateTime, TimeZone};
 
 
+/// A `Write` implementation that writes to a file whose name is derived from
+/// a timestamp using a strftime pattern.
 pub struct TimestampFileWriter<Tz: TimeZone> {
     filename_pattern: String,
     timestamp: DateTime<Tz>,
@@ -14,7 +16,18 @@ pub struct TimestampFileWriter<Tz: TimeZone> {
 }
 
 
+impl<Tz: TimeZone> Debug for TimestampFileWriter<Tz> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TimestampFileWriter")
+            .field("filename_pattern", &self.filename_pattern)
+            .field("current_filename", &self.current_filename)
+            .finish()
+    }
+}
+
+
 impl<Tz: TimeZone> TimestampFileWriter<Tz> where Tz::Offset: Display {
+    /// Create a writer, the file is opened on the first write.
     pub fn new(filename_pattern: String, timestamp: DateTime<Tz>) -> TimestampFileWriter<Tz> {
         TimestampFileWriter {
             filename_pattern,
@@ -25,6 +38,7 @@ impl<Tz: TimeZone> TimestampFileWriter<Tz> where Tz::Offset: Display {
         }
     }
 
+    /// Update the timestamp, returning `true` if that opened a new file.
     pub fn set_timestamp(&mut self, timestamp: DateTime<Tz>) -> Result<bool> {
         self.timestamp = timestamp;
         self.timestamp_changed = true;
@@ -32,6 +46,7 @@ impl<Tz: TimeZone> TimestampFileWriter<Tz> where Tz::Offset: Display {
         self.check_timestamp_change()
     }
 
+    /// The name of the currently open file.
     pub fn filename(&self) -> Option<&str> {
         match self.current_filename {
             None => None,
@@ -0,0 +1,503 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use resol_vbus::{
+    chrono::format::{Item, StrftimeItems},
+    Language,
+    Specification,
+    ToPacketFieldId,
+    ToPacketId,
+};
+
+
+use config::{AlarmCondition, Config, NotifierKind};
+use derived_fields::DerivedFields;
+use expression::Expression;
+use virtual_fields::configured_virtual_field_ids;
+
+
+/// Prefixes of the top-level keys older versions used to configure the single
+/// instance of a generator, and the table they have moved into.
+const LEGACY_KEY_PREFIXES: &[(&str, &str)] = &[
+    ("png_", "png"),
+    ("csv_", "csv"),
+    ("live_data_text_", "live_data_text"),
+    ("sqlite_", "sqlite"),
+];
+
+
+/// Keywords that can be used instead of a packet field ID in the `fields` of
+/// a `[[sqlite]]` table in tabular mode.
+const SQLITE_KEYWORDS: &[&str] = &["UtcDateTime", "LocalDateTime"];
+
+
+struct Validator {
+    spec: Option<Specification>,
+    virtual_field_ids: HashSet<String>,
+    problems: Vec<String>,
+}
+
+
+impl Validator {
+    fn problem(&mut self, key: String, message: String) {
+        self.problems.push(format!("{}: {}", key, message));
+    }
+
+    fn check_packet_id(&mut self, key: String, packet_id_string: &str) {
+        let packet_id = match packet_id_string.to_packet_id() {
+            Ok(packet_id) => packet_id,
+            Err(_) => {
+                self.problem(key, format!("{:?} is not a valid packet ID, expected a format like \"00_0010_7E11_10_0100\"", packet_id_string));
+                return;
+            },
+        };
+
+        if let Some(ref spec) = self.spec {
+            if spec.get_packet_spec_by_id(packet_id).fields.is_empty() {
+                self.problem(key, format!("packet {:?} is not described in the VSF", packet_id_string));
+            }
+        }
+    }
+
+    fn check_packet_field_id(&mut self, key: String, packet_field_id: &str) {
+        if self.virtual_field_ids.contains(packet_field_id) {
+            return;
+        }
+
+        let id = match packet_field_id.to_packet_field_id() {
+            Ok(id) => id,
+            Err(_) => {
+                self.problem(key, format!("{:?} is not a valid packet field ID, expected a format like \"00_0010_7E11_10_0100_000_2_0\"", packet_field_id));
+                return;
+            },
+        };
+
+        if let Some(ref spec) = self.spec {
+            let packet_spec = spec.get_packet_spec_by_id(id.packet_id);
+            if packet_spec.get_field_spec(id.field_id).is_none() {
+                self.problem(key, format!("field {:?} is not described in the VSF", packet_field_id));
+            }
+        }
+    }
+
+    fn check_name(&mut self, key: String, name: &str, names: &mut HashSet<String>) {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            self.problem(key, format!("{:?} must only contain letters, digits and underscores", name));
+        } else if !names.insert(name.to_string()) {
+            self.problem(key, format!("{:?} is used by more than one table of this kind", name));
+        }
+    }
+
+    fn check_strftime(&mut self, key: String, pattern: &str) {
+        let is_valid = StrftimeItems::new(pattern).all(|item| match item {
+            Item::Error => false,
+            _ => true,
+        });
+
+        if !is_valid {
+            self.problem(key, format!("{:?} is not a valid strftime pattern, see https://docs.rs/chrono/0.3.1/chrono/format/strftime/index.html", pattern));
+        }
+    }
+
+    fn check_file_exists(&mut self, key: String, filename: &str) {
+        if !Path::new(filename).is_file() {
+            self.problem(key, format!("file {:?} does not exist", filename));
+        }
+    }
+}
+
+
+/// Count the `?` placeholders in the SQL `statement`, ignoring those inside
+/// string literals and quoted identifiers.
+fn count_placeholders(statement: &str) -> usize {
+    let mut count = 0;
+    let mut quote = None;
+
+    for c in statement.chars() {
+        match quote {
+            Some(q) => if c == q {
+                quote = None;
+            },
+            None => match c {
+                '\'' | '"' | '`' => quote = Some(c),
+                '?' => count += 1,
+                _ => {},
+            },
+        }
+    }
+
+    count
+}
+
+
+/// Check the `config` and return a description of every problem found,
+/// each prefixed with the key path of the offending setting.
+pub fn validate_config(config: &Config) -> Vec<String> {
+    let mut v = Validator {
+        spec: None,
+        virtual_field_ids: configured_virtual_field_ids(config),
+        problems: Vec::new(),
+    };
+
+    match config.load_spec_file() {
+        Ok(spec_file) => v.spec = Some(Specification::from_file(spec_file, Language::En)),
+        Err(err) => v.problem("vsf_filename".to_string(), format!("unable to load VSF file: {}", err)),
+    }
+
+    match (&config.path, &config.address, &config.replay_filenames) {
+        (Some(_), Some(_), _) => v.problem("path".to_string(), "only one of `path` and `address` may be set".to_string()),
+        (None, None, None) => v.problem("path".to_string(), "no connection method configured, set one of `path`, `address` or `replay_filenames`".to_string()),
+        _ => {},
+    }
+
+    if let Some(ref replay_filenames) = config.replay_filenames {
+        for (index, filename) in replay_filenames.iter().enumerate() {
+            v.check_file_exists(format!("replay_filenames[{}]", index), filename);
+        }
+    }
+
+    if config.vbus_server_address.is_some() && config.path.is_none() && config.address.is_none() {
+        v.problem("vbus_server_address".to_string(), "sharing the VBus requires a `path` or `address` to connect to".to_string());
+    }
+
+    if config.clock_sync_value.is_some() {
+        if config.path.is_none() && config.address.is_none() {
+            v.problem("clock_sync_value".to_string(), "synchronizing the clock requires a `path` or `address` to connect to".to_string());
+        }
+        if config.clock_sync_interval.unwrap_or(3600) <= 0 {
+            v.problem("clock_sync_interval".to_string(), "must be greater than 0".to_string());
+        }
+        if config.clock_sync_max_drift.unwrap_or(30) < 0 {
+            v.problem("clock_sync_max_drift".to_string(), "must not be negative".to_string());
+        }
+    }
+
+    for key in config.unknown_keys.keys() {
+        match LEGACY_KEY_PREFIXES.iter().find(|&&(prefix, _)| key.starts_with(prefix)) {
+            Some(&(prefix, table)) => v.problem(key.clone(), format!("has moved into the `[[{}]]` table as `{}`, see config.toml.example", table, &key[prefix.len()..])),
+            None => v.problem(key.clone(), "is not a known setting".to_string()),
+        }
+    }
+
+    for (index, packet_id) in config.known_packet_ids.iter().enumerate() {
+        v.check_packet_id(format!("known_packet_ids[{}]", index), packet_id);
+    }
+
+    for (index, png_config) in config.png.iter().enumerate() {
+        if png_config.tick_interval > 0 {
+            v.check_file_exists(format!("png[{}].input_filename", index), &png_config.input_filename);
+        }
+
+        for (label_index, label) in png_config.labels.iter().enumerate() {
+            let key = format!("png[{}].labels[{}]", index, label_index);
+            if let Some(ref field) = label.field {
+                v.check_packet_field_id(format!("{}.field", key), field);
+            }
+            if let Some(ref timestamp) = label.timestamp {
+                v.check_strftime(format!("{}.timestamp", key), timestamp);
+            }
+            if label.field.is_none() && label.timestamp.is_none() && label.text.is_none() {
+                v.problem(key, "one of `field`, `timestamp` or `text` must be set".to_string());
+            }
+        }
+    }
+
+    for (index, csv_config) in config.csv.iter().enumerate() {
+        v.check_strftime(format!("csv[{}].output_filename_pattern", index), &csv_config.output_filename_pattern);
+
+        if let Some(ref fields) = csv_config.fields {
+            for (field_index, field) in fields.iter().enumerate() {
+                v.check_packet_field_id(format!("csv[{}].fields[{}]", index, field_index), field);
+            }
+        }
+    }
+
+    for (index, sqlite_config) in config.sqlite.iter().enumerate() {
+        let key = format!("sqlite[{}]", index);
+        match (&sqlite_config.datasets_table, &sqlite_config.fields_table, &sqlite_config.statement, &sqlite_config.fields) {
+            (Some(_), Some(_), None, None) => {},
+            (None, None, Some(statement), Some(fields)) => {
+                let placeholder_count = count_placeholders(statement);
+                if placeholder_count != fields.len() {
+                    v.problem(format!("{}.statement", key), format!("contains {} placeholders, but `fields` has {} entries", placeholder_count, fields.len()));
+                }
+
+                for (field_index, field) in fields.iter().enumerate() {
+                    if !SQLITE_KEYWORDS.contains(&field.as_str()) {
+                        v.check_packet_field_id(format!("{}.fields[{}]", key, field_index), field);
+                    }
+                }
+            },
+            _ => v.problem(key, "set either `datasets_table` and `fields_table` (relational mode) or `statement` and `fields` (tabular mode)".to_string()),
+        }
+    }
+
+    for (index, summary_config) in config.summary.iter().enumerate() {
+        if let Some(ref fields) = summary_config.fields {
+            for (field_index, field) in fields.iter().enumerate() {
+                v.check_packet_field_id(format!("summary[{}].fields[{}]", index, field_index), field);
+            }
+        }
+    }
+
+    for (index, mqtt_config) in config.mqtt.iter().enumerate() {
+        if let Some(qos) = mqtt_config.qos {
+            if qos > 2 {
+                v.problem(format!("mqtt[{}].qos", index), format!("{} is not a valid QoS level, expected 0, 1 or 2", qos));
+            }
+        }
+        if let Some(ref tls_ca_filename) = mqtt_config.tls_ca_filename {
+            v.check_file_exists(format!("mqtt[{}].tls_ca_filename", index), tls_ca_filename);
+        }
+    }
+
+    for (index, influxdb_config) in config.influxdb.iter().enumerate() {
+        if let Some(ref pattern) = influxdb_config.output_filename_pattern {
+            v.check_strftime(format!("influxdb[{}].output_filename_pattern", index), pattern);
+        }
+        if influxdb_config.tick_interval > 0 && influxdb_config.url.is_none() && influxdb_config.output_filename_pattern.is_none() {
+            v.problem(format!("influxdb[{}]", index), "one of `url` or `output_filename_pattern` must be set".to_string());
+        }
+    }
+
+    for (index, recording_config) in config.recording.iter().enumerate() {
+        v.check_strftime(format!("recording[{}].output_filename_pattern", index), &recording_config.output_filename_pattern);
+    }
+
+    for (index, datagrams_config) in config.datagrams.iter().enumerate() {
+        match datagrams_config.output_filename_pattern {
+            Some(ref pattern) => v.check_strftime(format!("datagrams[{}].output_filename_pattern", index), pattern),
+            None => if datagrams_config.sqlite_filename.is_none() {
+                v.problem(format!("datagrams[{}]", index), "one of `output_filename_pattern` or `sqlite_filename` must be set".to_string());
+            },
+        }
+    }
+
+    let mut energy_names = HashSet::new();
+    for (index, energy_config) in config.energy.iter().enumerate() {
+        v.check_name(format!("energy[{}].name", index), &energy_config.name, &mut energy_names);
+        v.check_packet_field_id(format!("energy[{}].field", index), &energy_config.field);
+    }
+
+    let mut relay_names = HashSet::new();
+    for (index, relay_config) in config.relay.iter().enumerate() {
+        v.check_name(format!("relay[{}].name", index), &relay_config.name, &mut relay_names);
+        v.check_packet_field_id(format!("relay[{}].field", index), &relay_config.field);
+    }
+
+    let derived_field_ids = config.derived.iter().map(DerivedFields::field_id).collect::<Vec<_>>();
+    let mut derived_names = HashSet::new();
+    for (index, derived_config) in config.derived.iter().enumerate() {
+        let key = format!("derived[{}]", index);
+        v.check_name(format!("{}.name", key), &derived_config.name, &mut derived_names);
+        match Expression::parse(&derived_config.expression) {
+            Ok(expression) => for field_id in expression.field_ids() {
+                // Derived fields are evaluated in order, so only the ones above are known
+                match derived_field_ids.iter().position(|id| *id == field_id) {
+                    Some(position) if position == index => v.problem(format!("{}.expression", key), format!("{:?} references the field itself", field_id)),
+                    Some(position) if position > index => v.problem(format!("{}.expression", key), format!("{:?} is defined below, only derived fields defined above can be referenced", field_id)),
+                    _ => v.check_packet_field_id(format!("{}.expression", key), &field_id),
+                }
+            },
+            Err(err) => v.problem(format!("{}.expression", key), format!("{}", err)),
+        }
+    }
+
+    let mut notifier_names = HashSet::new();
+    for (index, notifier_config) in config.notifier.iter().enumerate() {
+        let key = format!("notifier[{}]", index);
+        v.check_name(format!("{}.name", key), &notifier_config.name, &mut notifier_names);
+        match notifier_config.kind {
+            NotifierKind::Command => if notifier_config.command.is_none() {
+                v.problem(key, "`command` must be set for the command kind".to_string());
+            },
+            NotifierKind::Webhook => if notifier_config.url.is_none() {
+                v.problem(key, "`url` must be set for the webhook kind".to_string());
+            },
+            NotifierKind::Smtp => if notifier_config.smtp_host.is_none() || notifier_config.from.is_none() || notifier_config.to.is_none() {
+                v.problem(key, "`smtp_host`, `from` and `to` must be set for the smtp kind".to_string());
+            },
+        }
+    }
+
+    let mut alarm_names = HashSet::new();
+    for (index, alarm_config) in config.alarm.iter().enumerate() {
+        let key = format!("alarm[{}]", index);
+        v.check_name(format!("{}.name", key), &alarm_config.name, &mut alarm_names);
+
+        let needs_threshold = match alarm_config.condition {
+            AlarmCondition::Above | AlarmCondition::Below | AlarmCondition::Rate => true,
+            _ => false,
+        };
+        if needs_threshold && alarm_config.threshold.is_none() {
+            v.problem(key.clone(), "`threshold` must be set for this condition".to_string());
+        }
+
+        match alarm_config.condition {
+            AlarmCondition::PacketMissing => match alarm_config.packet {
+                Some(ref packet) => v.check_packet_id(format!("{}.packet", key), packet),
+                None => v.problem(key.clone(), "`packet` must be set for this condition".to_string()),
+            },
+            AlarmCondition::ConnectionLost => {},
+            _ => match alarm_config.field {
+                Some(ref field) => v.check_packet_field_id(format!("{}.field", key), field),
+                None => v.problem(key.clone(), "`field` must be set for this condition".to_string()),
+            },
+        }
+
+        if let Some(ref notifiers) = alarm_config.notifiers {
+            for (notifier_index, notifier) in notifiers.iter().enumerate() {
+                if !notifier_names.contains(notifier) {
+                    v.problem(format!("{}.notifiers[{}]", key, notifier_index), format!("no `[[notifier]]` named {:?}", notifier));
+                }
+            }
+        }
+    }
+
+    v.problems
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(settings: &str) -> Vec<String> {
+        let config = ::toml::from_str::<Config>(&format!("known_packet_ids = []\naddress = \"127.0.0.1:7053\"\n{}", settings)).unwrap();
+        validate_config(&config)
+    }
+
+    #[test]
+    fn test_minimal_config_is_valid() {
+        assert_eq!(Vec::<String>::new(), validate(""));
+    }
+
+    #[test]
+    fn test_connection_method_is_required() {
+        let config = ::toml::from_str::<Config>("known_packet_ids = []").unwrap();
+
+        assert_eq!(vec![
+            "path: no connection method configured, set one of `path`, `address` or `replay_filenames`",
+        ], validate_config(&config));
+    }
+
+    #[test]
+    fn test_legacy_and_unknown_keys_are_rejected() {
+        let problems = validate("csv_tick_interval = 60\nfoo = 1\n");
+
+        assert_eq!(vec![
+            "csv_tick_interval: has moved into the `[[csv]]` table as `tick_interval`, see config.toml.example",
+            "foo: is not a known setting",
+        ], problems);
+    }
+
+    #[test]
+    fn test_packet_ids_are_checked() {
+        let config = ::toml::from_str::<Config>(concat!(
+            "known_packet_ids = [\"00_0010_7210_10_0100\", \"00_0010_7210_10\", \"00_0010_FFFF_10_0100\"]\n",
+            "address = \"127.0.0.1:7053\"\n",
+        )).unwrap();
+
+        let problems = validate_config(&config);
+
+        assert_eq!(vec![
+            "known_packet_ids[1]: \"00_0010_7210_10\" is not a valid packet ID, expected a format like \"00_0010_7E11_10_0100\"",
+            "known_packet_ids[2]: packet \"00_0010_FFFF_10_0100\" is not described in the VSF",
+        ], problems);
+    }
+
+    #[test]
+    fn test_field_ids_are_checked() {
+        let problems = validate(concat!(
+            "[[csv]]\n",
+            "tick_interval = 60\n",
+            "output_filename_pattern = \"%Y%m%d.csv\"\n",
+            "fields = [\"00_0010_7210_10_0100_000_2_0\", \"00_0010_7210_10_0100_999_2_0\", \"temperature\"]\n",
+        ));
+
+        assert_eq!(vec![
+            "csv[0].fields[1]: field \"00_0010_7210_10_0100_999_2_0\" is not described in the VSF",
+            "csv[0].fields[2]: \"temperature\" is not a valid packet field ID, expected a format like \"00_0010_7E11_10_0100_000_2_0\"",
+        ], problems);
+    }
+
+    #[test]
+    fn test_sqlite_placeholders_in_literals_are_ignored() {
+        let problems = validate(concat!(
+            "[[sqlite]]\n",
+            "tick_interval = 60\n",
+            "filename = \"test.db\"\n",
+            "statement = \"INSERT INTO \\\"what?\\\" VALUES ('?', ?, ?)\"\n",
+            "fields = [\"UtcDateTime\", \"00_0010_7210_10_0100_000_2_0\"]\n",
+        ));
+
+        assert_eq!(Vec::<String>::new(), problems);
+    }
+
+    #[test]
+    fn test_sqlite_placeholder_count_must_match_fields() {
+        let problems = validate(concat!(
+            "[[sqlite]]\n",
+            "tick_interval = 60\n",
+            "filename = \"test.db\"\n",
+            "statement = \"INSERT INTO data VALUES (?, ?)\"\n",
+            "fields = [\"UtcDateTime\"]\n",
+        ));
+
+        assert_eq!(vec![
+            "sqlite[0].statement: contains 2 placeholders, but `fields` has 1 entries",
+        ], problems);
+    }
+
+    #[test]
+    fn test_names_are_checked() {
+        let problems = validate(concat!(
+            "[[relay]]\n",
+            "name = \"pump\"\n",
+            "field = \"00_0010_7210_10_0100_020_1_0\"\n",
+            "sqlite_filename = \"test.db\"\n",
+            "[[relay]]\n",
+            "name = \"pump\"\n",
+            "field = \"00_0010_7210_10_0100_021_1_0\"\n",
+            "sqlite_filename = \"test.db\"\n",
+            "[[relay]]\n",
+            "name = \"pump 3\"\n",
+            "field = \"00_0010_7210_10_0100_023_1_0\"\n",
+            "sqlite_filename = \"test.db\"\n",
+        ));
+
+        assert_eq!(vec![
+            "relay[1].name: \"pump\" is used by more than one table of this kind",
+            "relay[2].name: \"pump 3\" must only contain letters, digits and underscores",
+        ], problems);
+    }
+
+    #[test]
+    fn test_derived_references_are_checked() {
+        let problems = validate(concat!(
+            "[[derived]]\n",
+            "name = \"a\"\n",
+            "expression = \"00_0010_7210_10_0100_000_2_0 + derived_b\"\n",
+            "[[derived]]\n",
+            "name = \"b\"\n",
+            "expression = \"derived_a + derived_b\"\n",
+            "[[derived]]\n",
+            "name = \"c\"\n",
+            "expression = \"derived_b * (\"\n",
+        ));
+
+        assert_eq!(vec![
+            "derived[0].expression: \"derived_b\" is defined below, only derived fields defined above can be referenced",
+            "derived[1].expression: \"derived_b\" references the field itself",
+            "derived[2].expression: Unexpected end of expression",
+        ], problems);
+    }
+
+    #[test]
+    fn test_count_placeholders() {
+        assert_eq!(0, count_placeholders("SELECT 1"));
+        assert_eq!(2, count_placeholders("INSERT INTO t VALUES (?, ?)"));
+        assert_eq!(1, count_placeholders("INSERT INTO t VALUES ('it''s ?', ?)"));
+        assert_eq!(1, count_placeholders("INSERT INTO \"a?b\" (`c?`) VALUES (?)"));
+    }
+}
@@ -0,0 +1,402 @@
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Result as IoResult, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use resol_vbus::ReadWithTimeout;
+
+
+use config::Config;
+use connection::VBusReader;
+use error::Result;
+
+
+/// Maximum length of a command line sent by a client during the handshake.
+const MAX_LINE_LENGTH: u64 = 256;
+
+
+/// Number of seconds a client has to complete the handshake.
+const HANDSHAKE_TIMEOUT: u64 = 30;
+
+
+/// Number of chunks of VBus data queued for a client before it is considered
+/// stalled and dropped.
+const CLIENT_QUEUE_LENGTH: usize = 64;
+
+
+/// Number of seconds a client may block a single write.
+const CLIENT_WRITE_TIMEOUT: u64 = 10;
+
+
+/// Number of seconds after the last write of a client during which the
+/// logger does not access the bus itself.
+const CLIENT_ACTIVITY_TIMEOUT: u64 = 10;
+
+
+#[derive(Default)]
+struct BusState {
+    is_locked: bool,
+    last_client_write: Option<Instant>,
+}
+
+
+struct Client {
+    peer_addr: SocketAddr,
+    sender: SyncSender<Vec<u8>>,
+}
+
+
+struct Shared {
+    password: String,
+    channel: u8,
+    clients: Mutex<Vec<Client>>,
+    writer: Mutex<Option<Box<dyn Write + Send>>>,
+    bus_state: Mutex<BusState>,
+}
+
+
+impl Shared {
+    fn broadcast(&self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+
+        // Only queue the bytes, so that a slow client cannot stall the logger
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|client| {
+            match client.sender.try_send(bytes.to_vec()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    info!("Dropping VBus-over-TCP client {}, it does not keep up with the data", client.peer_addr);
+                    false
+                },
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    fn remove_client(&self, peer_addr: SocketAddr) {
+        self.clients.lock().unwrap().retain(|client| client.peer_addr != peer_addr);
+    }
+
+    /// Forward the `bytes` written by a client to the bus, unless the logger
+    /// itself is accessing the bus. Returns `false` if the bytes were dropped.
+    fn write_from_client(&self, bytes: &[u8]) -> IoResult<bool> {
+        let mut bus_state = self.bus_state.lock().unwrap();
+        if bus_state.is_locked {
+            return Ok(false);
+        }
+
+        bus_state.last_client_write = Some(Instant::now());
+
+        self.write_to_bus(bytes)?;
+
+        Ok(true)
+    }
+
+    fn write_to_bus(&self, bytes: &[u8]) -> IoResult<()> {
+        match *self.writer.lock().unwrap() {
+            Some(ref mut writer) => {
+                writer.write_all(bytes)?;
+                writer.flush()
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+
+/// Re-shares the VBus connection of the logger with VBus-over-TCP clients,
+/// e.g. RESOL ServiceCenter.
+///
+/// The server speaks the server side of the handshake that the `TcpConnector`
+/// uses (`CONNECT`, `PASS`, `CHANNEL`, `DATA` and `QUIT`). After the `DATA`
+/// command all bytes received from the VBus are forwarded to the client and
+/// all bytes received from the client are forwarded to the VBus. Clients that
+/// do not keep up with the data are dropped. Only the configured `channel` of
+/// the logger's connection is shared.
+///
+/// While the logger itself accesses the bus using a `BusLock`, e.g. to read
+/// parameters or synchronize the clock, the bytes written by clients are
+/// dropped.
+#[derive(Clone)]
+pub struct VBusServer {
+    shared: Arc<Shared>,
+}
+
+
+impl fmt::Debug for VBusServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VBusServer")
+            .field("client_count", &self.client_count())
+            .finish()
+    }
+}
+
+
+impl VBusServer {
+    /// Start listening on the `vbus_server_address`, if it is configured and
+    /// no recorded data is replayed.
+    pub fn from_config(config: &Config) -> Result<Option<VBusServer>> {
+        let address = match config.vbus_server_address {
+            Some(ref address) if !config.is_replaying() => address,
+            _ => return Ok(None),
+        };
+
+        let listener = TcpListener::bind(address)?;
+
+        let server = VBusServer {
+            shared: Arc::new(Shared {
+                password: config.vbus_server_password.clone().unwrap_or("vbus".to_string()),
+                channel: config.channel.unwrap_or(0),
+                clients: Mutex::new(Vec::new()),
+                writer: Mutex::new(None),
+                bus_state: Mutex::new(BusState::default()),
+            }),
+        };
+
+        let shared = server.shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("Unable to accept VBus-over-TCP client: {}", err);
+                        continue;
+                    },
+                };
+
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    if let Err(err) = handle_client(&shared, stream) {
+                        debug!("VBus-over-TCP client failed: {}", err);
+                    }
+                });
+            }
+        });
+
+        info!("Serving VBus-over-TCP on {}", address);
+
+        Ok(Some(server))
+    }
+
+    /// The number of clients currently receiving data.
+    pub fn client_count(&self) -> usize {
+        self.shared.clients.lock().unwrap().len()
+    }
+
+    /// Reserve the bus for the logger until the returned `BusLock` is dropped.
+    ///
+    /// Fails if a client has recently written to the bus, to not interfere
+    /// with e.g. a parameterization in progress.
+    pub fn lock_bus(&self) -> Result<BusLock> {
+        let mut bus_state = self.shared.bus_state.lock().unwrap();
+
+        if let Some(last_client_write) = bus_state.last_client_write {
+            if last_client_write.elapsed() < Duration::from_secs(CLIENT_ACTIVITY_TIMEOUT) {
+                return Err("The bus is in use by a VBus-over-TCP client".into());
+            }
+        }
+
+        bus_state.is_locked = true;
+
+        Ok(BusLock {
+            shared: self.shared.clone(),
+        })
+    }
+
+    /// Share the connection consisting of the `reader` and `writer` with the
+    /// clients, returning the halves to use instead.
+    pub fn attach(&self, reader: Box<dyn VBusReader>, writer: Box<dyn Write + Send>) -> (Box<dyn VBusReader>, Box<dyn Write + Send>) {
+        *self.shared.writer.lock().unwrap() = Some(writer);
+
+        let reader = TeeReader {
+            inner: reader,
+            shared: self.shared.clone(),
+        };
+
+        let writer = SharedWriter {
+            shared: self.shared.clone(),
+        };
+
+        (Box::new(reader), Box::new(writer))
+    }
+}
+
+
+/// Reserves the bus for the logger, see `VBusServer::lock_bus`.
+pub struct BusLock {
+    shared: Arc<Shared>,
+}
+
+
+impl fmt::Debug for BusLock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BusLock").finish()
+    }
+}
+
+
+impl Drop for BusLock {
+    fn drop(&mut self) {
+        self.shared.bus_state.lock().unwrap().is_locked = false;
+    }
+}
+
+
+struct TeeReader {
+    inner: Box<dyn VBusReader>,
+    shared: Arc<Shared>,
+}
+
+
+impl Read for TeeReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let len = self.inner.read(buf)?;
+        self.shared.broadcast(&buf[0..len]);
+        Ok(len)
+    }
+}
+
+
+impl ReadWithTimeout for TeeReader {
+    fn read_with_timeout(&mut self, buf: &mut [u8], timeout: Option<Duration>) -> IoResult<usize> {
+        let len = self.inner.read_with_timeout(buf, timeout)?;
+        self.shared.broadcast(&buf[0..len]);
+        Ok(len)
+    }
+}
+
+
+struct SharedWriter {
+    shared: Arc<Shared>,
+}
+
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.shared.write_to_bus(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+
+fn handle_client(shared: &Shared, stream: TcpStream) -> IoResult<()> {
+    let peer_addr = stream.peer_addr()?;
+
+    debug!("VBus-over-TCP client {} connected", peer_addr);
+
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    writer.write_all(b"+HELLO\n")?;
+
+    // Do not let clients that never complete the handshake occupy a thread
+    reader.get_ref().set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT)))?;
+
+    let mut is_authorized = false;
+
+    loop {
+        let mut line = String::new();
+        let len = reader.by_ref().take(MAX_LINE_LENGTH).read_line(&mut line)?;
+        if len == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && len as u64 >= MAX_LINE_LENGTH {
+            writer.write_all(b"-ERROR: Line too long\n")?;
+            return Ok(());
+        }
+
+        let line = line.trim();
+        let (command, arg) = match line.find(' ') {
+            Some(index) => (&line[0..index], line[index + 1..].trim()),
+            None => (line, ""),
+        };
+
+        let reply = match command.to_uppercase().as_str() {
+            "CONNECT" => "+OK\n",
+            "PASS" => if arg == shared.password {
+                is_authorized = true;
+                "+OK: Password accepted\n"
+            } else {
+                "-ERROR: Password rejected\n"
+            },
+            "CHANNEL" => match arg.parse::<u8>() {
+                Ok(channel) if channel == shared.channel => "+OK\n",
+                Ok(_) => "-ERROR: Channel not available, only the logger's channel is shared\n",
+                Err(_) => "-ERROR: Invalid channel\n",
+            },
+            "DATA" => if is_authorized {
+                writer.write_all(b"+OK: Data incoming...\n")?;
+                break;
+            } else {
+                "-ERROR: Not authorized\n"
+            },
+            "QUIT" => {
+                writer.write_all(b"+OK\n")?;
+                return Ok(());
+            },
+            _ => "-ERROR: Unknown command\n",
+        };
+
+        writer.write_all(reply.as_bytes())?;
+    }
+
+    info!("VBus-over-TCP client {} connected", peer_addr);
+
+    reader.get_ref().set_read_timeout(None)?;
+    writer.set_write_timeout(Some(Duration::from_secs(CLIENT_WRITE_TIMEOUT)))?;
+
+    let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(CLIENT_QUEUE_LENGTH);
+
+    shared.clients.lock().unwrap().push(Client {
+        peer_addr,
+        sender,
+    });
+
+    // Write the queued data in the background, the queue is closed once the
+    // client is removed
+    thread::spawn(move || {
+        for bytes in receiver.iter() {
+            if let Err(err) = writer.write_all(&bytes) {
+                debug!("Unable to write to VBus-over-TCP client {}: {}", peer_addr, err);
+                break;
+            }
+        }
+
+        // Also ends the read loop below if the write failed
+        let _ = writer.shutdown(Shutdown::Both);
+    });
+
+    let result = forward_from_client(shared, &mut reader, peer_addr);
+
+    shared.remove_client(peer_addr);
+
+    info!("VBus-over-TCP client {} disconnected", peer_addr);
+
+    result
+}
+
+
+fn forward_from_client<R: Read>(shared: &Shared, reader: &mut R, peer_addr: SocketAddr) -> IoResult<()> {
+    let mut buf = [0; 1024];
+    loop {
+        let len = reader.read(&mut buf)?;
+        if len == 0 {
+            return Ok(());
+        }
+
+        match shared.write_from_client(&buf[0..len]) {
+            Ok(true) => {},
+            Ok(false) => debug!("Dropped data from VBus-over-TCP client {} while the logger accesses the bus", peer_addr),
+            Err(err) => debug!("Unable to forward data from VBus-over-TCP client {}: {}", peer_addr, err),
+        }
+    }
+}
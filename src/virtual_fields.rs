@@ -0,0 +1,123 @@
+use std::collections::{BTreeMap, HashSet};
+
+use resol_vbus::{
+    chrono::prelude::*,
+    DataSet,
+    Specification,
+};
+
+
+use config::Config;
+use derived_fields::DerivedFields;
+use energy_aggregator::EnergyAggregator;
+use relay_statistics::RelayStatistics;
+
+
+/// A value computed by the logger itself instead of being received from the VBus.
+#[derive(Clone, Debug)]
+pub struct VirtualField {
+    /// The ID used to reference the field, e.g. in the `fields` of a `[[csv]]` table.
+    pub id: String,
+    /// The human-readable name of the field.
+    pub name: String,
+    /// The unit text of the field, may be empty.
+    pub unit: String,
+    /// The number of decimal places used when formatting the value.
+    pub precision: usize,
+    /// The current value, if known.
+    pub value: Option<f64>,
+}
+
+
+impl VirtualField {
+    /// Format the value, optionally followed by its unit. Returns an empty
+    /// string if the value is unknown.
+    pub fn fmt_value(&self, with_unit: bool) -> String {
+        match self.value {
+            Some(value) => if with_unit && !self.unit.is_empty() {
+                format!("{:.*} {}", self.precision, value, self.unit)
+            } else {
+                format!("{:.*}", self.precision, value)
+            },
+            None => String::new(),
+        }
+    }
+}
+
+
+/// The collection of virtual fields provided by outputs like the
+/// `EnergyAggregator`, which is passed to all outputs alongside the data set.
+#[derive(Clone, Debug, Default)]
+pub struct VirtualFields {
+    fields: BTreeMap<String, VirtualField>,
+}
+
+
+impl VirtualFields {
+    /// Create an empty collection.
+    pub fn new() -> VirtualFields {
+        VirtualFields::default()
+    }
+
+    /// Add or replace the field with the `field.id`.
+    pub fn set(&mut self, field: VirtualField) {
+        self.fields.insert(field.id.clone(), field);
+    }
+
+    /// Get the field with the `id`.
+    pub fn get(&self, id: &str) -> Option<&VirtualField> {
+        self.fields.get(id)
+    }
+
+    /// Get the current value of the field with the `id`.
+    pub fn value(&self, id: &str) -> Option<f64> {
+        self.fields.get(id).and_then(|field| field.value)
+    }
+
+    /// Iterate over all fields, sorted by their IDs.
+    pub fn iter(&self) -> impl Iterator<Item = &VirtualField> {
+        self.fields.values()
+    }
+}
+
+
+/// The IDs of all virtual fields that the outputs configured in the `config`
+/// provide, e.g. to validate references to them.
+pub fn configured_virtual_field_ids(config: &Config) -> HashSet<String> {
+    let mut ids = HashSet::new();
+
+    for energy_config in config.energy.iter() {
+        ids.extend(EnergyAggregator::field_ids(energy_config));
+    }
+
+    for relay_config in config.relay.iter() {
+        ids.extend(RelayStatistics::field_ids(relay_config));
+    }
+
+    for derived_config in config.derived.iter() {
+        ids.insert(DerivedFields::field_id(derived_config));
+    }
+
+    ids
+}
+
+
+/// Get the value of the field with the `packet_field_id` in the `data_set`
+/// together with the timestamp of the packet containing it.
+pub fn sample_field(spec: &Specification, data_set: &DataSet, packet_field_id: &str) -> Option<(DateTime<UTC>, f64)> {
+    for field in spec.fields_in_data_set(data_set) {
+        if field.packet_field_id().packet_field_id_string() != packet_field_id {
+            continue;
+        }
+
+        let packet_id = &field.packet_spec().packet_id;
+        let timestamp = match data_set.iter().find(|data| data.id_string() == *packet_id) {
+            Some(data) => data.as_header().timestamp,
+            None => return None,
+        };
+
+        return field.raw_value_f64().map(|value| (timestamp, value));
+    }
+
+    None
+}